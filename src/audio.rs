@@ -0,0 +1,273 @@
+// Audio players that do not need a sound device, for headless runs and tests
+
+use crate::sound::AudioPlayer;
+use crate::StrResult;
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub enum SampleFormat {
+    #[default]
+    Int16,
+    Float32,
+}
+
+impl SampleFormat {
+    pub fn from_name(name: &str) -> Option<SampleFormat> {
+        match name {
+            "s16" => Some(SampleFormat::Int16),
+            "f32" => Some(SampleFormat::Float32),
+            _ => None,
+        }
+    }
+
+    fn bytes(self) -> u32 {
+        match self {
+            SampleFormat::Int16 => 2,
+            SampleFormat::Float32 => 4,
+        }
+    }
+}
+
+// The contents of the fmt chunk for stereo sound, also used in AVI files
+pub(crate) fn wave_format(samples_rate: u32, format: SampleFormat) -> Vec<u8> {
+    let tag: u16 = match format {
+        SampleFormat::Int16 => 1,
+        SampleFormat::Float32 => 3,
+    };
+    let block = 2 * format.bytes();
+    let mut out = Vec::with_capacity(18);
+    out.extend_from_slice(&tag.to_le_bytes());
+    out.extend_from_slice(&2u16.to_le_bytes());
+    out.extend_from_slice(&samples_rate.to_le_bytes());
+    out.extend_from_slice(&(samples_rate * block).to_le_bytes());
+    out.extend_from_slice(&(block as u16).to_le_bytes());
+    out.extend_from_slice(&(format.bytes() as u16 * 8).to_le_bytes());
+    out
+}
+
+// Streams stereo sound to a WAV file. The sizes in the header are filled in
+// by finish, or when the writer is dropped.
+pub struct WavWriter<W: Write + Seek = BufWriter<File>> {
+    w: W,
+    samples_rate: u32,
+    format: SampleFormat,
+    // Bytes of sound written
+    size: u64,
+    failed: bool,
+    finished: bool,
+}
+
+impl WavWriter {
+    pub fn create(path: &Path, samples_rate: u32, format: SampleFormat) -> StrResult<WavWriter> {
+        let file = File::create(path).map_err(|_| "Could not create WAV file")?;
+        WavWriter::new(BufWriter::new(file), samples_rate, format)
+            .map_err(|_| "Could not write WAV file")
+    }
+}
+
+impl<W: Write + Seek> WavWriter<W> {
+    pub fn new(w: W, samples_rate: u32, format: SampleFormat) -> io::Result<WavWriter<W>> {
+        let mut writer = WavWriter {
+            w,
+            samples_rate,
+            format,
+            size: 0,
+            failed: false,
+            finished: false,
+        };
+        writer.write_header()?;
+        Ok(writer)
+    }
+
+    // Float samples need an extended fmt chunk and a fact chunk with the length
+    fn write_header(&mut self) -> io::Result<()> {
+        let size = self.size.min(u32::MAX as u64 - 58) as u32;
+        let mut format = wave_format(self.samples_rate, self.format);
+        let mut fact = Vec::new();
+        if self.format == SampleFormat::Float32 {
+            format.extend_from_slice(&0u16.to_le_bytes());
+            fact.extend_from_slice(b"fact");
+            fact.extend_from_slice(&4u32.to_le_bytes());
+            fact.extend_from_slice(&(size / (2 * self.format.bytes())).to_le_bytes());
+        }
+        let riff_size = 4 + 8 + format.len() as u32 + fact.len() as u32 + 8 + size;
+        self.w.write_all(b"RIFF")?;
+        self.w.write_all(&riff_size.to_le_bytes())?;
+        self.w.write_all(b"WAVEfmt ")?;
+        self.w.write_all(&(format.len() as u32).to_le_bytes())?;
+        self.w.write_all(&format)?;
+        self.w.write_all(&fact)?;
+        self.w.write_all(b"data")?;
+        self.w.write_all(&size.to_le_bytes())
+    }
+
+    // Writes samples of the left and right channel in turn
+    pub fn write_interleaved(&mut self, samples: &[f32]) -> io::Result<()> {
+        let data: Vec<u8> = match self.format {
+            SampleFormat::Int16 => samples
+                .iter()
+                .flat_map(|&v| ((v.clamp(-1.0, 1.0) * 32767.0) as i16).to_le_bytes())
+                .collect(),
+            SampleFormat::Float32 => samples.iter().flat_map(|v| v.to_le_bytes()).collect(),
+        };
+        self.size += data.len() as u64;
+        self.w.write_all(&data)
+    }
+
+    pub fn finish(&mut self) -> io::Result<()> {
+        self.finished = true;
+        if self.failed {
+            return Err(io::Error::other("Could not write all samples"));
+        }
+        self.w.seek(SeekFrom::Start(0))?;
+        self.write_header()?;
+        self.w.seek(SeekFrom::End(0))?;
+        self.w.flush()
+    }
+}
+
+impl<W: Write + Seek + Send> AudioPlayer for WavWriter<W> {
+    fn play(&mut self, buf_left: &[f32], buf_right: &[f32]) {
+        if self.failed {
+            return;
+        }
+        let mut samples = Vec::with_capacity(buf_left.len() * 2);
+        for (l, r) in buf_left.iter().zip(buf_right.iter()) {
+            samples.push(*l);
+            samples.push(*r);
+        }
+        if self.write_interleaved(&samples).is_err() {
+            self.failed = true;
+        }
+    }
+
+    fn samples_rate(&self) -> u32 {
+        self.samples_rate
+    }
+
+    // There is no playback to catch up with, so the sound is never dropped
+    // to resynchronise
+    fn underflowed(&self) -> bool {
+        true
+    }
+}
+
+impl<W: Write + Seek> Drop for WavWriter<W> {
+    fn drop(&mut self) {
+        if !self.finished {
+            let _ = self.finish();
+        }
+    }
+}
+
+// Keeps the APU running without playing anything
+pub struct NullPlayer {
+    samples_rate: u32,
+}
+
+impl NullPlayer {
+    pub fn new(samples_rate: u32) -> NullPlayer {
+        NullPlayer { samples_rate }
+    }
+}
+
+impl AudioPlayer for NullPlayer {
+    fn play(&mut self, _buf_left: &[f32], _buf_right: &[f32]) {}
+
+    fn samples_rate(&self) -> u32 {
+        self.samples_rate
+    }
+
+    fn underflowed(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{SampleFormat, WavWriter};
+    use crate::gbmode::Model;
+    use crate::sound::{AudioPlayer, Sound};
+    use std::io::{self, Cursor, Seek, SeekFrom, Write};
+    use std::sync::{Arc, Mutex};
+
+    // A file that can still be read after the writer is handed to the APU
+    #[derive(Clone)]
+    struct SharedFile(Arc<Mutex<Cursor<Vec<u8>>>>);
+
+    impl Write for SharedFile {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Seek for SharedFile {
+        fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+            self.0.lock().unwrap().seek(pos)
+        }
+    }
+
+    fn read_u32(data: &[u8], pos: usize) -> u32 {
+        u32::from_le_bytes([data[pos], data[pos + 1], data[pos + 2], data[pos + 3]])
+    }
+
+    #[test]
+    fn wav_headers() {
+        let mut file = Cursor::new(Vec::new());
+        {
+            let mut wav = WavWriter::new(&mut file, 44100, SampleFormat::Int16).unwrap();
+            wav.play(&[0.0, 1.0, -2.0], &[0.5, -1.0, 0.25]);
+        }
+        let data = file.into_inner();
+        assert_eq!(data.len(), 44 + 12);
+        assert_eq!(read_u32(&data, 4), 36 + 12);
+        assert_eq!(read_u32(&data, 24), 44100);
+        assert_eq!(&data[36..40], b"data");
+        assert_eq!(read_u32(&data, 40), 12);
+        // Left and right in turn, limited to the range of the samples
+        let sample = |i: usize| i16::from_le_bytes([data[44 + i * 2], data[45 + i * 2]]);
+        assert_eq!(sample(1), 16383);
+        assert_eq!(sample(2), 32767);
+        assert_eq!(sample(4), -32767);
+
+        let mut file = Cursor::new(Vec::new());
+        {
+            let mut wav = WavWriter::new(&mut file, 48000, SampleFormat::Float32).unwrap();
+            wav.play(&[0.5, 0.0], &[-0.5, 0.0]);
+            wav.finish().unwrap();
+        }
+        let data = file.into_inner();
+        assert_eq!(data.len(), 58 + 16);
+        assert_eq!(u16::from_le_bytes([data[20], data[21]]), 3);
+        assert_eq!(&data[38..42], b"fact");
+        assert_eq!(read_u32(&data, 46), 2);
+        assert_eq!(read_u32(&data, 54), 16);
+        assert_eq!(&data[58..62], &0.5f32.to_le_bytes());
+    }
+
+    #[test]
+    fn samples_after_sync() {
+        let file = SharedFile(Arc::new(Mutex::new(Cursor::new(Vec::new()))));
+        let wav = WavWriter::new(file.clone(), 48000, SampleFormat::Int16).unwrap();
+        let mut sound = Sound::new(Box::new(wav), Model::Dmg);
+        let len = || file.0.lock().unwrap().get_ref().len();
+        sound.wb(0xFF26, 0x80);
+        for _ in 0..1000 {
+            sound.do_cycle(456);
+        }
+        let before_sync = len();
+        assert!(before_sync > 44);
+        // Leaving fast forward asks the player to resynchronise
+        sound.sync();
+        for _ in 0..1000 {
+            sound.do_cycle(456);
+        }
+        assert!(len() > before_sync);
+    }
+}
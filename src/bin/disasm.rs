@@ -0,0 +1,122 @@
+use rboy::disasm::{self, SymbolTable};
+use std::path::Path;
+
+const EXITCODE_SUCCESS: i32 = 0;
+const EXITCODE_LOADFAILS: i32 = 2;
+
+fn main() {
+    let exit_status = real_main();
+    if exit_status != EXITCODE_SUCCESS {
+        std::process::exit(exit_status);
+    }
+}
+
+fn real_main() -> i32 {
+    let matches = clap::App::new("rboy-disasm")
+        .version("0.1")
+        .author("Mathijs van de Nes")
+        .about("Disassembles code from a Gameboy ROM")
+        .arg(
+            clap::Arg::with_name("filename")
+                .help("Sets the ROM file to read")
+                .required(true),
+        )
+        .arg(
+            clap::Arg::with_name("location")
+                .help("Sets the start as bank:address or address, in hexadecimal")
+                .required(true)
+                .validator(|s| match parse_location(&s) {
+                    Some((_, address)) if address < 0x8000 => Ok(()),
+                    Some(..) => Err("Address must be in ROM".to_owned()),
+                    None => Err("Could not parse location".to_owned()),
+                }),
+        )
+        .arg(
+            clap::Arg::with_name("count")
+                .help("Sets the number of instructions. Default: 20")
+                .short("n")
+                .long("count")
+                .validator(|s| match s.parse::<u32>() {
+                    Err(e) => Err(format!("Could not parse count: {}", e)),
+                    Ok(..) => Ok(()),
+                })
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("symbols")
+                .help("Loads labels from a .sym file, by default the one next to the ROM")
+                .long("sym")
+                .takes_value(true),
+        )
+        .get_matches();
+
+    let filename = matches.value_of("filename").unwrap();
+    let (bank, mut address) = parse_location(matches.value_of("location").unwrap()).unwrap();
+    let count = matches
+        .value_of("count")
+        .unwrap_or("20")
+        .parse::<u32>()
+        .unwrap();
+
+    let rom = match std::fs::read(filename) {
+        Ok(rom) => rom,
+        Err(..) => {
+            warn("Could not read ROM");
+            return EXITCODE_LOADFAILS;
+        }
+    };
+
+    let symbols = match matches.value_of("symbols") {
+        Some(path) => match SymbolTable::load(Path::new(path)) {
+            Ok(symbols) => symbols,
+            Err(message) => {
+                warn(message);
+                return EXITCODE_LOADFAILS;
+            }
+        },
+        None => SymbolTable::load(&Path::new(filename).with_extension("sym"))
+            .unwrap_or_else(|_| SymbolTable::new()),
+    };
+
+    for _ in 0..count {
+        // Stop at the end of the bank
+        if address >= 0x8000 || (bank == 0 && address >= 0x4000) {
+            break;
+        }
+        let offset = if address < 0x4000 {
+            address as usize
+        } else {
+            bank as usize * 0x4000 + (address as usize & 0x3FFF)
+        };
+        if offset >= rom.len() {
+            break;
+        }
+        let bytes = &rom[offset..std::cmp::min(offset + 3, rom.len())];
+        let (text, len) = disasm::disassemble(bytes, address, bank, Some(&symbols));
+
+        if let Some(label) = symbols.lookup(bank, address) {
+            println!("{}:", label);
+        }
+        let hex: Vec<String> = bytes[..std::cmp::min(len as usize, bytes.len())]
+            .iter()
+            .map(|b| format!("{:02X}", b))
+            .collect();
+        println!("  {:02X}:{:04X}  {:<9} {}", bank, address, hex.join(" "), text);
+        address += len;
+    }
+
+    EXITCODE_SUCCESS
+}
+
+// A plain address is in bank 0 or, for switchable ROM, bank 1
+fn parse_location(s: &str) -> Option<(u16, u16)> {
+    if s.contains(':') {
+        return disasm::parse_location(s);
+    }
+    let address = u16::from_str_radix(s.trim_start_matches("0x"), 16).ok()?;
+    Some((if address < 0x4000 { 0 } else { 1 }, address))
+}
+
+fn warn(message: &str) {
+    eprintln!("{}", message);
+}
@@ -0,0 +1,435 @@
+use rboy::device::Device;
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+
+const EXITCODE_SUCCESS: i32 = 0;
+const EXITCODE_TESTFAILED: i32 = 1;
+const EXITCODE_CPULOADFAILS: i32 = 2;
+const EXITCODE_TIMEOUT: i32 = 3;
+const EXITCODE_OUTPUTFAILS: i32 = 4;
+
+const SAMPLES_RATE: u32 = 48000;
+
+const MOONEYE_PASS: &[u8] = &[3, 5, 8, 13, 21, 34];
+const MOONEYE_FAIL: &[u8] = &[0x42, 0x42, 0x42, 0x42, 0x42, 0x42];
+
+enum TestResult {
+    Passed,
+    Failed,
+    Running,
+}
+
+fn main() {
+    let exit_status = real_main();
+    if exit_status != EXITCODE_SUCCESS {
+        std::process::exit(exit_status);
+    }
+}
+
+fn real_main() -> i32 {
+    let matches = clap::App::new("rboy-headless")
+        .version("0.1")
+        .author("Mathijs van de Nes")
+        .about("Runs a Gameboy ROM without a window or audio device")
+        .arg(
+            clap::Arg::with_name("filename")
+                .help("Sets the ROM file to load")
+                .required(true),
+        )
+        .arg(
+            clap::Arg::with_name("classic")
+                .help("Forces the emulator to run in classic Gameboy mode, same as --model dmg")
+                .short("c")
+                .long("classic"),
+        )
+        .arg(
+            clap::Arg::with_name("model")
+                .help("Hardware to emulate: dmg0, dmg, mgb, sgb, sgb2, cgb or agb. Default: cgb")
+                .long("model")
+                .possible_values(&["dmg0", "dmg", "mgb", "sgb", "sgb2", "cgb", "agb"])
+                .conflicts_with("classic")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("skip-checksum")
+                .help("Skips verification of the cartridge checksum")
+                .long("skip-checksum"),
+        )
+        .arg(
+            clap::Arg::with_name("boot-rom")
+                .help("Runs a DMG, MGB or CGB boot ROM image before the cartridge")
+                .long("boot-rom")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("palette")
+                .help("Classic mode colors: grey, dmg, pocket, light or a palette file")
+                .long("palette")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("color-correction")
+                .help("CGB color conversion: raw, gambatte, accurate or agb. Default: gambatte")
+                .long("color-correction")
+                .possible_values(&["raw", "gambatte", "accurate", "agb"])
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("compat-palette")
+                .help("CGB palette for monochrome games, as chosen with the keys at boot")
+                .long("compat-palette")
+                .possible_values(&[
+                    "green", "blue", "brown", "pastel", "dark-green", "dark-blue", "red",
+                    "orange", "inverted", "grayscale", "dark-brown", "yellow",
+                ])
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("filter")
+                .help("Scales or post-processes the screen: none, scale2x, scale3x, hq2x or lcd")
+                .long("filter")
+                .possible_values(&["none", "scale2x", "scale3x", "hq2x", "lcd"])
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("blend")
+                .help("Mixes every frame with the previous one, like the slow LCD of the DMG")
+                .long("blend"),
+        )
+        .arg(
+            clap::Arg::with_name("no-access-blocking")
+                .help("Lets the CPU access VRAM, OAM and palettes while the PPU uses them")
+                .long("no-access-blocking"),
+        )
+        .arg(
+            clap::Arg::with_name("frames")
+                .help("Stops after the given number of frames")
+                .short("f")
+                .long("frames")
+                .validator(validate_number)
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("cycles")
+                .help("Stops after the given number of clock cycles")
+                .short("n")
+                .long("cycles")
+                .validator(validate_number)
+                .takes_value(true),
+        )
+        .group(
+            clap::ArgGroup::with_name("limit")
+                .args(&["frames", "cycles"])
+                .multiple(true)
+                .required(true),
+        )
+        .arg(
+            clap::Arg::with_name("serial-out")
+                .help("Writes the data from the serial port to a file, use - for stdout")
+                .short("s")
+                .long("serial-out")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("screenshot")
+                .help("Writes the final screen to a PNG file, or a PPM file without a .png extension")
+                .short("o")
+                .long("screenshot")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("audio-out")
+                .help("Writes the sound to a WAV file")
+                .long("audio-out")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("audio-format")
+                .help("Samples in the --audio-out file: s16 or f32. Default: s16")
+                .long("audio-format")
+                .possible_values(&["s16", "f32"])
+                .requires("audio-out")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("record")
+                .help("Records the picture and sound to an AVI, Y4M or GIF file")
+                .long("record")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("trace")
+                .help("Writes a log of every executed instruction to a file, use - for stdout")
+                .long("trace")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("camera")
+                .help("Grayscale PGM image for the Pocket Camera, repeat for a sequence")
+                .long("camera")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            clap::Arg::with_name("pass")
+                .help("Stops successfully when the serial output contains this text")
+                .long("pass")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("fail")
+                .help("Stops with a failure when the serial output contains this text")
+                .long("fail")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("mooneye")
+                .help("Checks the serial output for the Mooneye test suite result sequence")
+                .long("mooneye"),
+        )
+        .get_matches();
+
+    let filename = matches.value_of("filename").unwrap();
+    let model = match matches.value_of("model") {
+        Some(name) => rboy::Model::from_name(name).unwrap(),
+        None if matches.is_present("classic") => rboy::Model::Dmg,
+        None => rboy::Model::Cgb,
+    };
+    let opt_skip_checksum = matches.is_present("skip-checksum");
+    let opt_mooneye = matches.is_present("mooneye");
+    let max_frames = matches.value_of("frames").map(|v| v.parse::<u64>().unwrap());
+    let max_cycles = matches.value_of("cycles").map(|v| v.parse::<u64>().unwrap());
+    let pass_text = matches.value_of("pass").map(|v| v.as_bytes().to_vec());
+    let fail_text = matches.value_of("fail").map(|v| v.as_bytes().to_vec());
+    let has_criteria = opt_mooneye || pass_text.is_some();
+    let filter = match matches.value_of("filter") {
+        Some(name) => rboy::filters::Filter::from_name(name).unwrap(),
+        None => rboy::filters::Filter::None,
+    };
+    let mut blender = if matches.is_present("blend") {
+        Some(rboy::filters::FrameBlender::new(0.5))
+    } else {
+        None
+    };
+    let mut blended = None;
+
+    let mut device = match Device::new_model(filename, model, opt_skip_checksum) {
+        Ok(device) => device,
+        Err(message) => {
+            warn(message);
+            return EXITCODE_CPULOADFAILS;
+        }
+    };
+
+    if let Some(path) = matches.value_of("boot-rom") {
+        if let Err(message) = device.load_boot_rom(std::path::Path::new(path)) {
+            warn(message);
+            return EXITCODE_CPULOADFAILS;
+        }
+    }
+    if let Some(name) = matches.value_of("palette") {
+        if let Err(message) = device.set_dmg_palette_by_name(name) {
+            warn(message);
+            return EXITCODE_CPULOADFAILS;
+        }
+    }
+    if let Some(name) = matches.value_of("color-correction") {
+        device.set_color_correction(rboy::ColorCorrection::from_name(name).unwrap());
+    }
+    if let Some(name) = matches.value_of("compat-palette") {
+        device.set_compat_palette(rboy::CompatPalette::from_name(name).unwrap());
+    }
+    if matches.is_present("no-access-blocking") {
+        device.set_access_blocking(false);
+    }
+    if let Some(path) = matches.value_of("trace") {
+        let tracer: Box<dyn Write + Send> = if path == "-" {
+            Box::new(std::io::BufWriter::new(std::io::stdout()))
+        } else {
+            match std::fs::File::create(path) {
+                Ok(f) => Box::new(std::io::BufWriter::new(f)),
+                Err(..) => {
+                    warn("Could not create trace file");
+                    return EXITCODE_OUTPUTFAILS;
+                }
+            }
+        };
+        device.set_tracer(Some(tracer));
+    }
+
+    if let Some(paths) = matches.values_of("camera") {
+        let paths: Vec<std::path::PathBuf> = paths.map(|p| p.into()).collect();
+        if let Err(message) = device.set_camera_images(&paths) {
+            warn(message);
+            return EXITCODE_CPULOADFAILS;
+        }
+    }
+
+    if let Some(path) = matches.value_of("audio-out") {
+        let format = matches.value_of("audio-format").map_or(rboy::SampleFormat::Int16, |name| {
+            rboy::SampleFormat::from_name(name).unwrap()
+        });
+        let path = std::path::Path::new(path);
+        match rboy::WavWriter::create(path, SAMPLES_RATE, format) {
+            Ok(wav) => device.enable_audio(Box::new(wav)),
+            Err(message) => {
+                warn(message);
+                return EXITCODE_OUTPUTFAILS;
+            }
+        }
+    }
+    if let Some(path) = matches.value_of("record") {
+        if matches.value_of("audio-out").is_none() {
+            // The sound is only needed for the recording
+            device.enable_audio(Box::new(rboy::NullPlayer::new(SAMPLES_RATE)));
+        }
+        if let Err(message) = device.start_recording(std::path::Path::new(path)) {
+            warn(message);
+            return EXITCODE_OUTPUTFAILS;
+        }
+    }
+
+    let serial = Arc::new(Mutex::new(Vec::new()));
+    let serial_cb = serial.clone();
+    device.set_serial_callback(Box::new(move |v| {
+        serial_cb.lock().unwrap().push(v);
+        None
+    }));
+
+    let mut frames = 0;
+    let mut cycles = 0;
+    let mut serial_len = 0;
+    let mut result = TestResult::Running;
+
+    while below_limit(frames, max_frames) && below_limit(cycles, max_cycles) {
+        cycles += device.do_cycle() as u64;
+        if device.check_and_reset_gpu_updated() {
+            frames += 1;
+            if let Some(ref mut blender) = blender {
+                blended = Some(blender.blend(device.get_gpu_data()));
+            }
+        }
+        if let Some(reason) = device.check_and_reset_break() {
+            warn(&reason.to_string());
+            result = TestResult::Failed;
+            break;
+        }
+
+        let output = serial.lock().unwrap();
+        if output.len() != serial_len {
+            serial_len = output.len();
+            result = check_serial(&output, &pass_text, &fail_text, opt_mooneye);
+            if let TestResult::Running = result {
+                continue;
+            }
+            break;
+        }
+    }
+
+    if device.is_recording() {
+        if let Err(message) = device.stop_recording() {
+            warn(message);
+            return EXITCODE_OUTPUTFAILS;
+        }
+    }
+
+    let output = serial.lock().unwrap();
+    if let Some(path) = matches.value_of("serial-out") {
+        let written = if path == "-" {
+            std::io::stdout().write_all(&output)
+        } else {
+            std::fs::write(path, &*output)
+        };
+        if written.is_err() {
+            warn("Could not write serial output");
+            return EXITCODE_OUTPUTFAILS;
+        }
+    }
+
+    if let Some(path) = matches.value_of("screenshot") {
+        let (width, height) = device.screen_size();
+        let frame = match blended {
+            Some(ref frame) => frame,
+            None => device.get_gpu_data(),
+        };
+        let size = (width * filter.scale(), height * filter.scale());
+        let data = filter.apply(frame, width, height);
+        let written = if path.ends_with(".png") {
+            std::fs::File::create(path)
+                .and_then(|mut f| rboy::png::write_png(&mut f, &data, size.0, size.1))
+        } else {
+            write_ppm(path, &data, size)
+        };
+        if written.is_err() {
+            warn("Could not write screenshot");
+            return EXITCODE_OUTPUTFAILS;
+        }
+    }
+
+    match result {
+        TestResult::Passed => EXITCODE_SUCCESS,
+        TestResult::Failed => {
+            warn("Test failed");
+            EXITCODE_TESTFAILED
+        }
+        TestResult::Running if has_criteria => {
+            warn("Test did not finish in time");
+            EXITCODE_TIMEOUT
+        }
+        TestResult::Running => EXITCODE_SUCCESS,
+    }
+}
+
+fn validate_number(s: String) -> Result<(), String> {
+    match s.parse::<u64>() {
+        Err(e) => Err(format!("Could not parse number: {}", e)),
+        Ok(..) => Ok(()),
+    }
+}
+
+fn below_limit(value: u64, limit: Option<u64>) -> bool {
+    match limit {
+        Some(limit) => value < limit,
+        None => true,
+    }
+}
+
+fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+    !needle.is_empty() && haystack.windows(needle.len()).any(|w| w == needle)
+}
+
+fn check_serial(
+    output: &[u8],
+    pass_text: &Option<Vec<u8>>,
+    fail_text: &Option<Vec<u8>>,
+    mooneye: bool,
+) -> TestResult {
+    if let Some(ref text) = *fail_text {
+        if contains(output, text) {
+            return TestResult::Failed;
+        }
+    }
+    if mooneye && contains(output, MOONEYE_FAIL) {
+        return TestResult::Failed;
+    }
+    if let Some(ref text) = *pass_text {
+        if contains(output, text) {
+            return TestResult::Passed;
+        }
+    }
+    if mooneye && contains(output, MOONEYE_PASS) {
+        return TestResult::Passed;
+    }
+    TestResult::Running
+}
+
+fn write_ppm(path: &str, data: &[u8], (width, height): (usize, usize)) -> std::io::Result<()> {
+    let mut f = std::fs::File::create(path)?;
+    writeln!(f, "P6 {} {} 255", width, height)?;
+    f.write_all(data)
+}
+
+fn warn(message: &str) {
+    eprintln!("{}", message);
+}
@@ -1,8 +1,12 @@
+use crate::debug::BreakReason;
+use crate::gbmode::{GbMode, Model};
 use crate::register::CpuFlag::{C, N, H, Z};
 use crate::register::Registers;
 use crate::serial::SerialCallback;
 use crate::mmu::MMU;
+use crate::state::{StateReader, StateWriter};
 use crate::StrResult;
+use std::io::Write;
 
 pub struct CPU<'a> {
     reg: Registers,
@@ -11,39 +15,167 @@ pub struct CPU<'a> {
     ime: bool,
     setdi: u32,
     setei: u32,
+    locked: bool,
+    breakpoints: Vec<u16>,
+    temp_breakpoint: Option<u16>,
+    skip_breakpoint: bool,
+    pub break_reason: Option<BreakReason>,
+    tracer: Option<Box<dyn Write + Send>>,
 }
 
 impl<'a> CPU<'a> {
     pub fn new(romname: &str, serial_callback: Option<SerialCallback<'a>>, skip_checksum: bool) -> StrResult<CPU<'a>> {
-        let cpu_mmu = MMU::new(romname, serial_callback, skip_checksum)?;
-        Ok(CPU {
-            reg: Registers::new(),
-            halted: false,
-            ime: true,
-            setdi: 0,
-            setei: 0,
-            mmu: cpu_mmu,
-        })
+        CPU::new_model(romname, Model::Dmg, serial_callback, skip_checksum)
     }
 
     pub fn new_cgb(romname: &str, serial_callback: Option<SerialCallback<'a>>, skip_checksum: bool) -> StrResult<CPU<'a>> {
-        let cpu_mmu = MMU::new_cgb(romname, serial_callback, skip_checksum)?;
+        CPU::new_model(romname, Model::Cgb, serial_callback, skip_checksum)
+    }
+
+    pub fn new_model(romname: &str, model: Model, serial_callback: Option<SerialCallback<'a>>, skip_checksum: bool) -> StrResult<CPU<'a>> {
+        let cpu_mmu = MMU::new_model(romname, model, serial_callback, skip_checksum)?;
         Ok(CPU {
-            reg: Registers::new_cgb(),
+            reg: Registers::new_model(model, cpu_mmu.gbmode == GbMode::Color),
             halted: false,
             ime: true,
             setdi: 0,
             setei: 0,
+            locked: false,
+            breakpoints: Vec::new(),
+            temp_breakpoint: None,
+            skip_breakpoint: false,
+            break_reason: None,
+            tracer: None,
             mmu: cpu_mmu,
         })
     }
 
+    // Starts from address 0 with the boot ROM instead of the state it leaves behind
+    pub fn set_boot_rom(&mut self, data: Vec<u8>) -> StrResult<()> {
+        self.mmu.set_boot_rom(data)?;
+        self.reg = Registers::power_on();
+        self.ime = false;
+        Ok(())
+    }
+
     pub fn do_cycle(&mut self) -> u32 {
+        if self.check_breakpoint() {
+            return 0;
+        }
+        self.mmu.do_compat_select();
+        self.mmu.start_instruction();
         let ticks = self.docycle() * 4;
-        return self.mmu.do_cycle(ticks);
+        let ticks = self.mmu.do_cycle(ticks);
+        if let Some(hit) = self.mmu.watch_hit.take() {
+            self.break_reason = Some(hit);
+        }
+        ticks
+    }
+
+    pub fn step(&mut self) -> u32 {
+        self.skip_breakpoint = true;
+        self.do_cycle()
+    }
+
+    // Runs over CALL and RST by placing a temporary breakpoint after them.
+    // Returns false if the instruction was simply stepped instead.
+    pub fn step_over(&mut self) -> bool {
+        let pc = self.reg.pc;
+        let len = match self.mmu.peekbyte(pc) {
+            _ if self.halted || self.locked => 0,
+            0xC4 | 0xCC | 0xCD | 0xD4 | 0xDC => 3,
+            0xC7 | 0xCF | 0xD7 | 0xDF | 0xE7 | 0xEF | 0xF7 | 0xFF => 1,
+            _ => 0,
+        };
+        if len == 0 {
+            self.step();
+            return false;
+        }
+        self.temp_breakpoint = Some(pc.wrapping_add(len));
+        self.skip_breakpoint = true;
+        true
+    }
+
+    pub fn registers(&self) -> Registers {
+        self.reg
+    }
+
+    pub fn interrupts_enabled(&self) -> bool {
+        self.ime
+    }
+
+    pub fn halted(&self) -> bool {
+        self.halted
+    }
+
+    pub fn add_breakpoint(&mut self, address: u16) {
+        if !self.breakpoints.contains(&address) {
+            self.breakpoints.push(address);
+        }
+    }
+
+    pub fn remove_breakpoint(&mut self, address: u16) -> bool {
+        let len = self.breakpoints.len();
+        self.breakpoints.retain(|&a| a != address);
+        self.breakpoints.len() != len
+    }
+
+    pub fn breakpoints(&self) -> &[u16] {
+        &self.breakpoints
+    }
+
+    pub fn set_tracer(&mut self, tracer: Option<Box<dyn Write + Send>>) {
+        self.tracer = tracer;
+    }
+
+    fn check_breakpoint(&mut self) -> bool {
+        if self.skip_breakpoint {
+            self.skip_breakpoint = false;
+            return false;
+        }
+        if self.halted || self.locked {
+            return false;
+        }
+        let pc = self.reg.pc;
+        if self.temp_breakpoint == Some(pc) {
+            self.temp_breakpoint = None;
+            self.break_reason = Some(BreakReason::Step);
+        } else if self.breakpoints.contains(&pc) {
+            self.break_reason = Some(BreakReason::Breakpoint(pc));
+        } else {
+            return false;
+        }
+        // Do not stop at the same address again when resuming
+        self.skip_breakpoint = true;
+        true
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        self.reg.save_state(w);
+        w.write_bool(self.halted);
+        w.write_bool(self.ime);
+        w.write_u32(self.setdi);
+        w.write_u32(self.setei);
+        w.write_bool(self.locked);
+        self.mmu.save_state(w);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> StrResult<()> {
+        self.reg.load_state(r)?;
+        self.halted = r.read_bool()?;
+        self.ime = r.read_bool()?;
+        self.setdi = r.read_u32()?;
+        self.setei = r.read_u32()?;
+        self.locked = r.read_bool()?;
+        self.mmu.load_state(r)
     }
 
     fn docycle(&mut self) -> u32 {
+        if self.locked {
+            // Only a reset can recover from a lock up
+            return 1;
+        }
+
         self.updateime();
         match self.handleinterrupt() {
             0 => {},
@@ -54,10 +186,29 @@ impl<'a> CPU<'a> {
             // Emulate an noop instruction
             1
         } else {
+            if self.tracer.is_some() {
+                self.trace();
+            }
             self.call()
         }
     }
 
+    // Logs the state before an instruction in the format used by Gameboy Doctor
+    fn trace(&mut self) {
+        let pc = self.reg.pc;
+        let mem: Vec<u8> = (0..4).map(|i| self.mmu.peekbyte(pc.wrapping_add(i))).collect();
+        let r = self.reg;
+        let line = format!(
+            "A:{:02X} F:{:02X} B:{:02X} C:{:02X} D:{:02X} E:{:02X} H:{:02X} L:{:02X} SP:{:04X} PC:{:04X} PCMEM:{:02X},{:02X},{:02X},{:02X}",
+            r.a, r.af() & 0xFF, r.b, r.c, r.d, r.e, r.h, r.l, r.sp, pc, mem[0], mem[1], mem[2], mem[3]
+        );
+        if let Some(ref mut tracer) = self.tracer {
+            if writeln!(tracer, "{}", line).is_err() {
+                self.tracer = None;
+            }
+        }
+    }
+
     fn fetchbyte(&mut self) -> u8 {
         let b = self.mmu.rb(self.reg.pc);
         self.reg.pc += 1;
@@ -362,10 +513,17 @@ impl<'a> CPU<'a> {
             0xFB => { self.setei = 2; 1 },
             0xFE => { let v = self.fetchbyte(); self.alu_cp(v); 2 },
             0xFF => { self.pushstack(self.reg.pc); self.reg.pc = 0x38; 4 },
-            other=> panic!("Instruction {:2X} is not implemented", other),
+            other=> self.lockup(other),
         }
     }
 
+    fn lockup(&mut self, opcode: u8) -> u32 {
+        self.reg.pc = self.reg.pc.wrapping_sub(1);
+        self.locked = true;
+        self.break_reason = Some(BreakReason::IllegalOpcode { address: self.reg.pc, opcode });
+        1
+    }
+
     fn call_cb(&mut self) -> u32 {
         let opcode = self.fetchbyte();
         match opcode {
@@ -836,7 +994,8 @@ mod test
                 Ok(cpu) => cpu,
             };
             let mut ticks = 0;
-            while ticks < 63802933 * 2
+            // Without double speed the tests take twice as long in classic mode
+            while ticks < 63802933 * 4
             {
                 ticks += c.do_cycle();
             }
@@ -876,4 +1035,82 @@ mod test
         assert!(&*output == CPU_SERIAL, "Serial did not output the expected result");
         assert!(sum_color == GPU_COLOR_CHECKSUM, "GPU did not produce expected graphics");
     }
+
+    #[test]
+    fn save_state_roundtrip() {
+        use crate::state::{StateReader, StateWriter};
+
+        let mut c = CPU::new_cgb(CPUINSTRS, None, false).unwrap();
+        let mut ticks = 0;
+        while ticks < 4194304 { ticks += c.do_cycle(); }
+
+        let mut state = StateWriter::new();
+        c.save_state(&mut state);
+        let state = state.into_inner();
+
+        ticks = 0;
+        while ticks < 4194304 { ticks += c.do_cycle(); }
+        let expected_data = c.mmu.gpu.data.clone();
+        let expected_pc = c.reg.pc;
+
+        c.load_state(&mut StateReader::new(&state)).unwrap();
+        ticks = 0;
+        while ticks < 4194304 { ticks += c.do_cycle(); }
+
+        assert!(c.mmu.gpu.data == expected_data, "GPU output differs after loading state");
+        assert_eq!(c.reg.pc, expected_pc);
+    }
+
+    #[test]
+    fn breakpoints() {
+        use crate::debug::{BreakReason, WatchType};
+
+        let mut c = CPU::new(CPUINSTRS, None, false).unwrap();
+        c.add_breakpoint(0x0100);
+        assert_eq!(c.do_cycle(), 0);
+        assert!(c.break_reason.take() == Some(BreakReason::Breakpoint(0x0100)));
+        c.do_cycle();
+        assert_eq!(c.reg.pc, 0x0101);
+        assert!(c.break_reason.is_none());
+
+        // LD A,42 ; LD (D000),A ; illegal opcode
+        for (i, &b) in [0x3E, 0x42, 0xEA, 0x00, 0xD0, 0xD3].iter().enumerate() {
+            c.mmu.writebyte(0xC000 + i as u16, b);
+        }
+        c.reg.pc = 0xC000;
+        c.mmu.add_watchpoint(0xD000, WatchType::Write);
+        c.step();
+        assert!(c.break_reason.is_none());
+        c.step();
+        assert!(c.break_reason.take() == Some(BreakReason::Watchpoint { address: 0xD000, value: 0x42, write: true }));
+        c.step();
+        assert!(c.break_reason.take() == Some(BreakReason::IllegalOpcode { address: 0xC005, opcode: 0xD3 }));
+        c.step();
+        assert_eq!(c.reg.pc, 0xC005);
+    }
+
+    #[test]
+    fn trace_format() {
+        use std::io::{self, Write};
+        use std::sync::{Arc, Mutex};
+
+        struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+        impl Write for SharedBuffer {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> { self.0.lock().unwrap().write(buf) }
+            fn flush(&mut self) -> io::Result<()> { Ok(()) }
+        }
+
+        let output = Arc::new(Mutex::new(Vec::new()));
+        let mut c = CPU::new(CPUINSTRS, None, false).unwrap();
+        c.set_tracer(Some(Box::new(SharedBuffer(output.clone()))));
+        c.do_cycle();
+        c.do_cycle();
+
+        let output = String::from_utf8(output.lock().unwrap().clone()).unwrap();
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(lines, [
+            "A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:00,C3,37,06",
+            "A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0101 PCMEM:C3,37,06,CE",
+        ]);
+    }
 }
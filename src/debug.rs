@@ -0,0 +1,58 @@
+use std::fmt;
+
+#[derive(PartialEq, Copy, Clone)]
+pub enum WatchType {
+    Read,
+    Write,
+    Access,
+}
+
+#[derive(PartialEq, Copy, Clone)]
+pub enum BreakReason {
+    Breakpoint(u16),
+    Watchpoint { address: u16, value: u8, write: bool },
+    IllegalOpcode { address: u16, opcode: u8 },
+    Step,
+}
+
+impl WatchType {
+    pub fn matches(self, write: bool) -> bool {
+        match self {
+            WatchType::Read => !write,
+            WatchType::Write => write,
+            WatchType::Access => true,
+        }
+    }
+}
+
+impl fmt::Display for WatchType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            WatchType::Read => write!(f, "read"),
+            WatchType::Write => write!(f, "write"),
+            WatchType::Access => write!(f, "access"),
+        }
+    }
+}
+
+impl fmt::Display for BreakReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            BreakReason::Breakpoint(address) => write!(f, "Breakpoint at {:04X}", address),
+            BreakReason::Watchpoint {
+                address,
+                value,
+                write: true,
+            } => write!(f, "Watchpoint: wrote {:02X} to {:04X}", value, address),
+            BreakReason::Watchpoint { address, value, .. } => {
+                write!(f, "Watchpoint: read {:02X} from {:04X}", value, address)
+            }
+            BreakReason::IllegalOpcode { address, opcode } => write!(
+                f,
+                "Illegal opcode {:02X} at {:04X}, the CPU has locked up",
+                opcode, address
+            ),
+            BreakReason::Step => write!(f, "Stepped"),
+        }
+    }
+}
@@ -1,11 +1,27 @@
 use crate::cpu::CPU;
+use crate::debug::{BreakReason, WatchType};
+use crate::gbmode::Model;
+use crate::gpu::{SCREEN_H, SCREEN_W};
+use crate::disasm::{self, SymbolTable};
+use crate::filters::Filter;
 use crate::keypad::KeypadKey;
+use crate::mbc::{self, CameraCallback, InfraredCallback, RumbleCallback};
+use crate::palette::{ColorCorrection, CompatPalette, DmgPalette};
+use crate::png;
 use crate::printer::GbPrinter;
+use crate::recorder::Recorder;
+use crate::register::Registers;
+use crate::serial::SerialCallback;
+use crate::sgb::{SGB_SCREEN_H, SGB_SCREEN_W};
 use crate::sound;
+use crate::state::{StateReader, StateWriter};
 use crate::StrResult;
+use std::io::{Read, Write};
 
 pub struct Device {
     cpu: CPU<'static>,
+    cartridge_id: Vec<u8>,
+    recorder: Option<Recorder>,
 }
 
 fn stdoutprinter(v: u8) -> Option<u8> {
@@ -19,15 +35,43 @@ fn stdoutprinter(v: u8) -> Option<u8> {
 
 impl Device {
     pub fn new(romname: &str, skip_checksum: bool) -> StrResult<Device> {
-        CPU::new(romname, None, skip_checksum).map(|cpu| Device { cpu: cpu })
+        CPU::new(romname, None, skip_checksum).map(Device::from_cpu)
     }
 
     pub fn new_cgb(romname: &str, skip_checksum: bool) -> StrResult<Device> {
-        CPU::new_cgb(romname, None, skip_checksum).map(|cpu| Device { cpu: cpu })
+        CPU::new_cgb(romname, None, skip_checksum).map(Device::from_cpu)
+    }
+
+    // Emulates a specific model, the cartridge decides between classic and
+    // color mode on CGB and AGB
+    pub fn new_model(romname: &str, model: Model, skip_checksum: bool) -> StrResult<Device> {
+        CPU::new_model(romname, model, None, skip_checksum).map(Device::from_cpu)
+    }
+
+    fn from_cpu(cpu: CPU<'static>) -> Device {
+        // Title and checksums from the cartridge header, read while bank 0 is
+        // still mapped as some MBCs can switch it out later
+        let cartridge_id = (0x134..0x150).map(|a| cpu.mmu.mbc.readrom(a)).collect();
+        Device {
+            cpu,
+            cartridge_id,
+            recorder: None,
+        }
+    }
+
+    // Runs a DMG, MGB or CGB boot ROM image from power on. This has to be
+    // called before the emulation starts.
+    pub fn load_boot_rom(&mut self, path: &std::path::Path) -> StrResult<()> {
+        let data = std::fs::read(path).map_err(|_| "Could not read boot ROM")?;
+        self.cpu.set_boot_rom(data)
     }
 
     pub fn do_cycle(&mut self) -> u32 {
-        self.cpu.do_cycle()
+        let cycles = self.cpu.do_cycle();
+        if let Some(ref mut recorder) = self.recorder {
+            recorder.add_cycles(cycles);
+        }
+        cycles
     }
 
     pub fn set_stdout(&mut self, output: bool) {
@@ -38,6 +82,10 @@ impl Device {
         }
     }
 
+    pub fn set_serial_callback(&mut self, callback: SerialCallback<'static>) {
+        self.cpu.mmu.serial.set_callback(callback);
+    }
+
     pub fn attach_printer(&mut self) {
         let mut printer = GbPrinter::new();
 
@@ -49,15 +97,82 @@ impl Device {
     pub fn check_and_reset_gpu_updated(&mut self) -> bool {
         let result = self.cpu.mmu.gpu.updated;
         self.cpu.mmu.gpu.updated = false;
+        if result {
+            self.record_frame();
+        }
         result
     }
 
+    // The screen in RGB, with the border around it on the SGB
     pub fn get_gpu_data(&self) -> &[u8] {
-        &self.cpu.mmu.gpu.data
+        match self.cpu.mmu.sgb {
+            Some(ref sgb) => &sgb.data,
+            None => &self.cpu.mmu.gpu.data,
+        }
+    }
+
+    // Width and height of the picture returned by get_gpu_data
+    pub fn screen_size(&self) -> (usize, usize) {
+        match self.cpu.mmu.sgb {
+            Some(..) => (SGB_SCREEN_W, SGB_SCREEN_H),
+            None => (SCREEN_W, SCREEN_H),
+        }
+    }
+
+    // Writes the current screen as a PNG image
+    pub fn screenshot_png<W: Write>(&self, writer: &mut W) -> StrResult<()> {
+        self.screenshot_png_filtered(writer, Filter::None)
+    }
+
+    // Writes the current screen as a PNG image, scaled up by the filter
+    pub fn screenshot_png_filtered<W: Write>(&self, writer: &mut W, filter: Filter) -> StrResult<()> {
+        let (width, height) = self.screen_size();
+        let data = filter.apply(self.get_gpu_data(), width, height);
+        png::write_png(writer, &data, width * filter.scale(), height * filter.scale())
+            .map_err(|_| "Could not write screenshot")
+    }
+
+    // Records every frame from now on to an AVI, Y4M or GIF file, chosen by
+    // the extension. The sound is included when audio is enabled, for Y4M it
+    // goes to a WAV file with the same name.
+    pub fn start_recording(&mut self, path: &std::path::Path) -> StrResult<()> {
+        if self.recorder.is_some() {
+            return Err("Already recording");
+        }
+        let (width, height) = self.screen_size();
+        let samples_rate = self.cpu.mmu.sound.as_ref().map(|s| s.samples_rate());
+        let recorder = Recorder::new(path, width, height, samples_rate)?;
+        if let Some(ref mut sound) = self.cpu.mmu.sound {
+            sound.start_recording();
+        }
+        self.recorder = Some(recorder);
+        Ok(())
+    }
+
+    pub fn stop_recording(&mut self) -> StrResult<()> {
+        let mut recorder = self.recorder.take().ok_or("Not recording")?;
+        if let Some(ref mut sound) = self.cpu.mmu.sound {
+            recorder.add_audio(&sound.stop_recording());
+        }
+        recorder.finish()
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.recorder.is_some()
+    }
+
+    fn record_frame(&mut self) {
+        if let Some(mut recorder) = self.recorder.take() {
+            recorder.add_frame(self.get_gpu_data());
+            if let Some(ref mut sound) = self.cpu.mmu.sound {
+                recorder.add_audio(&sound.take_recorded());
+            }
+            self.recorder = Some(recorder);
+        }
     }
 
     pub fn enable_audio(&mut self, player: Box<dyn sound::AudioPlayer>) {
-        self.cpu.mmu.sound = Some(sound::Sound::new(player));
+        self.cpu.mmu.sound = Some(sound::Sound::new(player, self.cpu.mmu.model));
     }
 
     pub fn sync_audio(&mut self) {
@@ -74,7 +189,221 @@ impl Device {
         self.cpu.mmu.keypad.keydown(key);
     }
 
+    // Called with the new state when the rumble motor switches on or off
+    pub fn set_rumble_callback(&mut self, callback: RumbleCallback) {
+        self.cpu.mmu.mbc.set_rumble_callback(callback);
+    }
+
+    // Connects the IR port of HuC1 and HuC3 cartridges
+    pub fn set_infrared_callback(&mut self, callback: InfraredCallback) {
+        self.cpu.mmu.mbc.set_infrared_callback(callback);
+    }
+
+    // Feeds the accelerometer of MBC7 cartridges, in g. Positive x is tilted
+    // to the right and positive y is tilted towards the player.
+    pub fn set_tilt(&mut self, x: f32, y: f32) {
+        self.cpu.mmu.mbc.set_tilt(x, y);
+    }
+
+    // Supplies the frames seen by the Pocket Camera sensor
+    pub fn set_camera_callback(&mut self, callback: CameraCallback) {
+        self.cpu.mmu.mbc.set_camera_callback(callback);
+    }
+
+    // Feeds the Pocket Camera from binary PGM images, a new image is used for
+    // every capture and the sequence repeats
+    pub fn set_camera_images(&mut self, paths: &[std::path::PathBuf]) -> StrResult<()> {
+        let images = paths
+            .iter()
+            .map(|path| mbc::load_pgm(path))
+            .collect::<StrResult<Vec<_>>>()?;
+        if images.is_empty() {
+            return Err("No camera images given");
+        }
+        let mut next = 0;
+        self.set_camera_callback(Box::new(move |image| {
+            image.copy_from_slice(&images[next]);
+            next = (next + 1) % images.len();
+        }));
+        Ok(())
+    }
+
+    // Whether VRAM, OAM and the CGB palettes are inaccessible to the CPU while
+    // the PPU uses them, as on hardware. Enabled by default.
+    pub fn set_access_blocking(&mut self, enabled: bool) {
+        self.cpu.mmu.gpu.access_blocking = enabled;
+    }
+
+    // Sets the colors of the BG, OBJ0 and OBJ1 palettes in classic mode
+    pub fn set_dmg_palette(&mut self, palette: DmgPalette) {
+        self.cpu.mmu.gpu.set_dmg_palette(palette);
+    }
+
+    // Selects a built-in palette by name, or loads one from a palette file
+    pub fn set_dmg_palette_by_name(&mut self, name: &str) -> StrResult<()> {
+        let palette = match DmgPalette::preset(name) {
+            Some(palette) => palette,
+            None => DmgPalette::load(std::path::Path::new(name))?,
+        };
+        self.set_dmg_palette(palette);
+        Ok(())
+    }
+
+    // Replaces the palette the CGB chose for a monochrome game. Holding a
+    // direction with A or B before the first do_cycle does the same, like on
+    // hardware.
+    pub fn set_compat_palette(&mut self, palette: CompatPalette) {
+        self.cpu.mmu.gpu.set_compat_palette(palette.colors());
+    }
+
+    // Selects how CGB colors are converted for display
+    pub fn set_color_correction(&mut self, correction: ColorCorrection) {
+        self.cpu.mmu.gpu.set_color_correction(correction);
+    }
+
+    pub fn model(&self) -> Model {
+        self.cpu.mmu.model
+    }
+
     pub fn romname(&self) -> String {
         self.cpu.mmu.mbc.romname()
     }
+
+    pub fn save_state<W: Write>(&self, writer: &mut W) -> StrResult<()> {
+        let mut state = StateWriter::new();
+        state.write_header();
+        state.write_vec(&self.cartridge_id);
+        self.cpu.save_state(&mut state);
+        writer
+            .write_all(&state.into_inner())
+            .map_err(|_| "Could not write save state")
+    }
+
+    pub fn load_state<R: Read>(&mut self, reader: &mut R) -> StrResult<()> {
+        let mut data = vec![];
+        reader
+            .read_to_end(&mut data)
+            .map_err(|_| "Could not read save state")?;
+        let mut state = StateReader::new(&data);
+        state.read_header()?;
+        if state.read_vec()? != self.cartridge_id {
+            return Err("Save state belongs to a different cartridge");
+        }
+        // The machine is overwritten while the state is read, so put the
+        // current one back when the state turns out to be invalid
+        let mut backup = StateWriter::new();
+        self.cpu.save_state(&mut backup);
+        let result = self.cpu.load_state(&mut state);
+        if result.is_err() {
+            let backup = backup.into_inner();
+            self.cpu
+                .load_state(&mut StateReader::new(&backup))
+                .expect("Could not restore the state from before loading");
+        }
+        result
+    }
+
+    pub fn check_and_reset_break(&mut self) -> Option<BreakReason> {
+        self.cpu.break_reason.take()
+    }
+
+    pub fn step(&mut self) -> u32 {
+        self.cpu.step()
+    }
+
+    pub fn step_over(&mut self) -> bool {
+        self.cpu.step_over()
+    }
+
+    pub fn add_breakpoint(&mut self, address: u16) {
+        self.cpu.add_breakpoint(address);
+    }
+
+    pub fn remove_breakpoint(&mut self, address: u16) -> bool {
+        self.cpu.remove_breakpoint(address)
+    }
+
+    pub fn breakpoints(&self) -> &[u16] {
+        self.cpu.breakpoints()
+    }
+
+    pub fn add_watchpoint(&mut self, address: u16, watchtype: WatchType) {
+        self.cpu.mmu.add_watchpoint(address, watchtype);
+    }
+
+    pub fn remove_watchpoint(&mut self, address: u16) -> bool {
+        self.cpu.mmu.remove_watchpoint(address)
+    }
+
+    pub fn watchpoints(&self) -> &[(u16, WatchType)] {
+        self.cpu.mmu.watchpoints()
+    }
+
+    pub fn registers(&self) -> Registers {
+        self.cpu.registers()
+    }
+
+    pub fn interrupts_enabled(&self) -> bool {
+        self.cpu.interrupts_enabled()
+    }
+
+    pub fn halted(&self) -> bool {
+        self.cpu.halted()
+    }
+
+    // Memory access for debugging, this does not trigger watchpoints or
+    // cartridge hardware such as the IR port
+    pub fn read_memory(&mut self, address: u16) -> u8 {
+        self.cpu.mmu.peekbyte(address)
+    }
+
+    pub fn write_memory(&mut self, address: u16, value: u8) {
+        self.cpu.mmu.writebyte(address, value);
+    }
+
+    // Logs the registers before every instruction, in the Gameboy Doctor format
+    pub fn set_tracer(&mut self, tracer: Option<Box<dyn Write + Send>>) {
+        self.cpu.set_tracer(tracer);
+    }
+
+    pub fn rombank(&self) -> usize {
+        self.cpu.mmu.mbc.rombank()
+    }
+
+    // Returns the instruction at the address and its length
+    pub fn disassemble(&mut self, address: u16, symbols: Option<&SymbolTable>) -> (String, u16) {
+        let bytes: Vec<u8> = (0..3)
+            .map(|i| self.read_memory(address.wrapping_add(i)))
+            .collect();
+        disasm::disassemble(&bytes, address, self.rombank() as u16, symbols)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Device;
+
+    #[test]
+    fn failed_load_keeps_state() {
+        let mut device = Device::new_cgb("roms/cpu_instrs.gb", false).unwrap();
+        let mut ticks = 0;
+        while ticks < 4194304 {
+            ticks += device.do_cycle();
+        }
+        let mut state = Vec::new();
+        device.save_state(&mut state).unwrap();
+
+        while ticks < 2 * 4194304 {
+            ticks += device.do_cycle();
+        }
+        let mut expected = Vec::new();
+        device.save_state(&mut expected).unwrap();
+
+        // Cut off in the middle of the memory
+        let truncated = &state[..state.len() / 2];
+        assert!(device.load_state(&mut &truncated[..]).is_err());
+        let mut after = Vec::new();
+        device.save_state(&mut after).unwrap();
+        assert!(after == expected, "Failed load changed the machine");
+    }
 }
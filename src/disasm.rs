@@ -0,0 +1,301 @@
+use crate::StrResult;
+use std::collections::BTreeMap;
+use std::path::Path;
+
+const REGS: [&str; 8] = ["B", "C", "D", "E", "H", "L", "(HL)", "A"];
+const ALU: [&str; 8] = ["ADD A,", "ADC A,", "SUB ", "SBC A,", "AND ", "XOR ", "OR ", "CP "];
+const ROT: [&str; 8] = ["RLC", "RRC", "RL", "RR", "SLA", "SRA", "SWAP", "SRL"];
+
+pub struct SymbolTable {
+    // Keyed on (address, bank) so all banks of an address are adjacent
+    symbols: BTreeMap<(u16, u16), String>,
+}
+
+impl SymbolTable {
+    pub fn new() -> SymbolTable {
+        SymbolTable {
+            symbols: BTreeMap::new(),
+        }
+    }
+
+    // Parses the `bank:address label` lines used by RGBDS and no$gmb
+    pub fn parse(text: &str) -> SymbolTable {
+        let mut table = SymbolTable::new();
+        for line in text.lines() {
+            let line = line.split(';').next().unwrap_or("");
+            let mut parts = line.split_whitespace();
+            if let (Some(location), Some(name)) = (parts.next(), parts.next()) {
+                if let Some((bank, address)) = parse_location(location) {
+                    table.symbols.insert((address, bank), name.to_owned());
+                }
+            }
+        }
+        table
+    }
+
+    pub fn load(path: &Path) -> StrResult<SymbolTable> {
+        std::fs::read_to_string(path)
+            .map(|text| SymbolTable::parse(&text))
+            .map_err(|_| "Could not read symbol file")
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.symbols.is_empty()
+    }
+
+    pub fn lookup(&self, bank: u16, address: u16) -> Option<&str> {
+        let bank = if address < 0x4000 { 0 } else { bank };
+        if let Some(name) = self.symbols.get(&(address, bank)) {
+            return Some(name);
+        }
+        // Outside of switchable ROM the bank is not known, take any match
+        if (0x4000..0x8000).contains(&address) {
+            return None;
+        }
+        self.symbols
+            .range((address, 0)..=(address, 0xFFFF))
+            .next()
+            .map(|(_, name)| name.as_str())
+    }
+
+    pub fn find(&self, name: &str) -> Option<(u16, u16)> {
+        self.symbols
+            .iter()
+            .find(|&(_, n)| n == name)
+            .map(|(&(address, bank), _)| (bank, address))
+    }
+}
+
+impl Default for SymbolTable {
+    fn default() -> SymbolTable {
+        SymbolTable::new()
+    }
+}
+
+// Parses `bank:address` with both parts in hexadecimal
+pub fn parse_location(s: &str) -> Option<(u16, u16)> {
+    let mut parts = s.splitn(2, ':');
+    let bank = u16::from_str_radix(parts.next()?, 16).ok()?;
+    let address = u16::from_str_radix(parts.next()?, 16).ok()?;
+    Some((bank, address))
+}
+
+// Decodes the instruction at the start of `bytes`, which is located at `address`.
+// Returns the text and the length of the instruction.
+pub fn disassemble(
+    bytes: &[u8],
+    address: u16,
+    bank: u16,
+    symbols: Option<&SymbolTable>,
+) -> (String, u16) {
+    let byte = |i: usize| bytes.get(i).cloned().unwrap_or(0);
+    let opcode = byte(0);
+    if opcode == 0xCB {
+        return (cb_mnemonic(byte(1)), 2);
+    }
+    let template = match mnemonic(opcode) {
+        Some(template) => template,
+        None => return (format!("DB ${:02X}", opcode), 1),
+    };
+
+    let imm8 = byte(1);
+    let imm16 = (byte(1) as u16) | ((byte(2) as u16) << 8);
+    let label = |a: u16| match symbols.and_then(|s| s.lookup(bank, a)) {
+        Some(name) => name.to_owned(),
+        None => format!("${:04X}", a),
+    };
+
+    if template.contains("d16") {
+        (template.replace("d16", &format!("${:04X}", imm16)), 3)
+    } else if template.contains("a16") {
+        (template.replace("a16", &label(imm16)), 3)
+    } else if template.contains("d8") {
+        (template.replace("d8", &format!("${:02X}", imm8)), 2)
+    } else if template.contains("a8") {
+        (template.replace("a8", &label(0xFF00 | imm8 as u16)), 2)
+    } else if template.contains("r8") {
+        let target = address.wrapping_add(2).wrapping_add(imm8 as i8 as u16);
+        (template.replace("r8", &label(target)), 2)
+    } else if template.contains("s8") {
+        (template.replace("s8", &format!("{:+}", imm8 as i8)), 2)
+    } else {
+        (template, 1)
+    }
+}
+
+fn cb_mnemonic(opcode: u8) -> String {
+    let reg = REGS[opcode as usize & 7];
+    let bit = (opcode >> 3) & 7;
+    match opcode >> 6 {
+        0 => format!("{} {}", ROT[bit as usize], reg),
+        1 => format!("BIT {},{}", bit, reg),
+        2 => format!("RES {},{}", bit, reg),
+        _ => format!("SET {},{}", bit, reg),
+    }
+}
+
+// Operands are written as d8/d16 for immediates, a8/a16 for addresses,
+// r8 for relative jumps and s8 for signed offsets
+fn mnemonic(opcode: u8) -> Option<String> {
+    let text = match opcode {
+        0x76 => "HALT",
+        0x40..=0x7F => {
+            let dst = REGS[(opcode as usize >> 3) & 7];
+            let src = REGS[opcode as usize & 7];
+            return Some(format!("LD {},{}", dst, src));
+        }
+        0x80..=0xBF => {
+            let op = ALU[(opcode as usize >> 3) & 7];
+            return Some(format!("{}{}", op, REGS[opcode as usize & 7]));
+        }
+        0x00 => "NOP",
+        0x01 => "LD BC,d16",
+        0x02 => "LD (BC),A",
+        0x03 => "INC BC",
+        0x04 => "INC B",
+        0x05 => "DEC B",
+        0x06 => "LD B,d8",
+        0x07 => "RLCA",
+        0x08 => "LD (a16),SP",
+        0x09 => "ADD HL,BC",
+        0x0A => "LD A,(BC)",
+        0x0B => "DEC BC",
+        0x0C => "INC C",
+        0x0D => "DEC C",
+        0x0E => "LD C,d8",
+        0x0F => "RRCA",
+        0x10 => "STOP",
+        0x11 => "LD DE,d16",
+        0x12 => "LD (DE),A",
+        0x13 => "INC DE",
+        0x14 => "INC D",
+        0x15 => "DEC D",
+        0x16 => "LD D,d8",
+        0x17 => "RLA",
+        0x18 => "JR r8",
+        0x19 => "ADD HL,DE",
+        0x1A => "LD A,(DE)",
+        0x1B => "DEC DE",
+        0x1C => "INC E",
+        0x1D => "DEC E",
+        0x1E => "LD E,d8",
+        0x1F => "RRA",
+        0x20 => "JR NZ,r8",
+        0x21 => "LD HL,d16",
+        0x22 => "LD (HL+),A",
+        0x23 => "INC HL",
+        0x24 => "INC H",
+        0x25 => "DEC H",
+        0x26 => "LD H,d8",
+        0x27 => "DAA",
+        0x28 => "JR Z,r8",
+        0x29 => "ADD HL,HL",
+        0x2A => "LD A,(HL+)",
+        0x2B => "DEC HL",
+        0x2C => "INC L",
+        0x2D => "DEC L",
+        0x2E => "LD L,d8",
+        0x2F => "CPL",
+        0x30 => "JR NC,r8",
+        0x31 => "LD SP,d16",
+        0x32 => "LD (HL-),A",
+        0x33 => "INC SP",
+        0x34 => "INC (HL)",
+        0x35 => "DEC (HL)",
+        0x36 => "LD (HL),d8",
+        0x37 => "SCF",
+        0x38 => "JR C,r8",
+        0x39 => "ADD HL,SP",
+        0x3A => "LD A,(HL-)",
+        0x3B => "DEC SP",
+        0x3C => "INC A",
+        0x3D => "DEC A",
+        0x3E => "LD A,d8",
+        0x3F => "CCF",
+        0xC0 => "RET NZ",
+        0xC1 => "POP BC",
+        0xC2 => "JP NZ,a16",
+        0xC3 => "JP a16",
+        0xC4 => "CALL NZ,a16",
+        0xC5 => "PUSH BC",
+        0xC6 => "ADD A,d8",
+        0xC7 => "RST $00",
+        0xC8 => "RET Z",
+        0xC9 => "RET",
+        0xCA => "JP Z,a16",
+        0xCC => "CALL Z,a16",
+        0xCD => "CALL a16",
+        0xCE => "ADC A,d8",
+        0xCF => "RST $08",
+        0xD0 => "RET NC",
+        0xD1 => "POP DE",
+        0xD2 => "JP NC,a16",
+        0xD4 => "CALL NC,a16",
+        0xD5 => "PUSH DE",
+        0xD6 => "SUB d8",
+        0xD7 => "RST $10",
+        0xD8 => "RET C",
+        0xD9 => "RETI",
+        0xDA => "JP C,a16",
+        0xDC => "CALL C,a16",
+        0xDE => "SBC A,d8",
+        0xDF => "RST $18",
+        0xE0 => "LDH (a8),A",
+        0xE1 => "POP HL",
+        0xE2 => "LD ($FF00+C),A",
+        0xE5 => "PUSH HL",
+        0xE6 => "AND d8",
+        0xE7 => "RST $20",
+        0xE8 => "ADD SP,s8",
+        0xE9 => "JP HL",
+        0xEA => "LD (a16),A",
+        0xEE => "XOR d8",
+        0xEF => "RST $28",
+        0xF0 => "LDH A,(a8)",
+        0xF1 => "POP AF",
+        0xF2 => "LD A,($FF00+C)",
+        0xF3 => "DI",
+        0xF5 => "PUSH AF",
+        0xF6 => "OR d8",
+        0xF7 => "RST $30",
+        0xF8 => "LD HL,SPs8",
+        0xF9 => "LD SP,HL",
+        0xFA => "LD A,(a16)",
+        0xFB => "EI",
+        0xFE => "CP d8",
+        0xFF => "RST $38",
+        _ => return None,
+    };
+    Some(text.to_owned())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{disassemble, SymbolTable};
+
+    #[test]
+    fn instructions() {
+        let dis = |bytes: &[u8]| disassemble(bytes, 0x0150, 1, None);
+        assert_eq!(dis(&[0x00]), ("NOP".to_owned(), 1));
+        assert_eq!(dis(&[0x01, 0x34, 0x12]), ("LD BC,$1234".to_owned(), 3));
+        assert_eq!(dis(&[0x7E]), ("LD A,(HL)".to_owned(), 1));
+        assert_eq!(dis(&[0x9F]), ("SBC A,A".to_owned(), 1));
+        assert_eq!(dis(&[0x18, 0xFE]), ("JR $0150".to_owned(), 2));
+        assert_eq!(dis(&[0xE0, 0x40]), ("LDH ($FF40),A".to_owned(), 2));
+        assert_eq!(dis(&[0xF8, 0xFD]), ("LD HL,SP-3".to_owned(), 2));
+        assert_eq!(dis(&[0xCB, 0x7C]), ("BIT 7,H".to_owned(), 2));
+        assert_eq!(dis(&[0xCB, 0x36]), ("SWAP (HL)".to_owned(), 2));
+        assert_eq!(dis(&[0xD3]), ("DB $D3".to_owned(), 1));
+    }
+
+    #[test]
+    fn symbols() {
+        let symbols = SymbolTable::parse("; comment\n00:0150 Start\n01:4000 Bank1\n02:4000 Bank2\n00:C000 wBuffer\n");
+        let dis = |bytes: &[u8], bank| disassemble(bytes, 0x0150, bank, Some(&symbols)).0;
+        assert_eq!(dis(&[0xC3, 0x50, 0x01], 1), "JP Start");
+        assert_eq!(dis(&[0xCD, 0x00, 0x40], 2), "CALL Bank2");
+        assert_eq!(dis(&[0xCD, 0x00, 0x40], 3), "CALL $4000");
+        assert_eq!(dis(&[0xEA, 0x00, 0xC0], 5), "LD (wBuffer),A");
+        assert_eq!(symbols.find("Bank1"), Some((1, 0x4000)));
+    }
+}
@@ -0,0 +1,246 @@
+// Post-processing for the RGB screen returned by Device::get_gpu_data, so the
+// window and screenshots can share the same look
+
+type Rgb = [u8; 3];
+
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub enum Filter {
+    #[default]
+    None,
+    Scale2x,
+    Scale3x,
+    Hq2x,
+    // Dark lines between the pixels, like the grid of an LCD
+    Lcd,
+}
+
+impl Filter {
+    pub fn from_name(name: &str) -> Option<Filter> {
+        match name {
+            "none" => Some(Filter::None),
+            "scale2x" => Some(Filter::Scale2x),
+            "scale3x" => Some(Filter::Scale3x),
+            "hq2x" => Some(Filter::Hq2x),
+            "lcd" => Some(Filter::Lcd),
+            _ => None,
+        }
+    }
+
+    // How many times larger the output is in each direction
+    pub fn scale(self) -> usize {
+        match self {
+            Filter::None => 1,
+            Filter::Scale2x | Filter::Hq2x => 2,
+            Filter::Scale3x | Filter::Lcd => 3,
+        }
+    }
+
+    pub fn apply(self, data: &[u8], width: usize, height: usize) -> Vec<u8> {
+        match self {
+            Filter::None => data.to_vec(),
+            Filter::Scale2x => scale2x(data, width, height),
+            Filter::Scale3x => scale3x(data, width, height),
+            Filter::Hq2x => hq2x(data, width, height),
+            Filter::Lcd => lcd_grid(data, width, height),
+        }
+    }
+}
+
+// Gives the 3x3 neighbourhood of every pixel to `cell`, which returns the
+// N = scale * scale output pixels in reading order. Edges repeat the border pixels.
+fn scale_with<F, const N: usize>(
+    data: &[u8],
+    width: usize,
+    height: usize,
+    scale: usize,
+    cell: F,
+) -> Vec<u8>
+where
+    F: Fn(&[Rgb; 9]) -> [Rgb; N],
+{
+    let pixel = |x: isize, y: isize| {
+        let x = x.clamp(0, width as isize - 1) as usize;
+        let y = y.clamp(0, height as isize - 1) as usize;
+        let i = (y * width + x) * 3;
+        [data[i], data[i + 1], data[i + 2]]
+    };
+    let out_width = width * scale;
+    let mut out = vec![0; out_width * height * scale * 3];
+    for y in 0..height {
+        for x in 0..width {
+            let (xi, yi) = (x as isize, y as isize);
+            let mut around = [[0; 3]; 9];
+            for (i, p) in around.iter_mut().enumerate() {
+                *p = pixel(xi + (i % 3) as isize - 1, yi + (i / 3) as isize - 1);
+            }
+            for (i, color) in cell(&around).iter().enumerate() {
+                let ox = x * scale + i % scale;
+                let oy = y * scale + i / scale;
+                let o = (oy * out_width + ox) * 3;
+                out[o..o + 3].copy_from_slice(color);
+            }
+        }
+    }
+    out
+}
+
+// The Scale2x algorithm by Andrea Mazzoleni
+pub fn scale2x(data: &[u8], width: usize, height: usize) -> Vec<u8> {
+    scale_with(data, width, height, 2, |p| {
+        let [_, b, _, d, e, f, _, h, _] = *p;
+        if b != h && d != f {
+            [
+                if d == b { d } else { e },
+                if b == f { f } else { e },
+                if d == h { d } else { e },
+                if h == f { f } else { e },
+            ]
+        } else {
+            [e; 4]
+        }
+    })
+}
+
+pub fn scale3x(data: &[u8], width: usize, height: usize) -> Vec<u8> {
+    scale_with(data, width, height, 3, |p| {
+        let [a, b, c, d, e, f, g, h, i] = *p;
+        if b != h && d != f {
+            [
+                if d == b { d } else { e },
+                if (d == b && e != c) || (b == f && e != a) { b } else { e },
+                if b == f { f } else { e },
+                if (d == b && e != g) || (d == h && e != a) { d } else { e },
+                e,
+                if (b == f && e != i) || (h == f && e != c) { f } else { e },
+                if d == h { d } else { e },
+                if (d == h && e != i) || (h == f && e != g) { h } else { e },
+                if h == f { f } else { e },
+            ]
+        } else {
+            [e; 9]
+        }
+    })
+}
+
+// Colors are alike when they are close in YUV, with the thresholds of hqx
+fn similar(a: Rgb, b: Rgb) -> bool {
+    let yuv = |c: Rgb| {
+        let (r, g, b) = (c[0] as i32, c[1] as i32, c[2] as i32);
+        ((r * 299 + g * 587 + b * 114) / 1000, (b - r) / 4 + 128, (2 * g - r - b) / 8 + 128)
+    };
+    let (y1, u1, v1) = yuv(a);
+    let (y2, u2, v2) = yuv(b);
+    (y1 - y2).abs() <= 48 && (u1 - u2).abs() <= 7 && (v1 - v2).abs() <= 6
+}
+
+fn mix(colors: &[(Rgb, u32)]) -> Rgb {
+    let total: u32 = colors.iter().map(|&(_, w)| w).sum();
+    let mut out = [0; 3];
+    for (channel, value) in out.iter_mut().enumerate() {
+        let sum: u32 = colors.iter().map(|&(c, w)| c[channel] as u32 * w).sum();
+        *value = (sum / total) as u8;
+    }
+    out
+}
+
+// A simplified HQ2x: edges are found by comparing colors in YUV, and the
+// corners of each pixel are interpolated with the neighbours along an edge
+pub fn hq2x(data: &[u8], width: usize, height: usize) -> Vec<u8> {
+    scale_with(data, width, height, 2, |p| {
+        let e = p[4];
+        // Corner, the two neighbours next to it and the diagonal neighbour
+        [(1, 3, 0), (1, 5, 2), (7, 3, 6), (7, 5, 8)].map(|(v, h, diagonal)| {
+            let (v, h, diagonal) = (p[v], p[h], p[diagonal]);
+            if similar(v, h) && !similar(v, e) {
+                mix(&[(e, 2), (v, 1), (h, 1)])
+            } else if !similar(e, diagonal) && (similar(e, v) || similar(e, h)) {
+                mix(&[(e, 3), (diagonal, 1)])
+            } else {
+                e
+            }
+        })
+    })
+}
+
+// Every pixel becomes 3x3, with the right and bottom row darkened
+pub fn lcd_grid(data: &[u8], width: usize, height: usize) -> Vec<u8> {
+    scale_with(data, width, height, 3, |p| {
+        let e = p[4];
+        let line = e.map(|v| (v as u32 * 3 / 4) as u8);
+        [e, e, line, e, e, line, line, line, line]
+    })
+}
+
+// Mixes every frame with the previous output, so the image fades out like on
+// the slow LCD of the DMG. Games that flicker sprites every other frame use
+// this for transparency.
+pub struct FrameBlender {
+    previous: Vec<u8>,
+    // Weight of the previous frame, out of 256
+    weight: u32,
+}
+
+impl FrameBlender {
+    pub fn new(amount: f32) -> FrameBlender {
+        FrameBlender {
+            previous: Vec::new(),
+            weight: (amount.clamp(0.0, 1.0) * 256.0) as u32,
+        }
+    }
+
+    pub fn blend(&mut self, frame: &[u8]) -> Vec<u8> {
+        let out = if self.previous.len() == frame.len() {
+            frame
+                .iter()
+                .zip(self.previous.iter())
+                .map(|(&c, &p)| ((c as u32 * (256 - self.weight) + p as u32 * self.weight) >> 8) as u8)
+                .collect()
+        } else {
+            frame.to_vec()
+        };
+        self.previous.clear();
+        self.previous.extend_from_slice(&out);
+        out
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Filter, FrameBlender};
+
+    #[test]
+    fn scale2x_diagonal() {
+        // The steps of a black diagonal line on white are filled in, flat areas stay the same
+        let (w, b) = (255, 0);
+        let mut data = Vec::new();
+        for y in 0..3 {
+            for x in 0..3 {
+                let v = if x == y { b } else { w };
+                data.extend_from_slice(&[v, v, v]);
+            }
+        }
+        let out = Filter::Scale2x.apply(&data, 3, 3);
+        assert_eq!(out.len(), 6 * 6 * 3);
+        let at = |x: usize, y: usize| out[(y * 6 + x) * 3];
+        assert_eq!(at(2, 2), b);
+        assert_eq!(at(2, 1), b);
+        assert_eq!(at(3, 1), w);
+        assert_eq!(at(0, 5), w);
+
+        let flat = vec![0x80; 4 * 4 * 3];
+        for filter in [Filter::Scale3x, Filter::Hq2x].iter() {
+            let out = filter.apply(&flat, 4, 4);
+            assert_eq!(out.len(), flat.len() * filter.scale() * filter.scale());
+            assert!(out.iter().all(|&v| v == 0x80));
+        }
+    }
+
+    #[test]
+    fn frame_blending() {
+        let mut blender = FrameBlender::new(0.5);
+        assert_eq!(blender.blend(&[200, 0, 100]), vec![200, 0, 100]);
+        assert_eq!(blender.blend(&[0, 200, 100]), vec![100, 100, 100]);
+        // Older frames fade out instead of disappearing after one frame
+        assert_eq!(blender.blend(&[0, 0, 100]), vec![50, 50, 100]);
+    }
+}
@@ -10,3 +10,40 @@ pub enum GbSpeed {
     Single,
     Double,
 }
+
+// The hardware being emulated, which decides the state left by the boot ROM
+// and a few behaviours that differ between the models
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub enum Model {
+    Dmg0,
+    Dmg,
+    Mgb,
+    Sgb,
+    Sgb2,
+    Cgb,
+    Agb,
+}
+
+impl Model {
+    pub fn from_name(name: &str) -> Option<Model> {
+        match name {
+            "dmg0" => Some(Model::Dmg0),
+            "dmg" => Some(Model::Dmg),
+            "mgb" => Some(Model::Mgb),
+            "sgb" => Some(Model::Sgb),
+            "sgb2" => Some(Model::Sgb2),
+            "cgb" => Some(Model::Cgb),
+            "agb" => Some(Model::Agb),
+            _ => None,
+        }
+    }
+
+    pub fn is_sgb(self) -> bool {
+        self == Model::Sgb || self == Model::Sgb2
+    }
+
+    // Whether the model can run games in color
+    pub fn is_cgb(self) -> bool {
+        self == Model::Cgb || self == Model::Agb
+    }
+}
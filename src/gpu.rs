@@ -1,15 +1,30 @@
-use crate::gbmode::GbMode;
+use crate::gbmode::{GbMode, Model};
+use crate::palette::{ColorCorrection, CompatColors, DmgPalette, Shades};
+use crate::state::{StateReader, StateWriter};
+use crate::StrResult;
 
 const VRAM_SIZE: usize = 0x4000;
 const VOAM_SIZE: usize = 0xA0;
 pub const SCREEN_W: usize = 160;
 pub const SCREEN_H: usize = 144;
 
-#[derive(PartialEq, Copy, Clone)]
-enum PrioType {
-    Color0,
-    PrioFlag,
-    Normal,
+// A pixel in the sprite FIFO, color 0 is transparent
+#[derive(Copy, Clone, Default)]
+struct SpritePixel {
+    color: u8,
+    flags: u8,
+    index: u8,
+}
+
+// A sprite found on the current line during the OAM scan
+#[derive(Copy, Clone)]
+struct Sprite {
+    index: u8,
+    y: u8,
+    x: u8,
+    tile: u8,
+    flags: u8,
+    fetched: bool,
 }
 
 pub struct GPU {
@@ -29,6 +44,7 @@ pub struct GPU {
     m0_inte: bool,
     m1_inte: bool,
     m2_inte: bool,
+    stat_signal: bool,
     scy: u8,
     scx: u8,
     winy: u8,
@@ -36,9 +52,13 @@ pub struct GPU {
     palbr: u8,
     pal0r: u8,
     pal1r: u8,
-    palb: [u8; 4],
-    pal0: [u8; 4],
-    pal1: [u8; 4],
+    palb: Shades,
+    pal0: Shades,
+    pal1: Shades,
+    // Colors of the four shades in classic mode
+    dmg_palette: DmgPalette,
+    // Output color of every CGB color
+    color_lut: Vec<[u8; 3]>,
     vram: [u8; VRAM_SIZE],
     voam: [u8; VOAM_SIZE],
     cbgpal_inc: bool,
@@ -47,13 +67,48 @@ pub struct GPU {
     csprit_inc: bool,
     csprit_ind: u8,
     csprit: [[[u8; 3]; 4]; 8],
+    // OPRI bit 0, sprites are ordered by X coordinate instead of OAM index
+    opri: bool,
     vrambank: usize,
     pub data: Vec<u8>,
-    bgprio: [PrioType; SCREEN_W],
+    // Shade of every pixel in classic mode, which is what the SGB receives
+    pub shades: Vec<u8>,
     pub updated: bool,
     pub interrupt: u8,
     pub gbmode: GbMode,
+    pub model: Model,
+    // Locks the CPU out of VRAM, OAM and the CGB palettes while the PPU uses them
+    pub access_blocking: bool,
     hblanking: bool,
+    // Pixels pushed to the LCD on this line, and pixels still to be dropped
+    // for fine scrolling
+    lcd_x: u8,
+    discard: u8,
+    // Dots left of the discarded first tile fetch of a line
+    stall: u8,
+    // Background fetcher, which takes two dots per step
+    fetch_dot: u8,
+    fetch_x: u8,
+    fetch_tile: u8,
+    fetch_attr: u8,
+    fetch_lo: u8,
+    fetch_hi: u8,
+    window_active: bool,
+    // The window line counter only advances on lines where the window was drawn,
+    // and the window can only start once WY matched LY during the frame
+    window_line: u8,
+    wy_triggered: bool,
+    // With WX=166 the window also covers the whole next line
+    window_next_line: bool,
+    // The background FIFO only holds pixels of a single tile
+    bg_lo: u8,
+    bg_hi: u8,
+    bg_attr: u8,
+    bg_len: u8,
+    sprite_fifo: [SpritePixel; 8],
+    line_sprites: Vec<Sprite>,
+    sprite_fetch: Option<usize>,
+    sprite_dots: u8,
 }
 
 impl GPU {
@@ -75,6 +130,7 @@ impl GPU {
             m2_inte: false,
             m1_inte: false,
             m0_inte: false,
+            stat_signal: false,
             scy: 0,
             scx: 0,
             winy: 0,
@@ -82,29 +138,214 @@ impl GPU {
             palbr: 0,
             pal0r: 0,
             pal1r: 1,
-            palb: [0; 4],
-            pal0: [0; 4],
-            pal1: [0; 4],
+            palb: [[0; 3]; 4],
+            pal0: [[0; 3]; 4],
+            pal1: [[0; 3]; 4],
+            dmg_palette: DmgPalette::default(),
+            color_lut: ColorCorrection::default().lut(),
             vram: [0; VRAM_SIZE],
             voam: [0; VOAM_SIZE],
             data: vec![0; SCREEN_W * SCREEN_H * 3],
-            bgprio: [PrioType::Normal; SCREEN_W],
+            shades: vec![0; SCREEN_W * SCREEN_H],
             updated: false,
             interrupt: 0,
             gbmode: GbMode::Classic,
+            model: Model::Dmg,
+            access_blocking: true,
             cbgpal_inc: false,
             cbgpal_ind: 0,
             cbgpal: [[[0u8; 3]; 4]; 8],
             csprit_inc: false,
             csprit_ind: 0,
             csprit: [[[0u8; 3]; 4]; 8],
+            opri: false,
             vrambank: 0,
             hblanking: false,
+            lcd_x: 0,
+            discard: 0,
+            stall: 0,
+            fetch_dot: 0,
+            fetch_x: 0,
+            fetch_tile: 0,
+            fetch_attr: 0,
+            fetch_lo: 0,
+            fetch_hi: 0,
+            window_active: false,
+            window_line: 0,
+            wy_triggered: false,
+            window_next_line: false,
+            bg_lo: 0,
+            bg_hi: 0,
+            bg_attr: 0,
+            bg_len: 0,
+            sprite_fifo: [SpritePixel::default(); 8],
+            line_sprites: Vec::with_capacity(40),
+            sprite_fetch: None,
+            sprite_dots: 0,
         }
     }
 
-    pub fn new_cgb() -> GPU {
-        GPU::new()
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write_u8(self.mode);
+        w.write_u32(self.modeclock);
+        w.write_u8(self.line);
+        w.write_u8(self.lyc);
+        w.write_u8(self.rb(0xFF40));
+        w.write_u8(self.rb(0xFF41));
+        w.write_u8(self.scy);
+        w.write_u8(self.scx);
+        w.write_u8(self.winy);
+        w.write_u8(self.winx);
+        w.write_u8(self.palbr);
+        w.write_u8(self.pal0r);
+        w.write_u8(self.pal1r);
+        w.write_bytes(&self.vram);
+        w.write_bytes(&self.voam);
+        w.write_u8(self.rb(0xFF68));
+        w.write_u8(self.rb(0xFF6A));
+        for pal in self.cbgpal.iter().chain(self.csprit.iter()) {
+            for col in pal.iter() {
+                w.write_bytes(col);
+            }
+        }
+        w.write_bool(self.opri);
+        w.write_u8(self.vrambank as u8);
+        w.write_bytes(&self.data);
+        w.write_u8(self.interrupt);
+
+        w.write_bool(self.stat_signal);
+        w.write_u8(self.lcd_x);
+        w.write_u8(self.discard);
+        w.write_u8(self.stall);
+        w.write_bytes(&[
+            self.fetch_dot,
+            self.fetch_x,
+            self.fetch_tile,
+            self.fetch_attr,
+            self.fetch_lo,
+            self.fetch_hi,
+        ]);
+        w.write_bool(self.window_active);
+        w.write_u8(self.window_line);
+        w.write_bool(self.wy_triggered);
+        w.write_bool(self.window_next_line);
+        w.write_bytes(&[self.bg_lo, self.bg_hi, self.bg_attr, self.bg_len]);
+        for pixel in self.sprite_fifo.iter() {
+            w.write_bytes(&[pixel.color, pixel.flags, pixel.index]);
+        }
+        w.write_u8(self.line_sprites.len() as u8);
+        for sprite in self.line_sprites.iter() {
+            w.write_bytes(&[sprite.index, sprite.y, sprite.x, sprite.tile, sprite.flags]);
+            w.write_bool(sprite.fetched);
+        }
+        w.write_u8(self.sprite_fetch.map_or(0xFF, |i| i as u8));
+        w.write_u8(self.sprite_dots);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> StrResult<()> {
+        self.mode = r.read_u8()? & 0x03;
+        self.modeclock = r.read_u32()?;
+        self.line = r.read_u8()?;
+        self.lyc = r.read_u8()?;
+
+        let lcdc = r.read_u8()?;
+        self.lcd_on = lcdc & 0x80 == 0x80;
+        self.win_tilemap = if lcdc & 0x40 == 0x40 { 0x9C00 } else { 0x9800 };
+        self.win_on = lcdc & 0x20 == 0x20;
+        self.tilebase = if lcdc & 0x10 == 0x10 { 0x8000 } else { 0x8800 };
+        self.bg_tilemap = if lcdc & 0x08 == 0x08 { 0x9C00 } else { 0x9800 };
+        self.sprite_size = if lcdc & 0x04 == 0x04 { 16 } else { 8 };
+        self.sprite_on = lcdc & 0x02 == 0x02;
+        self.lcdc0 = lcdc & 0x01 == 0x01;
+
+        let stat = r.read_u8()?;
+        self.lyc_inte = stat & 0x40 == 0x40;
+        self.m2_inte = stat & 0x20 == 0x20;
+        self.m1_inte = stat & 0x10 == 0x10;
+        self.m0_inte = stat & 0x08 == 0x08;
+
+        self.scy = r.read_u8()?;
+        self.scx = r.read_u8()?;
+        self.winy = r.read_u8()?;
+        self.winx = r.read_u8()?;
+        self.palbr = r.read_u8()?;
+        self.pal0r = r.read_u8()?;
+        self.pal1r = r.read_u8()?;
+        self.update_pal();
+        r.read_bytes(&mut self.vram)?;
+        r.read_bytes(&mut self.voam)?;
+
+        let cbgpal = r.read_u8()?;
+        self.cbgpal_ind = cbgpal & 0x3F;
+        self.cbgpal_inc = cbgpal & 0x80 == 0x80;
+        let csprit = r.read_u8()?;
+        self.csprit_ind = csprit & 0x3F;
+        self.csprit_inc = csprit & 0x80 == 0x80;
+        for pal in self.cbgpal.iter_mut().chain(self.csprit.iter_mut()) {
+            for col in pal.iter_mut() {
+                r.read_bytes(col)?;
+            }
+        }
+
+        self.opri = r.read_bool()?;
+        self.vrambank = (r.read_u8()? & 0x01) as usize;
+        r.read_bytes(&mut self.data)?;
+        self.interrupt = r.read_u8()?;
+
+        self.stat_signal = r.read_bool()?;
+        // The position stays at the end of the line during HBlank
+        let last_x = if self.mode == 3 { SCREEN_W - 1 } else { SCREEN_W };
+        self.lcd_x = r.read_u8()?.min(last_x as u8);
+        self.discard = r.read_u8()? & 0x07;
+        self.stall = r.read_u8()?;
+        let mut fetcher = [0; 6];
+        r.read_bytes(&mut fetcher)?;
+        self.fetch_dot = fetcher[0].min(6);
+        self.fetch_x = fetcher[1];
+        self.fetch_tile = fetcher[2];
+        self.fetch_attr = fetcher[3];
+        self.fetch_lo = fetcher[4];
+        self.fetch_hi = fetcher[5];
+        self.window_active = r.read_bool()?;
+        self.window_line = r.read_u8()?;
+        self.wy_triggered = r.read_bool()?;
+        self.window_next_line = r.read_bool()?;
+        let mut bg = [0; 4];
+        r.read_bytes(&mut bg)?;
+        self.bg_lo = bg[0];
+        self.bg_hi = bg[1];
+        self.bg_attr = bg[2];
+        self.bg_len = bg[3].min(8);
+        for pixel in self.sprite_fifo.iter_mut() {
+            let mut bytes = [0; 3];
+            r.read_bytes(&mut bytes)?;
+            *pixel = SpritePixel {
+                color: bytes[0] & 0x03,
+                flags: bytes[1],
+                index: bytes[2],
+            };
+        }
+        self.line_sprites.clear();
+        for _ in 0..r.read_u8()?.min(40) {
+            let mut bytes = [0; 5];
+            r.read_bytes(&mut bytes)?;
+            self.line_sprites.push(Sprite {
+                index: bytes[0],
+                y: bytes[1],
+                x: bytes[2],
+                tile: bytes[3],
+                flags: bytes[4],
+                fetched: r.read_bool()?,
+            });
+        }
+        self.sprite_fetch = match r.read_u8()? as usize {
+            i if i < self.line_sprites.len() => Some(i),
+            _ => None,
+        };
+        self.sprite_dots = r.read_u8()?;
+
+        self.updated = true;
+        Ok(())
     }
 
     pub fn do_cycle(&mut self, ticks: u32) {
@@ -116,67 +357,136 @@ impl GPU {
         let mut ticksleft = ticks;
 
         while ticksleft > 0 {
-            let curticks = if ticksleft >= 80 { 80 } else { ticksleft };
+            if self.mode == 3 {
+                self.mode3_dot();
+                self.modeclock += 1;
+                ticksleft -= 1;
+                continue;
+            }
+
+            // Outside of mode 3 nothing happens until the next mode or line change
+            let next: u32 = if self.line < 144 && self.modeclock < 80 {
+                80
+            } else if self.line == 153 && self.modeclock < 4 {
+                4
+            } else {
+                456
+            };
+            let curticks = ::std::cmp::min(ticksleft, next.saturating_sub(self.modeclock));
             self.modeclock += curticks;
             ticksleft -= curticks;
 
-            // Full line takes 114 ticks
+            // Full line takes 456 dots
             if self.modeclock >= 456 {
                 self.modeclock -= 456;
                 self.line = (self.line + 1) % 154;
-                self.check_interrupt_lyc();
-
-                // This is a VBlank line
-                if self.line >= 144 && self.mode != 1 {
+                if self.line == 144 {
                     self.change_mode(1);
-                }
-            }
-
-            // This is a normal line
-            if self.line < 144 {
-                if self.modeclock <= 80 {
-                    if self.mode != 2 {
-                        self.change_mode(2);
-                    }
-                } else if self.modeclock <= (80 + 172) {
-                    // 252 cycles
-                    if self.mode != 3 {
-                        self.change_mode(3);
-                    }
+                } else if self.line < 144 {
+                    self.change_mode(2);
                 } else {
-                    // the remaining 204
-                    if self.mode != 0 {
-                        self.change_mode(0);
-                    }
+                    self.update_stat();
                 }
+            } else if self.modeclock == 80 && self.line < 144 {
+                self.start_mode3();
+            } else if self.modeclock == 4 && self.line == 153 {
+                // LY already reads 0 during most of the last line
+                self.update_stat();
             }
         }
     }
 
-    fn check_interrupt_lyc(&mut self) {
-        if self.lyc_inte && self.line == self.lyc {
+    fn ly(&self) -> u8 {
+        if self.line == 153 && self.modeclock >= 4 {
+            0
+        } else {
+            self.line
+        }
+    }
+
+    // The STAT interrupt fires on a rising edge of all enabled conditions combined
+    fn update_stat(&mut self) {
+        let signal = (self.lyc_inte && self.ly() == self.lyc)
+            || (self.m0_inte && self.mode == 0)
+            || (self.m1_inte && self.mode == 1)
+            || (self.m2_inte && self.mode == 2);
+        if signal && !self.stat_signal {
             self.interrupt |= 0x02;
         }
+        self.stat_signal = signal;
     }
 
     fn change_mode(&mut self, mode: u8) {
         self.mode = mode;
 
-        if match self.mode {
-            0 => {
-                self.renderscan();
-                self.hblanking = true;
-                self.m0_inte
-            }
+        match self.mode {
+            0 => self.hblanking = true,
             1 => {
                 self.interrupt |= 0x01;
                 self.updated = true;
-                self.m1_inte
+                self.window_line = 0;
+                self.wy_triggered = false;
+                self.window_next_line = false;
             }
-            2 => self.m2_inte,
+            _ => {}
+        }
+        self.update_stat();
+    }
+
+    fn start_mode3(&mut self) {
+        self.scan_oam();
+        self.lcd_x = 0;
+        self.discard = self.scx & 0x07;
+        self.stall = 6;
+        self.fetch_dot = 0;
+        self.fetch_x = 0;
+        self.bg_len = 0;
+        self.sprite_fifo = [SpritePixel::default(); 8];
+        if self.win_on && self.line == self.winy {
+            self.wy_triggered = true;
+        }
+        self.window_active = self.window_next_line && self.window_visible();
+        self.window_next_line = false;
+        if self.window_active {
+            self.discard = 0;
+        }
+        self.sprite_fetch = None;
+        self.change_mode(3);
+    }
+
+    // Selects the first 10 sprites in OAM that overlap the line
+    fn scan_oam(&mut self) {
+        self.line_sprites.clear();
+        let line = self.line as u32 + 16;
+        for index in 0..40 {
+            if self.line_sprites.len() == 10 {
+                break;
+            }
+            let y = self.voam[index * 4];
+            if line < y as u32 || line >= y as u32 + self.sprite_size {
+                continue;
+            }
+            self.line_sprites.push(Sprite {
+                index: index as u8,
+                y,
+                x: self.voam[index * 4 + 1],
+                tile: self.voam[index * 4 + 2],
+                flags: self.voam[index * 4 + 3],
+                fetched: false,
+            });
+        }
+    }
+
+    // OAM is in use during the OAM scan and rendering, VRAM and the palettes only
+    // during rendering
+    pub fn is_blocked(&self, a: u16) -> bool {
+        if !self.access_blocking {
+            return false;
+        }
+        match a {
+            0x8000..=0x9FFF | 0xFF69 | 0xFF6B => self.mode == 3,
+            0xFE00..=0xFE9F => self.mode == 2 || self.mode == 3,
             _ => false,
-        } {
-            self.interrupt |= 0x02;
         }
     }
 
@@ -195,16 +505,16 @@ impl GPU {
                     | (if self.lcdc0 { 0x01 } else { 0 })
             }
             0xFF41 => {
-                (if self.lyc_inte { 0x40 } else { 0 })
+                0x80 | (if self.lyc_inte { 0x40 } else { 0 })
                     | (if self.m2_inte { 0x20 } else { 0 })
                     | (if self.m1_inte { 0x10 } else { 0 })
                     | (if self.m0_inte { 0x08 } else { 0 })
-                    | (if self.line == self.lyc { 0x04 } else { 0 })
+                    | (if self.ly() == self.lyc { 0x04 } else { 0 })
                     | self.mode
             }
             0xFF42 => self.scy,
             0xFF43 => self.scx,
-            0xFF44 => self.line,
+            0xFF44 => self.ly(),
             0xFF45 => self.lyc,
             0xFF46 => 0, // Write only
             0xFF47 => self.palbr,
@@ -235,6 +545,10 @@ impl GPU {
                         | (self.csprit[palnum][colnum][2] << 2)
                 }
             }
+            0xFF6C => match self.gbmode {
+                GbMode::Color => 0xFE | (self.opri as u8),
+                _ => 0xFF,
+            },
             _ => panic!("GPU does not handle read {:04X}", a),
         }
     }
@@ -272,17 +586,37 @@ impl GPU {
                     self.mode = 0;
                     self.clear_screen();
                 }
+                // The first line after turning on the LCD stays in mode 0 instead of doing an OAM scan
+                if !orig_lcd_on && self.lcd_on {
+                    self.update_stat();
+                }
             }
             0xFF41 => {
+                // On the monochrome models a write briefly enables the LYC, mode 0
+                // and mode 1 sources, which can raise an interrupt
+                if self.lcd_on && !self.model.is_cgb() {
+                    self.lyc_inte = true;
+                    self.m0_inte = true;
+                    self.m1_inte = true;
+                    self.update_stat();
+                }
                 self.lyc_inte = v & 0x40 == 0x40;
                 self.m2_inte = v & 0x20 == 0x20;
                 self.m1_inte = v & 0x10 == 0x10;
                 self.m0_inte = v & 0x08 == 0x08;
+                if self.lcd_on {
+                    self.update_stat();
+                }
             }
             0xFF42 => self.scy = v,
             0xFF43 => self.scx = v,
             0xFF44 => {} // Read-only
-            0xFF45 => self.lyc = v,
+            0xFF45 => {
+                self.lyc = v;
+                if self.lcd_on {
+                    self.update_stat();
+                }
+            }
             0xFF46 => panic!("0xFF46 should be handled by MMU"),
             0xFF47 => {
                 self.palbr = v;
@@ -339,249 +673,512 @@ impl GPU {
                     self.csprit_ind = (self.csprit_ind + 1) & 0x3F;
                 };
             }
+            0xFF6C => {
+                if self.gbmode == GbMode::Color {
+                    self.opri = v & 0x01 == 0x01;
+                }
+            }
             _ => panic!("GPU does not handle write {:04X}", a),
         }
     }
 
     fn clear_screen(&mut self) {
-        for v in self.data.iter_mut() {
-            *v = 255;
+        let blank = match self.gbmode {
+            GbMode::Classic => self.dmg_palette.bg[0],
+            _ => [255; 3],
+        };
+        for pixel in self.data.chunks_mut(3) {
+            pixel.copy_from_slice(&blank);
+        }
+        for shade in self.shades.iter_mut() {
+            *shade = 0;
         }
         self.updated = true;
     }
 
+    pub fn set_dmg_palette(&mut self, palette: DmgPalette) {
+        self.dmg_palette = palette;
+        self.update_pal();
+    }
+
+    // Loads the palettes the CGB boot ROM sets up for monochrome games
+    pub fn set_compat_palette(&mut self, colors: CompatColors) {
+        let split = |c: u16| [(c & 0x1F) as u8, ((c >> 5) & 0x1F) as u8, ((c >> 10) & 0x1F) as u8];
+        self.cbgpal[0] = colors[0].map(split);
+        self.csprit[0] = colors[1].map(split);
+        self.csprit[1] = colors[2].map(split);
+    }
+
+    pub fn set_color_correction(&mut self, correction: ColorCorrection) {
+        self.color_lut = correction.lut();
+    }
+
     fn update_pal(&mut self) {
         for i in 0..4 {
-            self.palb[i] = GPU::get_monochrome_pal_val(self.palbr, i);
-            self.pal0[i] = GPU::get_monochrome_pal_val(self.pal0r, i);
-            self.pal1[i] = GPU::get_monochrome_pal_val(self.pal1r, i);
+            self.palb[i] = self.dmg_palette.bg[GPU::get_monochrome_pal_val(self.palbr, i)];
+            self.pal0[i] = self.dmg_palette.obj0[GPU::get_monochrome_pal_val(self.pal0r, i)];
+            self.pal1[i] = self.dmg_palette.obj1[GPU::get_monochrome_pal_val(self.pal1r, i)];
         }
     }
 
-    fn get_monochrome_pal_val(value: u8, index: usize) -> u8 {
-        match (value >> 2 * index) & 0x03 {
-            0 => 255,
-            1 => 192,
-            2 => 96,
-            _ => 0,
-        }
+    fn get_monochrome_pal_val(value: u8, index: usize) -> usize {
+        ((value >> (2 * index)) & 0x03) as usize
     }
 
-    fn renderscan(&mut self) {
-        for x in 0..SCREEN_W {
-            self.setcolor(x, 255);
-            self.bgprio[x] = PrioType::Normal;
+    fn mode3_dot(&mut self) {
+        if self.stall > 0 {
+            self.stall -= 1;
+            return;
+        }
+
+        // A sprite fetch waits for the current background fetch and pauses the FIFO
+        if self.sprite_fetch.is_none() && self.sprite_on && self.bg_len > 0 {
+            self.sprite_fetch = self.next_sprite();
+            self.sprite_dots = 0;
+        }
+        if let Some(i) = self.sprite_fetch {
+            if self.fetch_dot < 5 {
+                self.fetch_bg();
+                return;
+            }
+            self.sprite_dots += 1;
+            if self.sprite_dots == 6 {
+                self.fetch_sprite(i);
+                self.sprite_fetch = None;
+            }
+            return;
+        }
+
+        if !self.window_active && self.window_visible() && self.lcd_x + 7 >= self.winx {
+            self.window_active = true;
+            self.window_next_line = self.winx == 166;
+            // With WX below 7 the first pixels of the window are off screen, and
+            // with WX=0 the window also shifts with the fine scroll of SCX
+            self.discard = match self.winx {
+                0 => 7 + (self.scx & 0x07),
+                wx => 7u8.saturating_sub(wx),
+            };
+            self.fetch_dot = 0;
+            self.fetch_x = 0;
+            self.bg_len = 0;
+        }
+
+        if self.bg_len > 0 {
+            self.shift_pixel();
         }
-        self.draw_bg();
-        self.draw_sprites();
+        self.fetch_bg();
     }
 
-    fn setcolor(&mut self, x: usize, color: u8) {
-        self.data[self.line as usize * SCREEN_W * 3 + x * 3 + 0] = color;
-        self.data[self.line as usize * SCREEN_W * 3 + x * 3 + 1] = color;
-        self.data[self.line as usize * SCREEN_W * 3 + x * 3 + 2] = color;
+    fn window_visible(&self) -> bool {
+        self.win_on
+            && (self.gbmode == GbMode::Color || self.lcdc0)
+            && self.wy_triggered
+            && self.winx <= 166
     }
 
-    fn setrgb(&mut self, x: usize, r: u8, g: u8, b: u8) {
-        // Gameboy Color RGB correction
-        // Taken from the Gambatte emulator
-        // assume r, g and b are between 0 and 1F
-        let baseidx = self.line as usize * SCREEN_W * 3 + x * 3;
+    // The leftmost sprite that has been reached, in OAM order for equal positions
+    fn next_sprite(&self) -> Option<usize> {
+        let mut result: Option<usize> = None;
+        for (i, sprite) in self.line_sprites.iter().enumerate() {
+            if sprite.fetched || sprite.x > self.lcd_x + 8 {
+                continue;
+            }
+            match result {
+                Some(j) if self.line_sprites[j].x <= sprite.x => {}
+                _ => result = Some(i),
+            }
+        }
+        result
+    }
 
-        let r = r as u32;
-        let g = g as u32;
-        let b = b as u32;
+    fn fetch_bg(&mut self) {
+        match self.fetch_dot {
+            1 => {
+                let address = if self.window_active {
+                    let winline = self.window_line as u16;
+                    self.win_tilemap + (winline >> 3) * 32 + (self.fetch_x as u16 & 31)
+                } else {
+                    let bgy = self.scy.wrapping_add(self.line) as u16;
+                    let tilex = ((self.scx >> 3) as u16 + self.fetch_x as u16) & 31;
+                    self.bg_tilemap + (bgy >> 3) * 32 + tilex
+                };
+                self.fetch_tile = self.rbvram0(address);
+                self.fetch_attr = if self.gbmode == GbMode::Color {
+                    self.rbvram1(address)
+                } else {
+                    0
+                };
+            }
+            3 => self.fetch_lo = self.read_bg_tile(0),
+            5 => self.fetch_hi = self.read_bg_tile(1),
+            _ => {}
+        }
+        if self.fetch_dot < 6 {
+            self.fetch_dot += 1;
+        }
 
-        self.data[baseidx + 0] = ((r * 13 + g * 2 + b) >> 1) as u8;
-        self.data[baseidx + 1] = ((g * 3 + b) << 1) as u8;
-        self.data[baseidx + 2] = ((r * 3 + g * 2 + b * 11) >> 1) as u8;
+        // The tile is pushed as soon as the FIFO is empty
+        if self.fetch_dot == 6 && self.bg_len == 0 {
+            let xflip = self.fetch_attr & 0x20 != 0;
+            self.bg_lo = if xflip { self.fetch_lo.reverse_bits() } else { self.fetch_lo };
+            self.bg_hi = if xflip { self.fetch_hi.reverse_bits() } else { self.fetch_hi };
+            self.bg_attr = self.fetch_attr;
+            self.bg_len = 8;
+            self.fetch_x = self.fetch_x.wrapping_add(1);
+            self.fetch_dot = 0;
+        }
     }
 
-    fn draw_bg(&mut self) {
-        let drawbg = self.gbmode == GbMode::Color || self.lcdc0;
-
-        let winy = if !self.win_on || (self.gbmode != GbMode::Classic && !self.lcdc0) {
-            -1
+    fn read_bg_tile(&self, offset: u16) -> u8 {
+        let pixely = if self.window_active {
+            self.window_line as u16 & 0x07
         } else {
-            self.line as i32 - self.winy as i32
+            self.scy.wrapping_add(self.line) as u16 & 0x07
         };
+        let pixely = if self.fetch_attr & 0x40 != 0 { 7 - pixely } else { pixely };
 
-        if winy < 0 && drawbg == false {
-            return;
-        }
-
-        let wintiley = (winy as u16 >> 3) & 31;
-
-        let bgy = self.scy.wrapping_add(self.line);
-        let bgtiley = (bgy as u16 >> 3) & 31;
-
-        for x in 0..SCREEN_W {
-            let winx = -((self.winx as i32) - 7) + (x as i32);
-            let bgx = self.scx as u32 + x as u32;
-
-            let (tilemapbase, tiley, tilex, pixely, pixelx) = if winy >= 0 && winx >= 0 {
-                (
-                    self.win_tilemap,
-                    wintiley,
-                    (winx as u16 >> 3),
-                    winy as u16 & 0x07,
-                    winx as u8 & 0x07,
-                )
-            } else if drawbg {
-                (
-                    self.bg_tilemap,
-                    bgtiley,
-                    (bgx as u16 >> 3) & 31,
-                    bgy as u16 & 0x07,
-                    bgx as u8 & 0x07,
-                )
+        let tileaddress = self.tilebase
+            + (if self.tilebase == 0x8000 {
+                self.fetch_tile as u16
             } else {
-                continue;
-            };
+                (self.fetch_tile as i8 as i16 + 128) as u16
+            }) * 16;
 
-            let tilenr: u8 = self.rbvram0(tilemapbase + tiley * 32 + tilex);
-
-            let (palnr, vram1, xflip, yflip, prio) = if self.gbmode == GbMode::Color {
-                let flags = self.rbvram1(tilemapbase + tiley * 32 + tilex) as usize;
-                (
-                    flags & 0x07,
-                    flags & (1 << 3) != 0,
-                    flags & (1 << 5) != 0,
-                    flags & (1 << 6) != 0,
-                    flags & (1 << 7) != 0,
-                )
-            } else {
-                (0, false, false, false, false)
-            };
+        let address = tileaddress + pixely * 2 + offset;
+        match self.fetch_attr & 0x08 != 0 {
+            false => self.rbvram0(address),
+            true => self.rbvram1(address),
+        }
+    }
 
-            let tileaddress = self.tilebase
-                + (if self.tilebase == 0x8000 {
-                    tilenr as u16
-                } else {
-                    (tilenr as i8 as i16 + 128) as u16
-                }) * 16;
+    fn fetch_sprite(&mut self, i: usize) {
+        self.line_sprites[i].fetched = true;
+        let sprite = self.line_sprites[i];
+        let sprite_size = self.sprite_size as i32;
 
-            let a0 = match yflip {
-                false => tileaddress + (pixely * 2),
-                true => tileaddress + (14 - (pixely * 2)),
-            };
+        let row = self.line as i32 + 16 - sprite.y as i32;
+        let tiley = if sprite.flags & 0x40 != 0 { sprite_size - 1 - row } else { row } as u16;
+        let tilenum = (sprite.tile & (if sprite_size == 16 { 0xFE } else { 0xFF })) as u16;
 
-            let (b1, b2) = match vram1 {
-                false => (self.rbvram0(a0), self.rbvram0(a0 + 1)),
-                true => (self.rbvram1(a0), self.rbvram1(a0 + 1)),
-            };
+        let tileaddress = 0x8000u16 + tilenum * 16 + (tiley & 0x0F) * 2;
+        let (b1, b2) = if sprite.flags & 0x08 != 0 && self.gbmode == GbMode::Color {
+            (self.rbvram1(tileaddress), self.rbvram1(tileaddress + 1))
+        } else {
+            (self.rbvram0(tileaddress), self.rbvram0(tileaddress + 1))
+        };
 
-            let xbit = match xflip {
-                true => pixelx,
-                false => 7 - pixelx,
-            } as u32;
-            let colnr = if b1 & (1 << xbit) != 0 { 1 } else { 0 }
-                | if b2 & (1 << xbit) != 0 { 2 } else { 0 };
-
-            self.bgprio[x] = if colnr == 0 {
-                PrioType::Color0
-            } else if prio {
-                PrioType::PrioFlag
-            } else {
-                PrioType::Normal
-            };
-            if self.gbmode == GbMode::Color {
-                let r = self.cbgpal[palnr][colnr][0];
-                let g = self.cbgpal[palnr][colnr][1];
-                let b = self.cbgpal[palnr][colnr][2];
-                self.setrgb(x as usize, r, g, b);
-            } else {
-                let color = self.palb[colnr];
-                self.setcolor(x, color);
+        for x in 0..8 {
+            let slot = sprite.x as i32 - 8 + x - self.lcd_x as i32;
+            if !(0..8).contains(&slot) {
+                continue;
+            }
+            let xbit = 1 << (if sprite.flags & 0x20 != 0 { x } else { 7 - x } as u32);
+            let colnr = (if b1 & xbit != 0 { 1 } else { 0 }) | (if b2 & xbit != 0 { 2 } else { 0 });
+            if colnr == 0 {
+                continue;
+            }
+
+            // Sprites are fetched from left to right, so with X priority the
+            // pixels already in the FIFO win. Otherwise the lowest OAM index wins.
+            let x_priority = self.x_priority();
+            let current = &mut self.sprite_fifo[slot as usize];
+            if current.color == 0 || (!x_priority && sprite.index < current.index) {
+                *current = SpritePixel {
+                    color: colnr,
+                    flags: sprite.flags,
+                    index: sprite.index,
+                };
             }
         }
     }
 
-    fn draw_sprites(&mut self) {
-        if !self.sprite_on {
+    fn x_priority(&self) -> bool {
+        self.gbmode != GbMode::Color || self.opri
+    }
+
+    fn shift_pixel(&mut self) {
+        let colnr = ((self.bg_hi >> 6) & 0x02) | (self.bg_lo >> 7);
+        self.bg_lo <<= 1;
+        self.bg_hi <<= 1;
+        self.bg_len -= 1;
+
+        if self.discard > 0 {
+            self.discard -= 1;
             return;
         }
 
-        // TODO: limit of 10 sprites per line
+        let sprite = self.sprite_fifo[0];
+        self.sprite_fifo.copy_within(1.., 0);
+        self.sprite_fifo[7] = SpritePixel::default();
 
-        for index in 0..40 {
-            let i = 39 - index;
-            let spriteaddr = 0xFE00 + (i as u16) * 4;
-            let spritey = self.rb(spriteaddr + 0) as u16 as i32 - 16;
-            let spritex = self.rb(spriteaddr + 1) as u16 as i32 - 8;
-            let tilenum = (self.rb(spriteaddr + 2)
-                & (if self.sprite_size == 16 { 0xFE } else { 0xFF }))
-                as u16;
-            let flags = self.rb(spriteaddr + 3) as usize;
-            let usepal1: bool = flags & (1 << 4) != 0;
-            let xflip: bool = flags & (1 << 5) != 0;
-            let yflip: bool = flags & (1 << 6) != 0;
-            let belowbg: bool = flags & (1 << 7) != 0;
-            let c_palnr = flags & 0x07;
-            let c_vram1: bool = flags & (1 << 3) != 0;
-
-            let line = self.line as i32;
-            let sprite_size = self.sprite_size as i32;
-
-            if line < spritey || line >= spritey + sprite_size {
-                continue;
-            }
-            if spritex < -7 || spritex >= (SCREEN_W as i32) {
-                continue;
+        self.draw_pixel(colnr, sprite);
+        self.lcd_x += 1;
+        if self.lcd_x as usize == SCREEN_W {
+            if self.window_active {
+                self.window_line = self.window_line.wrapping_add(1);
             }
+            self.change_mode(0);
+        }
+    }
+
+    fn draw_pixel(&mut self, colnr: u8, sprite: SpritePixel) {
+        let x = self.lcd_x as usize;
+        let color = self.gbmode == GbMode::Color;
+        let bgcol = if !color && !self.lcdc0 { 0 } else { colnr as usize };
 
-            let tiley: u16 = if yflip {
-                (sprite_size - 1 - (line - spritey)) as u16
+        let behind_bg = bgcol != 0
+            && if color {
+                self.lcdc0 && (self.bg_attr & 0x80 != 0 || sprite.flags & 0x80 != 0)
             } else {
-                (line - spritey) as u16
+                sprite.flags & 0x80 != 0
             };
 
-            let tileaddress = 0x8000u16 + tilenum * 16 + tiley * 2;
-            let (b1, b2) = if c_vram1 && self.gbmode == GbMode::Color {
-                (self.rbvram1(tileaddress), self.rbvram1(tileaddress + 1))
+        if sprite.color != 0 && self.sprite_on && !behind_bg {
+            let spcol = sprite.color as usize;
+            if color {
+                let palnr = (sprite.flags & 0x07) as usize;
+                let r = self.csprit[palnr][spcol][0];
+                let g = self.csprit[palnr][spcol][1];
+                let b = self.csprit[palnr][spcol][2];
+                self.setrgb(x, r, g, b);
+            } else if self.gbmode == GbMode::ColorAsClassic {
+                // BGP and OBP select colors from the first CGB palettes
+                let (palnr, obp) = if sprite.flags & 0x10 != 0 {
+                    (1, self.pal1r)
+                } else {
+                    (0, self.pal0r)
+                };
+                let [r, g, b] = self.csprit[palnr][GPU::get_monochrome_pal_val(obp, spcol)];
+                self.setrgb(x, r, g, b);
             } else {
-                (self.rbvram0(tileaddress), self.rbvram0(tileaddress + 1))
+                let (color, obp) = if sprite.flags & 0x10 != 0 {
+                    (self.pal1[spcol], self.pal1r)
+                } else {
+                    (self.pal0[spcol], self.pal0r)
+                };
+                self.setcolor(x, color);
+                self.setshade(x, GPU::get_monochrome_pal_val(obp, spcol));
+            }
+        } else if color {
+            let palnr = (self.bg_attr & 0x07) as usize;
+            let r = self.cbgpal[palnr][bgcol][0];
+            let g = self.cbgpal[palnr][bgcol][1];
+            let b = self.cbgpal[palnr][bgcol][2];
+            self.setrgb(x, r, g, b);
+        } else if self.gbmode == GbMode::ColorAsClassic {
+            let shade = match self.lcdc0 {
+                true => GPU::get_monochrome_pal_val(self.palbr, bgcol),
+                false => 0,
             };
+            let [r, g, b] = self.cbgpal[0][shade];
+            self.setrgb(x, r, g, b);
+        } else if !self.lcdc0 {
+            let color = self.dmg_palette.bg[0];
+            self.setcolor(x, color);
+            self.setshade(x, 0);
+        } else {
+            let color = self.palb[bgcol];
+            self.setcolor(x, color);
+            self.setshade(x, GPU::get_monochrome_pal_val(self.palbr, bgcol));
+        }
+    }
 
-            'xloop: for x in 0..8 {
-                if spritex + x < 0 || spritex + x >= (SCREEN_W as i32) {
-                    continue;
-                }
+    fn setshade(&mut self, x: usize, shade: usize) {
+        self.shades[self.line as usize * SCREEN_W + x] = shade as u8;
+    }
 
-                let xbit = 1 << (if xflip { x } else { 7 - x } as u32);
-                let colnr =
-                    (if b1 & xbit != 0 { 1 } else { 0 }) | (if b2 & xbit != 0 { 2 } else { 0 });
-                if colnr == 0 {
-                    continue;
-                }
+    fn setcolor(&mut self, x: usize, color: [u8; 3]) {
+        let baseidx = self.line as usize * SCREEN_W * 3 + x * 3;
+        self.data[baseidx..baseidx + 3].copy_from_slice(&color);
+    }
 
-                if self.gbmode == GbMode::Color {
-                    if self.lcdc0
-                        && (self.bgprio[(spritex + x) as usize] == PrioType::PrioFlag
-                            || (belowbg && self.bgprio[(spritex + x) as usize] != PrioType::Color0))
-                    {
-                        continue 'xloop;
-                    }
-                    let r = self.csprit[c_palnr][colnr][0];
-                    let g = self.csprit[c_palnr][colnr][1];
-                    let b = self.csprit[c_palnr][colnr][2];
-                    self.setrgb((spritex + x) as usize, r, g, b);
-                } else {
-                    if belowbg && self.bgprio[(spritex + x) as usize] != PrioType::Color0 {
-                        continue 'xloop;
-                    }
-                    let color = if usepal1 {
-                        self.pal1[colnr]
-                    } else {
-                        self.pal0[colnr]
-                    };
-                    self.setcolor((spritex + x) as usize, color);
-                }
-            }
-        }
+    fn setrgb(&mut self, x: usize, r: u8, g: u8, b: u8) {
+        // r, g and b are between 0 and 1F
+        let color = self.color_lut[(r as usize) | ((g as usize) << 5) | ((b as usize) << 10)];
+        self.setcolor(x, color);
     }
 
     pub fn may_hdma(&self) -> bool {
         return self.hblanking;
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::{GPU, SCREEN_W};
+    use crate::gbmode::{GbMode, Model};
+
+    // Counts the dots spent in mode 3 on the second line after turning on the LCD
+    fn mode3_length(gpu: &mut GPU, lcdc: u8) -> u32 {
+        gpu.wb(0xFF40, lcdc);
+        gpu.do_cycle(456 + 80);
+        let mut dots = 0;
+        while gpu.rb(0xFF41) & 0x03 == 3 {
+            gpu.do_cycle(1);
+            dots += 1;
+        }
+        dots
+    }
+
+    #[test]
+    fn mode3_timing() {
+        let mut gpu = GPU::new();
+        assert_eq!(mode3_length(&mut gpu, 0x91), 172);
+
+        let mut gpu = GPU::new();
+        gpu.wb(0xFF43, 0x03);
+        assert_eq!(mode3_length(&mut gpu, 0x91), 175);
+
+        let mut gpu = GPU::new();
+        gpu.wb(0xFF4A, 0x00);
+        gpu.wb(0xFF4B, 0x57);
+        assert_eq!(mode3_length(&mut gpu, 0xB1), 178);
+
+        // A sprite at the start of the line waits for the first tile fetch
+        let mut gpu = GPU::new();
+        gpu.wb(0xFE00, 0x10);
+        gpu.wb(0xFE01, 0x08);
+        assert_eq!(mode3_length(&mut gpu, 0x93), 183);
+    }
+
+    #[test]
+    fn sprite_at_tile_start() {
+        // The first pixel of a tile must not leave the FIFO before the sprite
+        // starting there is fetched
+        for x in [8, 16, 88] {
+            let line = render_sprites(&mut GPU::new(), &[(x, 0x00)]);
+            let start = x as usize - 8;
+            assert_eq!(line[start], 0);
+            assert_eq!(line[start + 7], 0);
+            assert_eq!(line[start + 8], 255);
+        }
+    }
+
+    // Renders line 1 with sprites using a solid tile and returns the shade of each pixel
+    fn render_sprites(gpu: &mut GPU, sprites: &[(u8, u8)]) -> Vec<u8> {
+        for a in 0x8010..0x8020 {
+            gpu.wb(a, 0xFF);
+        }
+        gpu.wb(0xFF47, 0x00);
+        gpu.wb(0xFF48, 0xFF);
+        gpu.wb(0xFF49, 0x55);
+        for (i, &(x, flags)) in sprites.iter().enumerate() {
+            let address = 0xFE00 + i as u16 * 4;
+            gpu.wb(address, 0x11);
+            gpu.wb(address + 1, x);
+            gpu.wb(address + 2, 0x01);
+            gpu.wb(address + 3, flags);
+        }
+        gpu.wb(0xFF40, 0x93);
+        gpu.do_cycle(456 * 2);
+        (0..SCREEN_W).map(|x| gpu.data[(SCREEN_W + x) * 3]).collect()
+    }
+
+    #[test]
+    fn access_blocking() {
+        let mut gpu = GPU::new();
+        gpu.wb(0xFF40, 0x91);
+        assert!(!gpu.is_blocked(0x8000));
+        assert!(!gpu.is_blocked(0xFE00));
+
+        gpu.do_cycle(456);
+        assert!(gpu.is_blocked(0xFE00));
+        assert!(!gpu.is_blocked(0x8000));
+        assert!(!gpu.is_blocked(0xFF69));
+
+        gpu.do_cycle(80);
+        assert!(gpu.is_blocked(0xFE00));
+        assert!(gpu.is_blocked(0x8000));
+        assert!(gpu.is_blocked(0xFF6B));
+        assert!(!gpu.is_blocked(0xFF68));
+
+        gpu.access_blocking = false;
+        assert!(!gpu.is_blocked(0x8000));
+    }
+
+    #[test]
+    fn stat_write_quirk() {
+        // Writing STAT during HBlank raises an interrupt on DMG but not on CGB
+        for &(model, interrupt) in [(Model::Dmg, 0x02), (Model::Cgb, 0x00)].iter() {
+            let mut gpu = GPU::new();
+            gpu.model = model;
+            gpu.wb(0xFF45, 0x90);
+            gpu.wb(0xFF40, 0x91);
+            gpu.do_cycle(456 - 4);
+            gpu.interrupt = 0;
+            gpu.wb(0xFF41, 0x00);
+            assert_eq!(gpu.interrupt, interrupt);
+        }
+    }
+
+    #[test]
+    fn window_line_counter() {
+        let mut gpu = GPU::new();
+        // Tile 1 only has pixels on its third row
+        gpu.wb(0x8014, 0xFF);
+        for a in 0x9C00..0x9C20 {
+            gpu.wb(a, 0x01);
+        }
+        gpu.wb(0xFF47, 0xE4);
+        gpu.wb(0xFF4B, 0x07);
+
+        // The window is hidden on lines 2 and 3, so line 4 shows its third row
+        gpu.wb(0xFF40, 0xF1);
+        gpu.do_cycle(456 * 2);
+        gpu.wb(0xFF40, 0xD1);
+        gpu.do_cycle(456 * 2);
+        gpu.wb(0xFF40, 0xF1);
+        gpu.do_cycle(456);
+        assert_eq!(gpu.data[(4 * SCREEN_W) * 3], 192);
+        assert_eq!(gpu.data[SCREEN_W * 3], 255);
+    }
+
+    #[test]
+    fn sprite_limit() {
+        let sprites: Vec<(u8, u8)> = (0..11).map(|i| (8 + i * 12, 0x00)).collect();
+        let line = render_sprites(&mut GPU::new(), &sprites);
+        for i in 0..10 {
+            assert_eq!(line[i * 12], 0);
+        }
+        assert_eq!(line[120], 255);
+    }
+
+    #[test]
+    fn sprite_x_priority() {
+        // On DMG the leftmost sprite wins, even with a higher OAM index
+        let line = render_sprites(&mut GPU::new(), &[(20, 0x10), (16, 0x00)]);
+        assert_eq!(line[12], 0);
+        assert_eq!(line[15], 0);
+        assert_eq!(line[16], 192);
+
+        let line = render_sprites(&mut GPU::new(), &[(16, 0x10), (16, 0x00)]);
+        assert_eq!(line[8], 192);
+    }
+
+    #[test]
+    fn sprite_oam_priority() {
+        let cgb = |opri: u8| {
+            let mut gpu = GPU::new();
+            gpu.gbmode = GbMode::Color;
+            gpu.wb(0xFF6C, opri);
+            // Color 3 of OBJ palette 0 is white, palette 1 stays black
+            gpu.wb(0xFF6A, 0x06);
+            gpu.wb(0xFF6B, 0xFF);
+            gpu.wb(0xFF6A, 0x07);
+            gpu.wb(0xFF6B, 0x7F);
+            render_sprites(&mut gpu, &[(20, 0x01), (16, 0x00)])
+        };
+
+        // On CGB the lowest OAM index wins, even when it is further right
+        let line = cgb(0x00);
+        assert!(line[8] > 200);
+        assert_eq!(line[12], 0);
+        assert_eq!(line[19], 0);
+
+        // OPRI switches to the DMG order by X coordinate
+        let line = cgb(0x01);
+        assert!(line[12] > 200);
+        assert!(line[15] > 200);
+        assert_eq!(line[16], 0);
+    }
+}
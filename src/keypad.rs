@@ -1,8 +1,19 @@
+use crate::state::{StateReader, StateWriter};
+use crate::StrResult;
+
 pub struct Keypad {
     row0: u8,
     row1: u8,
     data: u8,
     pub interrupt: u8,
+    // SGB command packets are sent by pulsing P14 and P15
+    sgb: bool,
+    packet: [u8; 16],
+    packet_bit: Option<usize>,
+    sgb_packet: Option<[u8; 16]>,
+    // With SGB multiplayer the joypad to read is selected by pulsing P15
+    players: u8,
+    player: u8,
 }
 
 #[derive(Copy, Clone)]
@@ -24,6 +35,12 @@ impl Keypad {
             row1: 0x0F,
             data: 0,
             interrupt: 0,
+            sgb: false,
+            packet: [0; 16],
+            packet_bit: None,
+            sgb_packet: None,
+            players: 1,
+            player: 0,
         };
         res.update();
         return res
@@ -34,16 +51,115 @@ impl Keypad {
     }
 
     pub fn wb(&mut self, value: u8) {
+        let old = self.data & 0x30;
         self.data = value;
+        if self.sgb {
+            self.receive_bit(old, value & 0x30);
+            if self.players > 1 && old & 0x20 == 0 && value & 0x20 != 0 {
+                self.player = (self.player + 1) % self.players;
+            }
+        }
         self.update();
     }
-    
+
+    pub fn enable_sgb(&mut self) {
+        self.sgb = true;
+    }
+
+    pub fn take_sgb_packet(&mut self) -> Option<[u8; 16]> {
+        self.sgb_packet.take()
+    }
+
+    pub fn set_players(&mut self, players: u8) {
+        if players != self.players {
+            self.players = players;
+            self.player = 0;
+            self.update();
+        }
+    }
+
+    // Both lines low start a packet, then every bit is a pulse of P14 for a
+    // zero or P15 for a one. The 128 bits are followed by a zero stop bit.
+    fn receive_bit(&mut self, old: u8, new: u8) {
+        match new {
+            0x00 => {
+                self.packet = [0; 16];
+                self.packet_bit = Some(0);
+            }
+            0x10 | 0x20 if old == 0x30 => {
+                let one = new == 0x10;
+                self.packet_bit = match self.packet_bit {
+                    Some(128) => {
+                        if !one {
+                            self.sgb_packet = Some(self.packet);
+                        }
+                        None
+                    }
+                    Some(bit) => {
+                        if one {
+                            self.packet[bit / 8] |= 1 << (bit % 8);
+                        }
+                        Some(bit + 1)
+                    }
+                    None => None,
+                };
+            }
+            _ => {}
+        }
+    }
+
+    // The rows mirror the keys held on the host, so only the selection is restored
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write_u8(self.data);
+        w.write_u8(self.interrupt);
+        w.write_bytes(&self.packet);
+        w.write_u8(self.packet_bit.map_or(0xFF, |bit| bit as u8));
+        w.write_u8(self.players);
+        w.write_u8(self.player);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> StrResult<()> {
+        self.data = r.read_u8()?;
+        self.interrupt = r.read_u8()?;
+        r.read_bytes(&mut self.packet)?;
+        self.packet_bit = match r.read_u8()? as usize {
+            bit if bit <= 128 => Some(bit),
+            _ => None,
+        };
+        self.players = match r.read_u8()? {
+            2 => 2,
+            4 => 4,
+            _ => 1,
+        };
+        self.player = r.read_u8()? % self.players;
+        self.update();
+        Ok(())
+    }
+
     fn update(&mut self) {
         self.data &= 0x30;
+        // With both lines high the SGB returns the current joypad, and only
+        // the first joypad has keys connected
+        if self.data == 0x30 && self.players > 1 { self.data |= 0x0F - self.player; return; }
+        if self.player != 0 { self.data |= 0x0F; return; }
         if self.data & 0x10 == 0x10 { self.data |= self.row0; }
         if self.data & 0x20 == 0x20 { self.data |= self.row1; }
     }
 
+    pub fn is_pressed(&self, key: KeypadKey) -> bool {
+        let (row, bit) = match key {
+            KeypadKey::Right => (self.row1, 0),
+            KeypadKey::Left => (self.row1, 1),
+            KeypadKey::Up => (self.row1, 2),
+            KeypadKey::Down => (self.row1, 3),
+            KeypadKey::A => (self.row0, 0),
+            KeypadKey::B => (self.row0, 1),
+            KeypadKey::Select => (self.row0, 2),
+            KeypadKey::Start => (self.row0, 3),
+        };
+        row & (1 << bit) == 0
+    }
+
     pub fn keydown(&mut self, key: KeypadKey) {
         match key {
             KeypadKey::Right => self.row1 &= !(1 << 0),
@@ -125,4 +241,40 @@ mod test {
             keypad.keyup(keys1[i]);
         }
     }
+
+    // Sends a packet the way games do, with a reset pulse and a pulse per bit
+    fn send_packet(keypad: &mut super::Keypad, packet: &[u8; 16]) {
+        keypad.wb(0x00);
+        keypad.wb(0x30);
+        for i in 0..128 {
+            let one = packet[i / 8] & (1 << (i % 8)) != 0;
+            keypad.wb(if one { 0x10 } else { 0x20 });
+            keypad.wb(0x30);
+        }
+        keypad.wb(0x20);
+        keypad.wb(0x30);
+    }
+
+    #[test]
+    fn sgb_packets() {
+        let mut keypad = super::Keypad::new();
+        let packet = [0x89, 0x01, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xA5];
+        send_packet(&mut keypad, &packet);
+        assert_eq!(keypad.take_sgb_packet(), None);
+
+        keypad.enable_sgb();
+        send_packet(&mut keypad, &packet);
+        assert_eq!(keypad.take_sgb_packet(), Some(packet));
+
+        // Two players, the second joypad is selected by pulsing P15
+        keypad.set_players(2);
+        keypad.keydown(KeypadKey::A);
+        assert_eq!(keypad.rb(), 0x3F);
+        keypad.wb(0x10);
+        assert_eq!(keypad.rb(), 0x1E);
+        keypad.wb(0x30);
+        assert_eq!(keypad.rb(), 0x3E);
+        keypad.wb(0x10);
+        assert_eq!(keypad.rb(), 0x1F);
+    }
 }
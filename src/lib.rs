@@ -1,22 +1,38 @@
 #![crate_name = "rboy"]
 #![crate_type = "lib" ]
 
+pub use crate::audio::{NullPlayer, SampleFormat, WavWriter};
+pub use crate::debug::{BreakReason, WatchType};
+pub use crate::gbmode::Model;
 pub use crate::keypad::KeypadKey;
+pub use crate::mbc::{CameraCallback, InfraredCallback, RumbleCallback};
 pub use crate::gpu::{SCREEN_W, SCREEN_H};
+pub use crate::palette::{ColorCorrection, CompatPalette, DmgPalette};
+pub use crate::register::{CpuFlag, Registers};
+pub use crate::serial::SerialCallback;
 pub use crate::sound::AudioPlayer;
 
 pub mod device;
+pub mod disasm;
+pub mod filters;
+pub mod png;
+pub mod recorder;
 
+mod audio;
 mod cpu;
+mod debug;
 mod gbmode;
 mod gpu;
 mod keypad;
 mod mbc;
 mod mmu;
+mod palette;
 mod printer;
 mod register;
 mod serial;
+mod sgb;
 mod sound;
+mod state;
 mod timer;
 
 pub type StrResult<T> = Result<T, &'static str>;
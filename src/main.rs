@@ -2,13 +2,18 @@
 
 use cpal::traits::{DeviceTrait, EventLoopTrait, HostTrait};
 use rboy::device::Device;
+use rboy::disasm::SymbolTable;
+use rboy::{BreakReason, WatchType};
 use std::error::Error;
-use std::sync::mpsc::{self, Receiver, SyncSender, TryRecvError, TrySendError};
+use std::path::{Path, PathBuf};
+use std::io::Write;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender, TryRecvError, TrySendError};
 use std::sync::{Arc, Mutex};
 use std::thread;
 
 const EXITCODE_SUCCESS: i32 = 0;
 const EXITCODE_CPULOADFAILS: i32 = 2;
+const STATE_SLOTS: u32 = 10;
 
 #[derive(Default)]
 struct RenderOptions {
@@ -20,8 +25,30 @@ enum GBEvent {
     KeyDown(rboy::KeypadKey),
     SpeedUp,
     SpeedDown,
+    SaveState(PathBuf),
+    LoadState(PathBuf),
+    Break,
+    Tilt(f32, f32),
+    Screenshot(PathBuf, rboy::filters::Filter),
+    ToggleRecording(PathBuf),
 }
 
+const DEBUG_HELP: &str = "\
+c, continue              resume emulation
+s, step [count]          execute one or more instructions
+n, next                  execute one instruction, running over calls
+b, break <addr>          set a breakpoint
+u, dis [addr] [count]    disassemble, from PC by default
+w, watch <addr> [r|w|rw] set a watchpoint, on writes by default
+d, delete <addr>         remove a breakpoint or watchpoint
+l, list                  list breakpoints and watchpoints
+r, regs                  show the registers
+x <addr> [len]           show memory
+set <addr> <value>       write to memory
+q, quit                  quit the emulator
+Addresses are hexadecimal or a label from the .sym file next to the ROM.
+An empty line repeats the previous command";
+
 fn main() {
     let exit_status = real_main();
     if exit_status != EXITCODE_SUCCESS {
@@ -53,10 +80,18 @@ fn real_main() -> i32 {
         )
         .arg(
             clap::Arg::with_name("classic")
-                .help("Forces the emulator to run in classic Gameboy mode")
+                .help("Forces the emulator to run in classic Gameboy mode, same as --model dmg")
                 .short("c")
                 .long("classic"),
         )
+        .arg(
+            clap::Arg::with_name("model")
+                .help("Hardware to emulate: dmg0, dmg, mgb, sgb, sgb2, cgb or agb. Default: cgb")
+                .long("model")
+                .possible_values(&["dmg0", "dmg", "mgb", "sgb", "sgb2", "cgb", "agb"])
+                .conflicts_with("classic")
+                .takes_value(true),
+        )
         .arg(
             clap::Arg::with_name("scale")
                 .help("Sets the scale of the interface. Default: 2")
@@ -76,18 +111,102 @@ fn real_main() -> i32 {
                 .short("a")
                 .long("audio"),
         )
+        .arg(
+            clap::Arg::with_name("audio-out")
+                .help("Writes the sound to a 16 bit WAV file instead of playing it")
+                .long("audio-out")
+                .conflicts_with("audio")
+                .takes_value(true),
+        )
         .arg(
             clap::Arg::with_name("skip-checksum")
                 .help("Skips verification of the cartridge checksum")
                 .long("skip-checksum"),
         )
+        .arg(
+            clap::Arg::with_name("boot-rom")
+                .help("Runs a DMG, MGB or CGB boot ROM image before the cartridge")
+                .long("boot-rom")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("palette")
+                .help("Classic mode colors: grey, dmg, pocket, light or a palette file")
+                .long("palette")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("color-correction")
+                .help("CGB color conversion: raw, gambatte, accurate or agb. Default: gambatte")
+                .long("color-correction")
+                .possible_values(&["raw", "gambatte", "accurate", "agb"])
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("compat-palette")
+                .help("CGB palette for monochrome games, as chosen with the keys at boot")
+                .long("compat-palette")
+                .possible_values(&[
+                    "green", "blue", "brown", "pastel", "dark-green", "dark-blue", "red",
+                    "orange", "inverted", "grayscale", "dark-brown", "yellow",
+                ])
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("filter")
+                .help("Scales or post-processes the screen: none, scale2x, scale3x, hq2x or lcd")
+                .long("filter")
+                .possible_values(&["none", "scale2x", "scale3x", "hq2x", "lcd"])
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("blend")
+                .help("Mixes every frame with the previous one, like the slow LCD of the DMG")
+                .long("blend"),
+        )
+        .arg(
+            clap::Arg::with_name("no-access-blocking")
+                .help("Lets the CPU access VRAM, OAM and palettes while the PPU uses them")
+                .long("no-access-blocking"),
+        )
+        .arg(
+            clap::Arg::with_name("trace")
+                .help("Writes a log of every executed instruction to a file")
+                .long("trace")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("camera")
+                .help("Grayscale PGM image for the Pocket Camera, repeat for a sequence")
+                .long("camera")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            clap::Arg::with_name("record")
+                .help("Records the picture and sound to an AVI, Y4M or GIF file, F3 toggles it")
+                .long("record")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("debug")
+                .help("Starts paused in a debugger on the terminal, F12 breaks into it")
+                .short("d")
+                .long("debug"),
+        )
         .get_matches();
 
     let opt_serial = matches.is_present("serial");
     let opt_printer = matches.is_present("printer");
-    let opt_classic = matches.is_present("classic");
+    let model = match matches.value_of("model") {
+        Some(name) => rboy::Model::from_name(name).unwrap(),
+        None if matches.is_present("classic") => rboy::Model::Dmg,
+        None => rboy::Model::Cgb,
+    };
     let opt_audio = matches.is_present("audio");
     let opt_skip_checksum = matches.is_present("skip-checksum");
+    let opt_debug = matches.is_present("debug");
     let filename = matches.value_of("filename").unwrap();
     let scale = matches
         .value_of("scale")
@@ -97,7 +216,7 @@ fn real_main() -> i32 {
 
     let cpu = construct_cpu(
         filename,
-        opt_classic,
+        model,
         opt_serial,
         opt_printer,
         opt_skip_checksum,
@@ -116,7 +235,70 @@ fn real_main() -> i32 {
             }
         }
     }
+    if let Some(path) = matches.value_of("audio-out") {
+        match rboy::WavWriter::create(Path::new(path), 48000, rboy::SampleFormat::Int16) {
+            Ok(wav) => cpu.enable_audio(Box::new(wav)),
+            Err(message) => {
+                warn(message);
+                return EXITCODE_CPULOADFAILS;
+            }
+        }
+    }
+    if let Some(path) = matches.value_of("trace") {
+        match std::fs::File::create(path) {
+            Ok(f) => cpu.set_tracer(Some(Box::new(std::io::BufWriter::new(f)))),
+            Err(..) => {
+                warn("Could not create trace file");
+                return EXITCODE_CPULOADFAILS;
+            }
+        }
+    }
+    if let Some(paths) = matches.values_of("camera") {
+        let paths: Vec<std::path::PathBuf> = paths.map(|p| p.into()).collect();
+        if let Err(message) = cpu.set_camera_images(&paths) {
+            warn(message);
+            return EXITCODE_CPULOADFAILS;
+        }
+    }
+    if let Some(path) = matches.value_of("boot-rom") {
+        if let Err(message) = cpu.load_boot_rom(Path::new(path)) {
+            warn(message);
+            return EXITCODE_CPULOADFAILS;
+        }
+    }
+    if let Some(name) = matches.value_of("palette") {
+        if let Err(message) = cpu.set_dmg_palette_by_name(name) {
+            warn(message);
+            return EXITCODE_CPULOADFAILS;
+        }
+    }
+    if let Some(name) = matches.value_of("color-correction") {
+        cpu.set_color_correction(rboy::ColorCorrection::from_name(name).unwrap());
+    }
+    if let Some(name) = matches.value_of("compat-palette") {
+        cpu.set_compat_palette(rboy::CompatPalette::from_name(name).unwrap());
+    }
+    if matches.is_present("no-access-blocking") {
+        cpu.set_access_blocking(false);
+    }
+    if let Some(path) = matches.value_of("record") {
+        if let Err(message) = cpu.start_recording(Path::new(path)) {
+            warn(message);
+            return EXITCODE_CPULOADFAILS;
+        }
+    }
     let romname = cpu.romname();
+    let (width, height) = cpu.screen_size();
+    let screen_size = (width as u32, height as u32);
+    let filter = match matches.value_of("filter") {
+        Some(name) => rboy::filters::Filter::from_name(name).unwrap(),
+        None => rboy::filters::Filter::None,
+    };
+    let blender = if matches.is_present("blend") {
+        Some(rboy::filters::FrameBlender::new(0.5))
+    } else {
+        None
+    };
 
     let (sender1, receiver1) = mpsc::channel();
     let (sender2, receiver2) = mpsc::sync_channel(1);
@@ -126,28 +308,41 @@ fn real_main() -> i32 {
 
     let mut eventsloop = glium::glutin::EventsLoop::new();
     let window_builder = glium::glutin::WindowBuilder::new()
-        .with_dimensions(glium::glutin::dpi::LogicalSize::from((
-            rboy::SCREEN_W as u32,
-            rboy::SCREEN_H as u32,
-        )))
+        .with_dimensions(glium::glutin::dpi::LogicalSize::from(screen_size))
         .with_title("RBoy - ".to_owned() + &romname);
     let context_builder = glium::glutin::ContextBuilder::new();
     let display =
         glium::backend::glutin::Display::new(window_builder, context_builder, &eventsloop).unwrap();
-    set_window_size(display.gl_window().window(), scale);
+    set_window_size(display.gl_window().window(), screen_size, scale);
 
     let mut texture = glium::texture::texture2d::Texture2d::empty_with_format(
         &display,
         glium::texture::UncompressedFloatFormat::U8U8U8,
         glium::texture::MipmapsOption::NoMipmap,
-        rboy::SCREEN_W as u32,
-        rboy::SCREEN_H as u32,
+        screen_size.0 * filter.scale() as u32,
+        screen_size.1 * filter.scale() as u32,
     )
     .unwrap();
 
     let mut renderoptions = <RenderOptions as Default>::default();
+    let mut state_slot = 0;
+    let mut tilt_keys = [false; 4];
 
-    let cputhread = thread::spawn(move || run_cpu(cpu, sender2, receiver1));
+    let debugger = if opt_debug {
+        Some(Debugger {
+            symbols: load_symbols(filename),
+            last_command: String::new(),
+        })
+    } else {
+        None
+    };
+
+    let frames = FrameSender {
+        sender: sender2,
+        blender,
+        last_frame: Vec::new(),
+    };
+    let cputhread = thread::spawn(move || run_cpu(cpu, frames, receiver1, debugger));
 
     loop {
         let mut stop = false;
@@ -169,12 +364,12 @@ fn real_main() -> i32 {
                             state: Pressed,
                             virtual_keycode: Some(VirtualKeyCode::Key1),
                             ..
-                        } => set_window_size(display.gl_window().window(), 1),
+                        } => set_window_size(display.gl_window().window(), screen_size, 1),
                         KeyboardInput {
                             state: Pressed,
                             virtual_keycode: Some(VirtualKeyCode::R),
                             ..
-                        } => set_window_size(display.gl_window().window(), scale),
+                        } => set_window_size(display.gl_window().window(), screen_size, scale),
                         KeyboardInput {
                             state: Pressed,
                             virtual_keycode: Some(VirtualKeyCode::LShift),
@@ -197,6 +392,72 @@ fn real_main() -> i32 {
                             renderoptions.linear_interpolation =
                                 !renderoptions.linear_interpolation;
                         }
+                        KeyboardInput {
+                            state: Pressed,
+                            virtual_keycode: Some(VirtualKeyCode::F5),
+                            ..
+                        } => {
+                            let path = state_path(filename, state_slot);
+                            let _ = sender1.send(GBEvent::SaveState(path));
+                        }
+                        KeyboardInput {
+                            state: Pressed,
+                            virtual_keycode: Some(VirtualKeyCode::F9),
+                            ..
+                        } => {
+                            let path = state_path(filename, state_slot);
+                            let _ = sender1.send(GBEvent::LoadState(path));
+                        }
+                        KeyboardInput {
+                            state: Pressed,
+                            virtual_keycode: Some(VirtualKeyCode::F6),
+                            ..
+                        } => {
+                            state_slot = (state_slot + STATE_SLOTS - 1) % STATE_SLOTS;
+                            println!("Selected save state slot {}", state_slot);
+                        }
+                        KeyboardInput {
+                            state: Pressed,
+                            virtual_keycode: Some(VirtualKeyCode::F7),
+                            ..
+                        } => {
+                            state_slot = (state_slot + 1) % STATE_SLOTS;
+                            println!("Selected save state slot {}", state_slot);
+                        }
+                        KeyboardInput {
+                            state: Pressed,
+                            virtual_keycode: Some(VirtualKeyCode::F2),
+                            ..
+                        } => {
+                            let path = timestamped_path(filename, "png");
+                            let _ = sender1.send(GBEvent::Screenshot(path, filter));
+                        }
+                        KeyboardInput {
+                            state: Pressed,
+                            virtual_keycode: Some(VirtualKeyCode::F3),
+                            ..
+                        } => {
+                            let path = timestamped_path(filename, "avi");
+                            let _ = sender1.send(GBEvent::ToggleRecording(path));
+                        }
+                        KeyboardInput {
+                            state: Pressed,
+                            virtual_keycode: Some(VirtualKeyCode::F12),
+                            ..
+                        } => {
+                            let _ = sender1.send(GBEvent::Break);
+                        }
+                        KeyboardInput {
+                            state,
+                            virtual_keycode: Some(glutinkey),
+                            ..
+                        } if glutin_to_tilt(glutinkey).is_some() => {
+                            tilt_keys[glutin_to_tilt(glutinkey).unwrap()] = state == Pressed;
+                            let axis = |neg: usize, pos: usize| {
+                                tilt_keys[pos] as i32 as f32 - tilt_keys[neg] as i32 as f32
+                            };
+                            let _ = sender1.send(GBEvent::Tilt(axis(0, 1), axis(2, 3)));
+                        }
                         KeyboardInput {
                             state: Pressed,
                             virtual_keycode: Some(glutinkey),
@@ -227,9 +488,14 @@ fn real_main() -> i32 {
             break;
         }
 
-        match receiver2.recv() {
-            Ok(data) => recalculate_screen(&display, &mut texture, &*data, &renderoptions),
-            Err(..) => break, // Remote end has hung-up
+        // Keep handling window events while the debugger holds the emulation
+        match receiver2.recv_timeout(std::time::Duration::from_millis(50)) {
+            Ok(data) => {
+                let data = filter.apply(&data, width, height);
+                recalculate_screen(&display, &mut texture, &data, &renderoptions)
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => break, // Remote end has hung-up
         }
     }
 
@@ -254,6 +520,18 @@ fn glutin_to_keypad(key: glium::glutin::VirtualKeyCode) -> Option<rboy::KeypadKe
     }
 }
 
+// Index into the left, right, up and down tilt keys
+fn glutin_to_tilt(key: glium::glutin::VirtualKeyCode) -> Option<usize> {
+    use glium::glutin::VirtualKeyCode;
+    match key {
+        VirtualKeyCode::J => Some(0),
+        VirtualKeyCode::L => Some(1),
+        VirtualKeyCode::I => Some(2),
+        VirtualKeyCode::K => Some(3),
+        _ => None,
+    }
+}
+
 fn recalculate_screen(
     display: &glium::Display,
     texture: &mut glium::texture::texture2d::Texture2d,
@@ -268,18 +546,19 @@ fn recalculate_screen(
         glium::uniforms::MagnifySamplerFilter::Nearest
     };
 
+    let (width, height) = texture.dimensions();
     let rawimage2d = glium::texture::RawImage2d {
         data: std::borrow::Cow::Borrowed(datavec),
-        width: rboy::SCREEN_W as u32,
-        height: rboy::SCREEN_H as u32,
+        width,
+        height,
         format: glium::texture::ClientFormat::U8U8U8,
     };
     texture.write(
         glium::Rect {
             left: 0,
             bottom: 0,
-            width: rboy::SCREEN_W as u32,
-            height: rboy::SCREEN_H as u32,
+            width,
+            height,
         },
         rawimage2d,
     );
@@ -300,23 +579,91 @@ fn recalculate_screen(
     target.finish().unwrap();
 }
 
-fn warn(message: &'static str) {
-    use std::io::Write;
+fn warn(message: &str) {
     let _ = write!(&mut std::io::stderr(), "{}\n", message);
 }
 
+fn state_path(romfile: &str, slot: u32) -> PathBuf {
+    Path::new(romfile).with_extension(format!("ss{}", slot))
+}
+
+// Named after the ROM and the time in milliseconds, so screenshots and
+// recordings sort by time
+fn timestamped_path(romfile: &str, extension: &str) -> PathBuf {
+    let millis = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |t| t.as_millis());
+    let path = Path::new(romfile);
+    let stem = path.file_stem().map_or("rboy".into(), |s| s.to_string_lossy());
+    path.with_file_name(format!("{}-{}.{}", stem, millis, extension))
+}
+
+// Saves the last completed frame, as the screen may be halfway through the next one
+fn screenshot(cpu: &Device, frames: &FrameSender, path: &Path, filter: rboy::filters::Filter) {
+    let (width, height) = cpu.screen_size();
+    let frame = match frames.last_frame.is_empty() {
+        true => cpu.get_gpu_data(),
+        false => &frames.last_frame,
+    };
+    let data = filter.apply(frame, width, height);
+    let (width, height) = (width * filter.scale(), height * filter.scale());
+    let result = std::fs::File::create(path)
+        .map_err(|_| "Could not create screenshot file")
+        .and_then(|mut f| {
+            rboy::png::write_png(&mut f, &data, width, height).map_err(|_| "Could not write screenshot")
+        });
+    match result {
+        Ok(()) => println!("Screenshot saved to {}", path.display()),
+        Err(message) => warn(message),
+    }
+}
+
+fn toggle_recording(cpu: &mut Device, path: &Path) {
+    if cpu.is_recording() {
+        stop_recording(cpu);
+        return;
+    }
+    match cpu.start_recording(path) {
+        Ok(()) => println!("Recording to {}", path.display()),
+        Err(message) => warn(message),
+    }
+}
+
+fn stop_recording(cpu: &mut Device) {
+    match cpu.stop_recording() {
+        Ok(()) => println!("Recording stopped"),
+        Err(message) => warn(message),
+    }
+}
+
+fn save_state(cpu: &Device, path: &Path) {
+    let result = std::fs::File::create(path)
+        .map_err(|_| "Could not create save state file")
+        .and_then(|mut f| cpu.save_state(&mut f));
+    match result {
+        Ok(()) => println!("State saved to {}", path.display()),
+        Err(message) => warn(message),
+    }
+}
+
+fn load_state(cpu: &mut Device, path: &Path) {
+    let result = std::fs::File::open(path)
+        .map_err(|_| "Could not open save state file")
+        .and_then(|mut f| cpu.load_state(&mut f));
+    match result {
+        Ok(()) => println!("State loaded from {}", path.display()),
+        Err(message) => warn(message),
+    }
+}
+
 fn construct_cpu(
     filename: &str,
-    classic_mode: bool,
+    model: rboy::Model,
     output_serial: bool,
     output_printer: bool,
     skip_checksum: bool,
 ) -> Option<Box<Device>> {
-    let opt_c = match classic_mode {
-        true => Device::new(filename, skip_checksum),
-        false => Device::new_cgb(filename, skip_checksum),
-    };
-    let mut c = match opt_c {
+    let mut c = match Device::new_model(filename, model, skip_checksum) {
         Ok(cpu) => cpu,
         Err(message) => {
             warn(message);
@@ -333,21 +680,43 @@ fn construct_cpu(
     Some(Box::new(c))
 }
 
-fn run_cpu(mut cpu: Box<Device>, sender: SyncSender<Vec<u8>>, receiver: Receiver<GBEvent>) {
+fn run_cpu(
+    mut cpu: Box<Device>,
+    mut frames: FrameSender,
+    receiver: Receiver<GBEvent>,
+    mut debugger: Option<Debugger>,
+) {
     let periodic = timer_periodic(16);
     let mut limit_speed = true;
+    let debug = debugger.is_some();
+    let mut paused = debug;
 
     let waitticks = (4194304f64 / 1000.0 * 16.0).round() as u32;
     let mut ticks = 0;
 
     'outer: loop {
+        if paused {
+            if let Some(ref mut debugger) = debugger {
+                if !run_debugger(&mut cpu, &mut frames, debugger) {
+                    break 'outer;
+                }
+            }
+            paused = false;
+        }
+
         while ticks < waitticks {
             ticks += cpu.do_cycle();
-            if cpu.check_and_reset_gpu_updated() {
-                let data = cpu.get_gpu_data().to_vec();
-                if let Err(TrySendError::Disconnected(..)) = sender.try_send(data) {
-                    break 'outer;
+            if !frames.send(&mut cpu) {
+                break 'outer;
+            }
+            match cpu.check_and_reset_break() {
+                Some(reason) if debug => {
+                    println!("{}", reason);
+                    paused = true;
+                    continue 'outer;
                 }
+                Some(reason @ BreakReason::IllegalOpcode { .. }) => warn(&reason.to_string()),
+                _ => {}
             }
         }
 
@@ -363,6 +732,12 @@ fn run_cpu(mut cpu: Box<Device>, sender: SyncSender<Vec<u8>>, receiver: Receiver
                         limit_speed = true;
                         cpu.sync_audio();
                     }
+                    GBEvent::SaveState(path) => save_state(&cpu, &path),
+                    GBEvent::LoadState(path) => load_state(&mut cpu, &path),
+                    GBEvent::Break => paused = debug,
+                    GBEvent::Tilt(x, y) => cpu.set_tilt(x, y),
+                    GBEvent::Screenshot(path, filter) => screenshot(&cpu, &frames, &path, filter),
+                    GBEvent::ToggleRecording(path) => toggle_recording(&mut cpu, &path),
                 },
                 Err(TryRecvError::Empty) => break 'recv,
                 Err(TryRecvError::Disconnected) => break 'outer,
@@ -373,6 +748,251 @@ fn run_cpu(mut cpu: Box<Device>, sender: SyncSender<Vec<u8>>, receiver: Receiver
             let _ = periodic.recv();
         }
     }
+
+    if cpu.is_recording() {
+        stop_recording(&mut cpu);
+    }
+}
+
+// Passes completed frames to the window. Blending is done here for every
+// frame, as the window may skip some of them.
+struct FrameSender {
+    sender: SyncSender<Vec<u8>>,
+    blender: Option<rboy::filters::FrameBlender>,
+    // What the window shows, kept for screenshots
+    last_frame: Vec<u8>,
+}
+
+impl FrameSender {
+    // Returns false when the window has been closed
+    fn send(&mut self, cpu: &mut Device) -> bool {
+        if cpu.check_and_reset_gpu_updated() {
+            self.last_frame = match self.blender {
+                Some(ref mut blender) => blender.blend(cpu.get_gpu_data()),
+                None => cpu.get_gpu_data().to_vec(),
+            };
+            let data = self.last_frame.clone();
+            if let Err(TrySendError::Disconnected(..)) = self.sender.try_send(data) {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+// Returns false when the emulator should quit
+fn run_debugger(cpu: &mut Device, frames: &mut FrameSender, debugger: &mut Debugger) -> bool {
+    print_location(cpu, &debugger.symbols);
+    loop {
+        print!("(rboy) ");
+        let _ = std::io::stdout().flush();
+        let mut line = String::new();
+        match std::io::stdin().read_line(&mut line) {
+            Ok(0) | Err(..) => return false,
+            Ok(..) => {}
+        }
+        if !line.trim().is_empty() {
+            debugger.last_command = line.trim().to_owned();
+        }
+        let command = debugger.last_command.clone();
+        let args: Vec<&str> = command.split_whitespace().collect();
+        let parse_address = |s: &str| debugger.address(s);
+        match args.as_slice() {
+            [] => {}
+            ["c"] | ["continue"] => return true,
+            ["q"] | ["quit"] => return false,
+            ["h"] | ["help"] => println!("{}", DEBUG_HELP),
+            ["s"] | ["step"] => {
+                if !debug_step(cpu, frames, &debugger.symbols, 1) {
+                    return false;
+                }
+            }
+            ["s", count] | ["step", count] => match count.parse::<u32>() {
+                Ok(count) => {
+                    if !debug_step(cpu, frames, &debugger.symbols, count) {
+                        return false;
+                    }
+                }
+                Err(..) => println!("Invalid count"),
+            },
+            ["n"] | ["next"] => {
+                if cpu.step_over() {
+                    return true;
+                }
+                if !frames.send(cpu) {
+                    return false;
+                }
+                if let Some(reason) = cpu.check_and_reset_break() {
+                    println!("{}", reason);
+                }
+                print_location(cpu, &debugger.symbols);
+            }
+            ["b", addr] | ["break", addr] => match parse_address(addr) {
+                Some(addr) => cpu.add_breakpoint(addr),
+                None => println!("Invalid address"),
+            },
+            ["w", addr] | ["watch", addr] => match parse_address(addr) {
+                Some(addr) => cpu.add_watchpoint(addr, WatchType::Write),
+                None => println!("Invalid address"),
+            },
+            ["w", addr, kind] | ["watch", addr, kind] => {
+                let watchtype = match *kind {
+                    "r" => Some(WatchType::Read),
+                    "w" => Some(WatchType::Write),
+                    "rw" => Some(WatchType::Access),
+                    _ => None,
+                };
+                match (parse_address(addr), watchtype) {
+                    (Some(addr), Some(watchtype)) => cpu.add_watchpoint(addr, watchtype),
+                    _ => println!("Usage: watch <addr> [r|w|rw]"),
+                }
+            }
+            ["d", addr] | ["delete", addr] => match parse_address(addr) {
+                Some(addr) => {
+                    let removed = cpu.remove_breakpoint(addr) | cpu.remove_watchpoint(addr);
+                    if !removed {
+                        println!("No breakpoint or watchpoint at {:04X}", addr);
+                    }
+                }
+                None => println!("Invalid address"),
+            },
+            ["l"] | ["list"] => {
+                for addr in cpu.breakpoints() {
+                    println!("Breakpoint at {:04X}", addr);
+                }
+                for (addr, watchtype) in cpu.watchpoints() {
+                    println!("Watchpoint on {} at {:04X}", watchtype, addr);
+                }
+            }
+            ["r"] | ["regs"] => print_location(cpu, &debugger.symbols),
+            ["u"] | ["dis"] => {
+                let pc = cpu.registers().pc;
+                print_disassembly(cpu, &debugger.symbols, pc, 10);
+            }
+            ["u", addr] | ["dis", addr] => match parse_address(addr) {
+                Some(addr) => print_disassembly(cpu, &debugger.symbols, addr, 10),
+                None => println!("Invalid address"),
+            },
+            ["u", addr, count] | ["dis", addr, count] => {
+                match (parse_address(addr), count.parse::<u16>()) {
+                    (Some(addr), Ok(count)) => print_disassembly(cpu, &debugger.symbols, addr, count),
+                    _ => println!("Usage: dis [addr] [count]"),
+                }
+            }
+            ["x", addr] => match parse_address(addr) {
+                Some(addr) => print_memory(cpu, addr, 16),
+                None => println!("Invalid address"),
+            },
+            ["x", addr, len] => match (parse_address(addr), len.parse::<u16>()) {
+                (Some(addr), Ok(len)) => print_memory(cpu, addr, len),
+                _ => println!("Usage: x <addr> [len]"),
+            },
+            ["set", addr, value] => match (parse_address(addr), parse_hex(value)) {
+                (Some(addr), Some(value)) if value <= 0xFF => cpu.write_memory(addr, value as u8),
+                _ => println!("Usage: set <addr> <value>"),
+            },
+            _ => println!("Unknown command, type help for a list of commands"),
+        }
+    }
+}
+
+// Returns false when the window has been closed
+fn debug_step(
+    cpu: &mut Device,
+    frames: &mut FrameSender,
+    symbols: &SymbolTable,
+    count: u32,
+) -> bool {
+    for i in 0..count {
+        if i > 0 && cpu.breakpoints().contains(&cpu.registers().pc) {
+            println!("{}", BreakReason::Breakpoint(cpu.registers().pc));
+            break;
+        }
+        cpu.step();
+        if !frames.send(cpu) {
+            return false;
+        }
+        if let Some(reason) = cpu.check_and_reset_break() {
+            println!("{}", reason);
+            break;
+        }
+    }
+    print_location(cpu, symbols);
+    true
+}
+
+fn print_location(cpu: &mut Device, symbols: &SymbolTable) {
+    let regs = cpu.registers();
+    println!(
+        "{} IME={} HALT={}",
+        regs,
+        cpu.interrupts_enabled() as u8,
+        cpu.halted() as u8
+    );
+    print_disassembly(cpu, symbols, regs.pc, 1);
+}
+
+fn print_disassembly(cpu: &mut Device, symbols: &SymbolTable, mut address: u16, count: u16) {
+    let bank = cpu.rombank() as u16;
+    for _ in 0..count {
+        if let Some(label) = symbols.lookup(bank, address) {
+            println!("{}:", label);
+        }
+        let (text, len) = cpu.disassemble(address, Some(symbols));
+        let bytes: Vec<String> = (0..len)
+            .map(|i| format!("{:02X}", cpu.read_memory(address.wrapping_add(i))))
+            .collect();
+        println!("  {:04X}: {:<9} {}", address, bytes.join(" "), text);
+        address = address.wrapping_add(len);
+    }
+}
+
+fn print_memory(cpu: &mut Device, address: u16, len: u16) {
+    for row in (0..len).step_by(16) {
+        let start = address.wrapping_add(row);
+        let bytes: Vec<String> = (0..std::cmp::min(16, len - row))
+            .map(|i| format!("{:02X}", cpu.read_memory(start.wrapping_add(i))))
+            .collect();
+        println!("{:04X}: {}", start, bytes.join(" "));
+    }
+}
+
+struct Debugger {
+    symbols: SymbolTable,
+    last_command: String,
+}
+
+impl Debugger {
+    fn address(&self, s: &str) -> Option<u16> {
+        match self.symbols.find(s) {
+            Some((_, address)) => Some(address),
+            None => parse_hex(s),
+        }
+    }
+}
+
+// Uses the symbol file next to the ROM when there is one
+fn load_symbols(romfile: &str) -> SymbolTable {
+    let path = Path::new(romfile).with_extension("sym");
+    if !path.exists() {
+        return SymbolTable::new();
+    }
+    match SymbolTable::load(&path) {
+        Ok(symbols) => {
+            println!("Loaded symbols from {}", path.display());
+            symbols
+        }
+        Err(message) => {
+            warn(message);
+            SymbolTable::new()
+        }
+    }
+}
+
+// Values are hexadecimal, optionally prefixed by 0x or $
+fn parse_hex(s: &str) -> Option<u16> {
+    let s = s.trim_start_matches("0x").trim_start_matches('$');
+    u16::from_str_radix(s, 16).ok()
 }
 
 fn timer_periodic(ms: u64) -> Receiver<()> {
@@ -386,13 +1006,13 @@ fn timer_periodic(ms: u64) -> Receiver<()> {
     rx
 }
 
-fn set_window_size(window: &glium::glutin::Window, scale: u32) {
+fn set_window_size(window: &glium::glutin::Window, (width, height): (u32, u32), scale: u32) {
     use glium::glutin::dpi::{LogicalSize, PhysicalSize};
 
     let dpi = window.get_hidpi_factor();
 
     let physical_size =
-        PhysicalSize::from((rboy::SCREEN_W as u32 * scale, rboy::SCREEN_H as u32 * scale));
+        PhysicalSize::from((width * scale, height * scale));
     let logical_size = LogicalSize::from_physical(physical_size, dpi);
 
     window.set_inner_size(logical_size);
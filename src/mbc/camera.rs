@@ -0,0 +1,353 @@
+use crate::mbc::{ram_size, CameraCallback, MBC};
+use crate::state::{StateReader, StateWriter};
+use crate::StrResult;
+
+use std::fs::File;
+use std::io::prelude::*;
+use std::{io, path};
+
+pub const SENSOR_W: usize = 128;
+pub const SENSOR_H: usize = 112;
+
+const REGISTER_COUNT: usize = 0x36;
+const EDGE_RATIOS: [f32; 8] = [0.50, 0.75, 1.00, 1.25, 2.00, 3.00, 4.00, 5.00];
+
+// Pocket Camera cartridge with the M64282FP image sensor
+pub struct Camera {
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+    rombank: usize,
+    rambank: usize,
+    ram_on: bool,
+    registers: [u8; REGISTER_COUNT],
+    // Remaining ticks of the current capture, four for every M-cycle
+    capture_ticks: u32,
+    source: Option<CameraCallback>,
+    savepath: Option<path::PathBuf>,
+}
+
+impl Camera {
+    pub fn new(data: Vec<u8>, file: path::PathBuf) -> StrResult<Camera> {
+        let ramsize = ram_size(data[0x149]);
+        let mut res = Camera {
+            rom: data,
+            ram: vec![0; ramsize],
+            rombank: 1,
+            rambank: 0,
+            ram_on: false,
+            registers: [0; REGISTER_COUNT],
+            capture_ticks: 0,
+            source: None,
+            savepath: Some(file.with_extension("gbsave")),
+        };
+        res.loadram().map(|_| res)
+    }
+
+    fn loadram(&mut self) -> StrResult<()> {
+        match self.savepath {
+            None => Ok(()),
+            Some(ref savepath) => {
+                let mut data = vec![];
+                match File::open(savepath).and_then(|mut f| f.read_to_end(&mut data)) {
+                    Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+                    Err(_) => Err("Could not read RAM"),
+                    Ok(..) => {
+                        let len = ::std::cmp::min(data.len(), self.ram.len());
+                        self.ram[..len].copy_from_slice(&data[..len]);
+                        Ok(())
+                    }
+                }
+            }
+        }
+    }
+
+    fn start_capture(&mut self) {
+        let exposure = ((self.registers[2] as u32) << 8) | (self.registers[3] as u32);
+        let n = self.registers[1] & 0x80 != 0;
+        let cycles = 32446 + if n { 0 } else { 512 } + 16 * exposure;
+        self.capture_ticks = cycles * 4;
+    }
+
+    fn capture(&mut self) {
+        let mut image = vec![0x80; SENSOR_W * SENSOR_H];
+        if let Some(ref mut source) = self.source {
+            source(&mut image);
+        }
+
+        let exposure = ((self.registers[2] as u32) << 8) | (self.registers[3] as u32);
+        let exposed: Vec<f32> = image
+            .iter()
+            .map(|&v| (v as u32 * exposure / 0x300).min(255) as f32)
+            .collect();
+
+        let alpha = EDGE_RATIOS[((self.registers[4] >> 4) & 0x7) as usize];
+        let pixel = |x: i32, y: i32| {
+            let x = x.clamp(0, SENSOR_W as i32 - 1) as usize;
+            let y = y.clamp(0, SENSOR_H as i32 - 1) as usize;
+            exposed[y * SENSOR_W + x]
+        };
+
+        for y in 0..SENSOR_H {
+            for x in 0..SENSOR_W {
+                let (xi, yi) = (x as i32, y as i32);
+                let p = pixel(xi, yi);
+                let horizontal = 2.0 * p - pixel(xi - 1, yi) - pixel(xi + 1, yi);
+                let vertical = 2.0 * p - pixel(xi, yi - 1) - pixel(xi, yi + 1);
+                let value = match (self.registers[1] >> 5) & 0x3 {
+                    0 => p,
+                    1 => p + alpha * horizontal,
+                    2 => p + alpha * vertical,
+                    _ => p + alpha * (horizontal + vertical),
+                };
+                let color = self.dither(x, y, value.clamp(0.0, 255.0) as u8);
+                self.set_pixel(x, y, color);
+            }
+        }
+    }
+
+    // The registers hold a 4x4 matrix of three thresholds each
+    fn dither(&self, x: usize, y: usize, value: u8) -> u8 {
+        let base = 6 + ((y & 3) * 4 + (x & 3)) * 3;
+        let thresholds = &self.registers[base..base + 3];
+        if value < thresholds[0] {
+            3
+        } else if value < thresholds[1] {
+            2
+        } else if value < thresholds[2] {
+            1
+        } else {
+            0
+        }
+    }
+
+    // The image is stored as 16x14 tiles from address 0x100 of the first RAM bank
+    fn set_pixel(&mut self, x: usize, y: usize, color: u8) {
+        let tile = (y / 8) * (SENSOR_W / 8) + x / 8;
+        let address = 0x100 + tile * 16 + (y % 8) * 2;
+        let bit = 7 - (x % 8);
+        if address + 1 >= self.ram.len() {
+            return;
+        }
+        self.ram[address] = (self.ram[address] & !(1 << bit)) | ((color & 1) << bit);
+        self.ram[address + 1] = (self.ram[address + 1] & !(1 << bit)) | (((color >> 1) & 1) << bit);
+    }
+}
+
+impl Drop for Camera {
+    fn drop(&mut self) {
+        match self.savepath {
+            None => {}
+            Some(ref path) => {
+                let _ = File::create(path).and_then(|mut f| f.write_all(&self.ram));
+            }
+        };
+    }
+}
+
+impl MBC for Camera {
+    fn readrom(&self, a: u16) -> u8 {
+        let idx = if a < 0x4000 {
+            a as usize
+        } else {
+            (self.rombank * 0x4000) | ((a as usize) & 0x3FFF)
+        };
+        *self.rom.get(idx).unwrap_or(&0xFF)
+    }
+    fn readram(&mut self, a: u16) -> u8 {
+        if self.rambank & 0x10 != 0 {
+            // Only the control register can be read back
+            return match a & 0x7F {
+                0 => self.registers[0] & 0x07,
+                _ => 0x00,
+            };
+        }
+        let address = (self.rambank * 0x2000) | ((a as usize) & 0x1FFF);
+        *self.ram.get(address).unwrap_or(&0xFF)
+    }
+    fn writerom(&mut self, a: u16, v: u8) {
+        match a {
+            0x0000..=0x1FFF => self.ram_on = v & 0x0F == 0x0A,
+            0x2000..=0x3FFF => self.rombank = (v & 0x3F) as usize,
+            0x4000..=0x5FFF => self.rambank = (v & 0x1F) as usize,
+            0x6000..=0x7FFF => {}
+            _ => panic!("Could not write to {:04X} (Camera)", a),
+        }
+    }
+    fn writeram(&mut self, a: u16, v: u8) {
+        if self.rambank & 0x10 != 0 {
+            let register = (a & 0x7F) as usize;
+            if register == 0 {
+                if v & 0x01 != 0 && self.capture_ticks == 0 {
+                    self.start_capture();
+                }
+                let busy = self.registers[0] & 0x01;
+                self.registers[0] = (v & 0x06) | busy | (v & 0x01);
+            } else if register < REGISTER_COUNT {
+                self.registers[register] = v;
+            }
+            return;
+        }
+        if !self.ram_on {
+            return;
+        }
+        let address = (self.rambank * 0x2000) | ((a as usize) & 0x1FFF);
+        if address < self.ram.len() {
+            self.ram[address] = v;
+        }
+    }
+
+    fn rombank(&self) -> usize {
+        self.rombank
+    }
+
+    fn do_cycle(&mut self, ticks: u32) {
+        if self.capture_ticks == 0 {
+            return;
+        }
+        if ticks < self.capture_ticks {
+            self.capture_ticks -= ticks;
+            return;
+        }
+        self.capture_ticks = 0;
+        self.capture();
+        self.registers[0] &= !0x01;
+    }
+
+    fn set_camera_callback(&mut self, callback: CameraCallback) {
+        self.source = Some(callback);
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_u8(self.rombank as u8);
+        w.write_u8(self.rambank as u8);
+        w.write_bool(self.ram_on);
+        w.write_bytes(&self.registers);
+        w.write_u32(self.capture_ticks);
+        w.write_vec(&self.ram);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> StrResult<()> {
+        self.rombank = (r.read_u8()? & 0x3F) as usize;
+        self.rambank = (r.read_u8()? & 0x1F) as usize;
+        self.ram_on = r.read_bool()?;
+        r.read_bytes(&mut self.registers)?;
+        self.capture_ticks = r.read_u32()?;
+        r.read_vec_into(&mut self.ram)
+    }
+}
+
+// Loads a binary (P5) PGM image and scales it to the sensor size
+pub fn load_pgm(path: &path::Path) -> StrResult<Vec<u8>> {
+    let data = std::fs::read(path).map_err(|_| "Could not read image")?;
+
+    // The header has four fields separated by whitespace, and may have comments
+    let mut fields = Vec::new();
+    let mut pos = 0;
+    while fields.len() < 4 && pos < data.len() {
+        match data[pos] {
+            b'#' => {
+                while pos < data.len() && data[pos] != b'\n' {
+                    pos += 1;
+                }
+            }
+            c if c.is_ascii_whitespace() => pos += 1,
+            _ => {
+                let start = pos;
+                while pos < data.len() && !data[pos].is_ascii_whitespace() {
+                    pos += 1;
+                }
+                fields.push(String::from_utf8_lossy(&data[start..pos]).into_owned());
+            }
+        }
+    }
+    // A single whitespace character separates the header from the pixels
+    pos += 1;
+
+    if fields.len() < 4 || fields[0] != "P5" {
+        return Err("Image is not a binary PGM file");
+    }
+    let parse = |s: &str| s.parse::<usize>().map_err(|_| "Invalid PGM header");
+    let (width, height, maxval) = (parse(&fields[1])?, parse(&fields[2])?, parse(&fields[3])?);
+    if width == 0 || height == 0 || maxval == 0 || maxval > 255 {
+        return Err("Unsupported PGM format");
+    }
+    if data.len() < pos + width * height {
+        return Err("PGM file is truncated");
+    }
+
+    let pixels = &data[pos..pos + width * height];
+    let mut image = Vec::with_capacity(SENSOR_W * SENSOR_H);
+    for y in 0..SENSOR_H {
+        for x in 0..SENSOR_W {
+            let v = pixels[(y * height / SENSOR_H) * width + x * width / SENSOR_W] as usize;
+            image.push((v * 255 / maxval) as u8);
+        }
+    }
+    Ok(image)
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::{Camera, SENSOR_H, SENSOR_W};
+    use crate::mbc::MBC;
+
+    // A camera that sees the brightness given for every pixel index
+    pub fn camera(pixel: fn(usize) -> u8) -> Camera {
+        let mut rom = vec![0; 0x8000];
+        rom[0x147] = 0xFC;
+        rom[0x149] = 0x04;
+        let mut mbc = Camera::new(rom, "camera.gb".into()).unwrap();
+        mbc.savepath = None;
+        mbc.set_camera_callback(Box::new(move |image| {
+            assert_eq!(image.len(), SENSOR_W * SENSOR_H);
+            for (i, v) in image.iter_mut().enumerate() {
+                *v = pixel(i);
+            }
+        }));
+        mbc
+    }
+
+    // Takes a photo, running the cartridge for the given ticks at a time
+    pub fn capture(mbc: &mut Camera, ticks: u32) {
+        mbc.writerom(0x4000, 0x10);
+        // Exposure 0x0300 keeps the input as is, thresholds 0x40/0x80/0xC0
+        mbc.writeram(0xA002, 0x03);
+        mbc.writeram(0xA003, 0x00);
+        for i in 0..16 {
+            mbc.writeram(0xA006 + i * 3, 0x40);
+            mbc.writeram(0xA007 + i * 3, 0x80);
+            mbc.writeram(0xA008 + i * 3, 0xC0);
+        }
+        mbc.writeram(0xA000, 0x01);
+        assert_eq!(mbc.readram(0xA000), 0x01);
+        while mbc.readram(0xA000) & 0x01 != 0 {
+            mbc.do_cycle(ticks);
+        }
+        mbc.writerom(0x4000, 0x00);
+    }
+
+    #[test]
+    fn capture_white() {
+        let mut mbc = camera(|_| 0xFF);
+        capture(&mut mbc, 456);
+        assert!((0x100..0x100 + 16 * 14 * 16).all(|a| mbc.ram[a] == 0x00));
+    }
+
+    #[test]
+    fn capture_dark_gray() {
+        let mut mbc = camera(|_| 0x50);
+        capture(&mut mbc, 456);
+        // Color 2 only sets the high bit plane
+        assert_eq!(mbc.readram(0xA100), 0x00);
+        assert_eq!(mbc.readram(0xA101), 0xFF);
+        assert_eq!(mbc.readram(0xAEFF), 0xFF);
+    }
+
+    #[test]
+    fn capture_in_double_speed() {
+        // The MMU runs the cartridge two ticks at a time in double speed
+        let mut mbc = camera(|_| 0x50);
+        capture(&mut mbc, 2);
+        assert_eq!(mbc.readram(0xA101), 0xFF);
+    }
+}
@@ -0,0 +1,219 @@
+use crate::mbc::{ram_size, InfraredCallback, MBC};
+use crate::state::{StateReader, StateWriter};
+use crate::StrResult;
+
+use std::fs::File;
+use std::io::prelude::*;
+use std::{io, path};
+
+pub struct HuC1 {
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+    rombank: usize,
+    rambank: usize,
+    ir_mode: bool,
+    ir_led: bool,
+    infrared: Option<InfraredCallback>,
+    savepath: Option<path::PathBuf>,
+}
+
+impl HuC1 {
+    pub fn new(data: Vec<u8>, file: path::PathBuf) -> StrResult<HuC1> {
+        let ramsize = ram_size(data[0x149]);
+        let mut res = HuC1 {
+            rom: data,
+            ram: vec![0; ramsize],
+            rombank: 1,
+            rambank: 0,
+            ir_mode: false,
+            ir_led: false,
+            infrared: None,
+            savepath: Some(file.with_extension("gbsave")),
+        };
+        res.loadram().map(|_| res)
+    }
+
+    fn loadram(&mut self) -> StrResult<()> {
+        match self.savepath {
+            None => Ok(()),
+            Some(ref savepath) => {
+                let mut data = vec![];
+                match File::open(savepath).and_then(|mut f| f.read_to_end(&mut data)) {
+                    Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+                    Err(_) => Err("Could not read RAM"),
+                    Ok(..) => {
+                        let len = ::std::cmp::min(data.len(), self.ram.len());
+                        self.ram[..len].copy_from_slice(&data[..len]);
+                        Ok(())
+                    }
+                }
+            }
+        }
+    }
+
+    fn ram_address(&self, a: u16) -> Option<usize> {
+        let address = (self.rambank * 0x2000) | ((a as usize) & 0x1FFF);
+        if address < self.ram.len() {
+            Some(address)
+        } else {
+            None
+        }
+    }
+}
+
+impl Drop for HuC1 {
+    fn drop(&mut self) {
+        match self.savepath {
+            None => {}
+            Some(ref path) => {
+                let _ = File::create(path).and_then(|mut f| f.write_all(&self.ram));
+            }
+        };
+    }
+}
+
+impl MBC for HuC1 {
+    fn readrom(&self, a: u16) -> u8 {
+        let idx = if a < 0x4000 {
+            a as usize
+        } else {
+            (self.rombank * 0x4000) | ((a as usize) & 0x3FFF)
+        };
+        *self.rom.get(idx).unwrap_or(&0xFF)
+    }
+    fn readram(&mut self, a: u16) -> u8 {
+        if self.ir_mode {
+            let led = self.ir_led;
+            let light = match self.infrared {
+                Some(ref mut callback) => callback(led),
+                None => false,
+            };
+            return 0xC0 | (light as u8);
+        }
+        match self.ram_address(a) {
+            Some(address) => self.ram[address],
+            None => 0xFF,
+        }
+    }
+    fn writerom(&mut self, a: u16, v: u8) {
+        match a {
+            // There is no RAM enable, this switches between RAM and the IR port
+            0x0000..=0x1FFF => self.ir_mode = v & 0x0F == 0x0E,
+            0x2000..=0x3FFF => {
+                self.rombank = match (v as usize) & 0x3F {
+                    0 => 1,
+                    n => n,
+                }
+            }
+            0x4000..=0x5FFF => self.rambank = (v & 0x03) as usize,
+            0x6000..=0x7FFF => {}
+            _ => panic!("Could not write to {:04X} (HuC1)", a),
+        }
+    }
+    fn writeram(&mut self, a: u16, v: u8) {
+        if self.ir_mode {
+            self.ir_led = v & 0x01 == 0x01;
+            let led = self.ir_led;
+            if let Some(ref mut callback) = self.infrared {
+                callback(led);
+            }
+            return;
+        }
+        if let Some(address) = self.ram_address(a) {
+            self.ram[address] = v;
+        }
+    }
+
+    fn rombank(&self) -> usize {
+        self.rombank
+    }
+
+    fn peekram(&mut self, a: u16) -> u8 {
+        match self.ir_mode {
+            true => 0xC0,
+            false => self.readram(a),
+        }
+    }
+
+    fn set_infrared_callback(&mut self, callback: InfraredCallback) {
+        self.infrared = Some(callback);
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_u8(self.rombank as u8);
+        w.write_u8(self.rambank as u8);
+        w.write_bool(self.ir_mode);
+        w.write_bool(self.ir_led);
+        w.write_vec(&self.ram);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> StrResult<()> {
+        self.rombank = (r.read_u8()? & 0x3F) as usize;
+        self.rambank = (r.read_u8()? & 0x03) as usize;
+        self.ir_mode = r.read_bool()?;
+        self.ir_led = r.read_bool()?;
+        r.read_vec_into(&mut self.ram)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::HuC1;
+    use crate::mbc::MBC;
+    use std::sync::{Arc, Mutex};
+
+    fn huc1() -> HuC1 {
+        let mut rom = vec![0; 0x40000];
+        for bank in 0..16 {
+            rom[bank * 0x4000] = bank as u8;
+        }
+        rom[0x147] = 0xFF;
+        rom[0x149] = 0x03;
+        let mut mbc = HuC1::new(rom, "huc1.gb".into()).unwrap();
+        mbc.savepath = None;
+        mbc
+    }
+
+    #[test]
+    fn banking() {
+        let mut mbc = huc1();
+        assert_eq!(mbc.readrom(0x4000), 1);
+        mbc.writerom(0x2000, 0x05);
+        assert_eq!(mbc.readrom(0x4000), 5);
+        mbc.writerom(0x2000, 0x00);
+        assert_eq!(mbc.readrom(0x4000), 1);
+
+        // RAM needs no enable, every bank keeps its own data
+        mbc.writeram(0xA000, 0x11);
+        mbc.writerom(0x4000, 0x02);
+        mbc.writeram(0xA000, 0x22);
+        assert_eq!(mbc.readram(0xA000), 0x22);
+        mbc.writerom(0x4000, 0x00);
+        assert_eq!(mbc.readram(0xA000), 0x11);
+    }
+
+    #[test]
+    fn infrared() {
+        let mut mbc = huc1();
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let calls_cb = calls.clone();
+        mbc.set_infrared_callback(Box::new(move |on| {
+            calls_cb.lock().unwrap().push(on);
+            true
+        }));
+        mbc.writeram(0xA000, 0x42);
+
+        // 0x0E selects the IR port instead of RAM
+        mbc.writerom(0x0000, 0x0E);
+        mbc.writeram(0xA000, 0x01);
+        assert_eq!(mbc.readram(0xA000), 0xC1);
+        assert_eq!(*calls.lock().unwrap(), [true, true]);
+
+        // The debugger does not poll the sensor
+        assert_eq!(mbc.peekram(0xA000), 0xC0);
+        assert_eq!(calls.lock().unwrap().len(), 2);
+
+        mbc.writerom(0x0000, 0x0A);
+        assert_eq!(mbc.readram(0xA000), 0x42);
+    }
+}
@@ -0,0 +1,331 @@
+use crate::mbc::{ram_size, InfraredCallback, MBC};
+use crate::state::{StateReader, StateWriter};
+use crate::StrResult;
+
+use std::io::prelude::*;
+use std::path;
+use std::{fs, io, time};
+
+const RTC_MEMORY_SIZE: usize = 0x100;
+const MINUTES_PER_DAY: u64 = 24 * 60;
+
+pub struct HuC3 {
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+    rombank: usize,
+    rambank: usize,
+    mode: u8,
+    ir_led: bool,
+    infrared: Option<InfraredCallback>,
+    savepath: Option<path::PathBuf>,
+    // The clock chip has its own nibble wide memory, the first 6 nibbles
+    // are the minute of the day and the day counter
+    rtc_memory: [u8; RTC_MEMORY_SIZE],
+    rtc_address: u8,
+    rtc_command: u8,
+    rtc_response: u8,
+    rtc_zero: u64,
+}
+
+impl HuC3 {
+    pub fn new(data: Vec<u8>, file: path::PathBuf) -> StrResult<HuC3> {
+        let ramsize = ram_size(data[0x149]);
+        let mut res = HuC3 {
+            rom: data,
+            ram: vec![0; ramsize],
+            rombank: 1,
+            rambank: 0,
+            mode: 0,
+            ir_led: false,
+            infrared: None,
+            savepath: Some(file.with_extension("gbsave")),
+            rtc_memory: [0; RTC_MEMORY_SIZE],
+            rtc_address: 0,
+            rtc_command: 0,
+            rtc_response: 0,
+            rtc_zero: now(),
+        };
+        res.loadram().map(|_| res)
+    }
+
+    fn loadram(&mut self) -> StrResult<()> {
+        match self.savepath {
+            None => Ok(()),
+            Some(ref savepath) => {
+                let mut file = match fs::File::open(savepath) {
+                    Ok(f) => f,
+                    Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+                    Err(..) => return Err("Could not read existing save file"),
+                };
+                let mut rtc_bytes = [0; 8];
+                file.read_exact(&mut rtc_bytes)
+                    .map_err(|_| "Could not read RTC")?;
+                self.rtc_zero = u64::from_be_bytes(rtc_bytes);
+                file.read_exact(&mut self.rtc_memory)
+                    .map_err(|_| "Could not read RTC")?;
+                let mut data = vec![];
+                match file.read_to_end(&mut data) {
+                    Err(..) => Err("Could not read RAM"),
+                    Ok(..) => {
+                        let len = ::std::cmp::min(data.len(), self.ram.len());
+                        self.ram[..len].copy_from_slice(&data[..len]);
+                        Ok(())
+                    }
+                }
+            }
+        }
+    }
+
+    fn ram_address(&self, a: u16) -> Option<usize> {
+        let address = (self.rambank * 0x2000) | ((a as usize) & 0x1FFF);
+        if address < self.ram.len() {
+            Some(address)
+        } else {
+            None
+        }
+    }
+
+    fn rtc_execute(&mut self, v: u8) {
+        self.rtc_command = (v >> 4) & 0x07;
+        let argument = v & 0x0F;
+        match self.rtc_command {
+            0x1 => {
+                self.rtc_response = self.rtc_memory[self.rtc_address as usize];
+                self.rtc_address = self.rtc_address.wrapping_add(1);
+            }
+            0x3 => {
+                self.rtc_memory[self.rtc_address as usize] = argument;
+                self.rtc_address = self.rtc_address.wrapping_add(1);
+            }
+            0x4 => self.rtc_address = (self.rtc_address & 0xF0) | argument,
+            0x5 => self.rtc_address = (self.rtc_address & 0x0F) | (argument << 4),
+            0x6 => match argument {
+                0x0 => self.rtc_latch(),
+                0x1 => self.rtc_set(),
+                // Status request, the clock is always ready
+                0x2 => self.rtc_response = 0x1,
+                _ => {}
+            },
+            _ => {}
+        }
+    }
+
+    // Copies the current time into the clock memory
+    fn rtc_latch(&mut self) {
+        let minutes = now().saturating_sub(self.rtc_zero) / 60;
+        let minute_of_day = minutes % MINUTES_PER_DAY;
+        let days = (minutes / MINUTES_PER_DAY) & 0xFFF;
+        for i in 0..3 {
+            self.rtc_memory[i] = ((minute_of_day >> (i * 4)) & 0xF) as u8;
+            self.rtc_memory[3 + i] = ((days >> (i * 4)) & 0xF) as u8;
+        }
+    }
+
+    // Sets the current time from the clock memory
+    fn rtc_set(&mut self) {
+        let nibbles = |start: usize| {
+            (0..3).fold(0, |acc, i| acc | ((self.rtc_memory[start + i] as u64 & 0xF) << (i * 4)))
+        };
+        let minutes = nibbles(3) * MINUTES_PER_DAY + nibbles(0) % MINUTES_PER_DAY;
+        self.rtc_zero = now().saturating_sub(minutes * 60);
+    }
+}
+
+fn now() -> u64 {
+    match time::SystemTime::now().duration_since(time::UNIX_EPOCH) {
+        Ok(t) => t.as_secs(),
+        Err(_) => panic!("System clock is set to a time before the unix epoch (1970-01-01)"),
+    }
+}
+
+impl Drop for HuC3 {
+    fn drop(&mut self) {
+        match self.savepath {
+            None => {}
+            Some(ref path) => {
+                let mut file = match fs::File::create(path) {
+                    Ok(f) => f,
+                    Err(..) => return,
+                };
+                let ok = file.write_all(&self.rtc_zero.to_be_bytes()).is_ok()
+                    && file.write_all(&self.rtc_memory).is_ok();
+                if ok {
+                    let _ = file.write_all(&self.ram);
+                };
+            }
+        };
+    }
+}
+
+impl MBC for HuC3 {
+    fn readrom(&self, a: u16) -> u8 {
+        let idx = if a < 0x4000 {
+            a as usize
+        } else {
+            (self.rombank * 0x4000) | ((a as usize) & 0x3FFF)
+        };
+        *self.rom.get(idx).unwrap_or(&0xFF)
+    }
+    fn readram(&mut self, a: u16) -> u8 {
+        match self.mode {
+            0x0 | 0xA => match self.ram_address(a) {
+                Some(address) => self.ram[address],
+                None => 0xFF,
+            },
+            0xC => (self.rtc_command << 4) | self.rtc_response,
+            // Commands execute immediately, so the semaphore always reads ready
+            0xD => 0xFF,
+            0xE => {
+                let led = self.ir_led;
+                let light = match self.infrared {
+                    Some(ref mut callback) => callback(led),
+                    None => false,
+                };
+                0xC0 | (light as u8)
+            }
+            _ => 0xFF,
+        }
+    }
+    fn writerom(&mut self, a: u16, v: u8) {
+        match a {
+            0x0000..=0x1FFF => self.mode = v & 0x0F,
+            0x2000..=0x3FFF => {
+                self.rombank = match (v as usize) & 0x7F {
+                    0 => 1,
+                    n => n,
+                }
+            }
+            0x4000..=0x5FFF => self.rambank = (v & 0x03) as usize,
+            0x6000..=0x7FFF => {}
+            _ => panic!("Could not write to {:04X} (HuC3)", a),
+        }
+    }
+    fn writeram(&mut self, a: u16, v: u8) {
+        match self.mode {
+            0xA => {
+                if let Some(address) = self.ram_address(a) {
+                    self.ram[address] = v;
+                }
+            }
+            0xB => self.rtc_execute(v),
+            0xE => {
+                self.ir_led = v & 0x01 == 0x01;
+                let led = self.ir_led;
+                if let Some(ref mut callback) = self.infrared {
+                    callback(led);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn rombank(&self) -> usize {
+        self.rombank
+    }
+
+    fn peekram(&mut self, a: u16) -> u8 {
+        match self.mode {
+            0xE => 0xC0,
+            _ => self.readram(a),
+        }
+    }
+
+    fn set_infrared_callback(&mut self, callback: InfraredCallback) {
+        self.infrared = Some(callback);
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_u8(self.rombank as u8);
+        w.write_u8(self.rambank as u8);
+        w.write_u8(self.mode);
+        w.write_bool(self.ir_led);
+        w.write_bytes(&self.rtc_memory);
+        w.write_u8(self.rtc_address);
+        w.write_u8(self.rtc_command);
+        w.write_u8(self.rtc_response);
+        w.write_u64(self.rtc_zero);
+        w.write_vec(&self.ram);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> StrResult<()> {
+        self.rombank = (r.read_u8()? & 0x7F) as usize;
+        self.rambank = (r.read_u8()? & 0x03) as usize;
+        self.mode = r.read_u8()? & 0x0F;
+        self.ir_led = r.read_bool()?;
+        r.read_bytes(&mut self.rtc_memory)?;
+        self.rtc_address = r.read_u8()?;
+        self.rtc_command = r.read_u8()? & 0x07;
+        self.rtc_response = r.read_u8()? & 0x0F;
+        self.rtc_zero = r.read_u64()?;
+        r.read_vec_into(&mut self.ram)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::HuC3;
+    use crate::mbc::MBC;
+
+    fn huc3() -> HuC3 {
+        let mut rom = vec![0; 0x8000];
+        rom[0x147] = 0xFE;
+        let mut mbc = HuC3::new(rom, "huc3.gb".into()).unwrap();
+        mbc.savepath = None;
+        mbc
+    }
+
+    fn read_nibbles(mbc: &mut HuC3, address: u8, count: usize) -> Vec<u8> {
+        mbc.writerom(0x0000, 0x0B);
+        mbc.writeram(0xA000, 0x40 | (address & 0xF));
+        mbc.writeram(0xA000, 0x50 | (address >> 4));
+        (0..count)
+            .map(|_| {
+                mbc.writerom(0x0000, 0x0B);
+                mbc.writeram(0xA000, 0x10);
+                mbc.writerom(0x0000, 0x0C);
+                mbc.readram(0xA000) & 0x0F
+            })
+            .collect()
+    }
+
+    #[test]
+    fn rtc_set_and_latch() {
+        let mut mbc = huc3();
+        // Write 1000 minutes and 300 days, then set the clock from memory
+        mbc.writerom(0x0000, 0x0B);
+        mbc.writeram(0xA000, 0x40);
+        mbc.writeram(0xA000, 0x50);
+        for &nibble in [0x8, 0xE, 0x3, 0xC, 0x2, 0x1].iter() {
+            mbc.writeram(0xA000, 0x30 | nibble);
+        }
+        mbc.writeram(0xA000, 0x61);
+        assert_eq!(read_nibbles(&mut mbc, 0x00, 6), [0x8, 0xE, 0x3, 0xC, 0x2, 0x1]);
+
+        // Latching recomputes the memory from the clock
+        for i in 0..6 {
+            mbc.rtc_memory[i] = 0;
+        }
+        mbc.writerom(0x0000, 0x0B);
+        mbc.writeram(0xA000, 0x60);
+        let time = read_nibbles(&mut mbc, 0x00, 6);
+        assert!(time[3..] == [0xC, 0x2, 0x1]);
+        assert!(time[..3] == [0x8, 0xE, 0x3] || time[..3] == [0x9, 0xE, 0x3]);
+    }
+
+    #[test]
+    fn infrared() {
+        use std::sync::{Arc, Mutex};
+
+        let mut mbc = huc3();
+        let led = Arc::new(Mutex::new(false));
+        let led_cb = led.clone();
+        mbc.set_infrared_callback(Box::new(move |on| {
+            *led_cb.lock().unwrap() = on;
+            true
+        }));
+        mbc.writerom(0x0000, 0x0E);
+        assert_eq!(mbc.readram(0xA000), 0xC1);
+        mbc.writeram(0xA000, 0x01);
+        assert!(*led.lock().unwrap());
+    }
+}
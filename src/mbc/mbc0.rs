@@ -1,4 +1,5 @@
 use crate::mbc::MBC;
+use crate::state::{StateReader, StateWriter};
 use crate::StrResult;
 
 pub struct MBC0 {
@@ -15,7 +16,7 @@ impl MBC for MBC0 {
     fn readrom(&self, a: u16) -> u8 {
         self.rom[a as usize]
     }
-    fn readram(&self, _a: u16) -> u8 {
+    fn readram(&mut self, _a: u16) -> u8 {
         0
     }
     fn writerom(&mut self, _a: u16, _v: u8) {
@@ -24,4 +25,11 @@ impl MBC for MBC0 {
     fn writeram(&mut self, _a: u16, _v: u8) {
         ()
     }
+    fn rombank(&self) -> usize {
+        1
+    }
+    fn save_state(&self, _w: &mut StateWriter) {}
+    fn load_state(&mut self, _r: &mut StateReader) -> StrResult<()> {
+        Ok(())
+    }
 }
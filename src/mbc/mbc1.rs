@@ -2,15 +2,18 @@ use std::io::prelude::*;
 use std::{fs, io, path};
 
 use crate::mbc::{ram_size, MBC};
+use crate::state::{StateReader, StateWriter};
 use crate::StrResult;
 
 pub struct MBC1 {
     rom: Vec<u8>,
     ram: Vec<u8>,
     ram_on: bool,
-    ram_mode: bool,
-    rombank: usize,
-    rambank: usize,
+    banking_mode: bool,
+    bank1: usize,
+    bank2: usize,
+    multicart: bool,
+    rombanks: usize,
     savepath: Option<path::PathBuf>,
 }
 
@@ -21,19 +24,51 @@ impl MBC1 {
             0x03 => (Some(file.with_extension("gbsave")), ram_size(data[0x149])),
             _ => (None, 0),
         };
+        let rombanks = ::std::cmp::max(data.len() / 0x4000, 2).next_power_of_two();
 
         let mut res = MBC1 {
+            multicart: is_multicart(&data),
             rom: data,
             ram: ::std::iter::repeat(0u8).take(ramsize).collect(),
             ram_on: false,
-            ram_mode: false,
-            rombank: 1,
-            rambank: 0,
+            banking_mode: false,
+            bank1: 1,
+            bank2: 0,
+            rombanks,
             savepath: svpath,
         };
         res.loadram().map(|_| res)
     }
 
+    // MBC1M collection carts only wire up 4 bits of BANK1
+    fn bank2_shift(&self) -> usize {
+        if self.multicart {
+            4
+        } else {
+            5
+        }
+    }
+
+    fn rombank_low(&self) -> usize {
+        let bank = if self.banking_mode {
+            self.bank2 << self.bank2_shift()
+        } else {
+            0
+        };
+        bank & (self.rombanks - 1)
+    }
+
+    fn rombank_high(&self) -> usize {
+        let mask = (1 << self.bank2_shift()) - 1;
+        let bank = (self.bank2 << self.bank2_shift()) | (self.bank1 & mask);
+        bank & (self.rombanks - 1)
+    }
+
+    fn ram_address(&self, a: u16) -> usize {
+        let rambank = if self.banking_mode { self.bank2 } else { 0 };
+        ((rambank * 0x2000) | ((a & 0x1FFF) as usize)) & (self.ram.len() - 1)
+    }
+
     fn loadram(&mut self) -> StrResult<()> {
         match self.savepath {
             None => Ok(()),
@@ -43,7 +78,8 @@ impl MBC1 {
                     Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
                     Err(_) => Err("Could not open save file"),
                     Ok(..) => {
-                        self.ram = data;
+                        let len = ::std::cmp::min(data.len(), self.ram.len());
+                        self.ram[..len].copy_from_slice(&data[..len]);
                         Ok(())
                     }
                 }
@@ -66,51 +102,172 @@ impl Drop for MBC1 {
 impl MBC for MBC1 {
     fn readrom(&self, a: u16) -> u8 {
         let idx = if a < 0x4000 {
-            a as usize
+            (self.rombank_low() * 0x4000) | (a as usize)
         } else {
-            self.rombank * 0x4000 | ((a as usize) & 0x3FFF)
+            (self.rombank_high() * 0x4000) | ((a as usize) & 0x3FFF)
         };
-        *self.rom.get(idx).unwrap_or(&0)
+        *self.rom.get(idx).unwrap_or(&0xFF)
     }
-    fn readram(&self, a: u16) -> u8 {
-        if !self.ram_on {
-            return 0;
+    fn readram(&mut self, a: u16) -> u8 {
+        if !self.ram_on || self.ram.is_empty() {
+            return 0xFF;
         }
-        let rambank = if self.ram_mode { self.rambank } else { 0 };
-        self.ram[(rambank * 0x2000) | ((a & 0x1FFF) as usize)]
+        self.ram[self.ram_address(a)]
     }
 
     fn writerom(&mut self, a: u16, v: u8) {
         match a {
             0x0000..=0x1FFF => {
-                self.ram_on = v == 0x0A;
+                self.ram_on = v & 0x0F == 0x0A;
             }
             0x2000..=0x3FFF => {
-                self.rombank = (self.rombank & 0x60)
-                    | match (v as usize) & 0x1F {
-                        0 => 1,
-                        n => n,
-                    }
+                self.bank1 = match (v as usize) & 0x1F {
+                    0 => 1,
+                    n => n,
+                }
             }
             0x4000..=0x5FFF => {
-                if !self.ram_mode {
-                    self.rombank = self.rombank & 0x1F | (((v as usize) & 0x03) << 5)
-                } else {
-                    self.rambank = (v as usize) & 0x03;
-                }
+                self.bank2 = (v as usize) & 0x03;
             }
             0x6000..=0x7FFF => {
-                self.ram_mode = (v & 0x01) == 0x01;
+                self.banking_mode = (v & 0x01) == 0x01;
             }
             _ => panic!("Could not write to {:04X} (MBC1)", a),
         }
     }
 
     fn writeram(&mut self, a: u16, v: u8) {
-        if !self.ram_on {
+        if !self.ram_on || self.ram.is_empty() {
             return;
         }
-        let rambank = if self.ram_mode { self.rambank } else { 0 };
-        self.ram[(rambank * 0x2000) | ((a & 0x1FFF) as usize)] = v;
+        let address = self.ram_address(a);
+        self.ram[address] = v;
+    }
+
+    fn rombank(&self) -> usize {
+        self.rombank_high()
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_bool(self.ram_on);
+        w.write_bool(self.banking_mode);
+        w.write_u8(self.bank1 as u8);
+        w.write_u8(self.bank2 as u8);
+        w.write_vec(&self.ram);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> StrResult<()> {
+        self.ram_on = r.read_bool()?;
+        self.banking_mode = r.read_bool()?;
+        self.bank1 = match r.read_u8()? & 0x1F {
+            0 => 1,
+            n => n as usize,
+        };
+        self.bank2 = r.read_u8()? as usize & 0x03;
+        r.read_vec_into(&mut self.ram)
+    }
+}
+
+// Collection carts are 8 Mbit and repeat the Nintendo logo at the start of
+// each 2 Mbit game, so bank 0x10 has a header as well
+fn is_multicart(rom: &[u8]) -> bool {
+    const LOGO: ::std::ops::Range<usize> = 0x104..0x134;
+    rom.len() == 0x100000 && rom[LOGO] == rom[0x10 * 0x4000 + LOGO.start..0x10 * 0x4000 + LOGO.end]
+}
+
+#[cfg(test)]
+mod test {
+    use super::MBC1;
+    use crate::mbc::MBC;
+    use std::path::PathBuf;
+
+    // Every bank starts with its own number, the header has MBC1+RAM without battery
+    fn mbc1(banks: usize, ramsize: u8, multicart: bool) -> MBC1 {
+        let mut rom = vec![0; banks * 0x4000];
+        for bank in 0..banks {
+            rom[bank * 0x4000] = bank as u8;
+        }
+        rom[0x104..0x134].copy_from_slice(&[0xCE; 0x30]);
+        if multicart {
+            rom[0x40104..0x40134].copy_from_slice(&[0xCE; 0x30]);
+        }
+        rom[0x147] = 0x02;
+        rom[0x149] = ramsize;
+        MBC1::new(rom, PathBuf::from("mbc1.gb")).unwrap()
+    }
+
+    #[test]
+    fn rom_512kb() {
+        let mut mbc = mbc1(32, 0, false);
+        assert_eq!(mbc.readrom(0x4000), 1);
+        mbc.writerom(0x2000, 0x00);
+        assert_eq!(mbc.readrom(0x4000), 1);
+        mbc.writerom(0x2000, 0x1F);
+        assert_eq!(mbc.readrom(0x4000), 0x1F);
+        // Bank 2 is not connected on small ROMs
+        mbc.writerom(0x4000, 0x01);
+        mbc.writerom(0x6000, 0x01);
+        assert_eq!(mbc.readrom(0x4000), 0x1F);
+        assert_eq!(mbc.readrom(0x0000), 0x00);
+    }
+
+    #[test]
+    fn rom_2mb() {
+        let mut mbc = mbc1(128, 0, false);
+        mbc.writerom(0x4000, 0x02);
+        mbc.writerom(0x2000, 0x00);
+        assert_eq!(mbc.readrom(0x4000), 0x41);
+        assert_eq!(mbc.readrom(0x0000), 0x00);
+        mbc.writerom(0x6000, 0x01);
+        assert_eq!(mbc.readrom(0x0000), 0x40);
+        assert_eq!(mbc.readrom(0x4000), 0x41);
+        assert_eq!(mbc.rombank(), 0x41);
+    }
+
+    #[test]
+    fn ram_32kb() {
+        let mut mbc = mbc1(4, 3, false);
+        assert_eq!(mbc.readram(0xA000), 0xFF);
+        mbc.writerom(0x0000, 0x0A);
+        mbc.writerom(0x6000, 0x01);
+        for bank in 0..4 {
+            mbc.writerom(0x4000, bank);
+            mbc.writeram(0xA000, 0x10 + bank);
+        }
+        mbc.writerom(0x4000, 0x02);
+        assert_eq!(mbc.readram(0xA000), 0x12);
+        // Mode 0 always uses the first RAM bank
+        mbc.writerom(0x6000, 0x00);
+        assert_eq!(mbc.readram(0xA000), 0x10);
+        mbc.writerom(0x0000, 0x00);
+        assert_eq!(mbc.readram(0xA000), 0xFF);
+    }
+
+    #[test]
+    fn ram_8kb_ignores_bank2() {
+        let mut mbc = mbc1(4, 2, false);
+        mbc.writerom(0x0000, 0x0A);
+        mbc.writerom(0x6000, 0x01);
+        mbc.writeram(0xA000, 0x42);
+        mbc.writerom(0x4000, 0x03);
+        assert_eq!(mbc.readram(0xA000), 0x42);
+    }
+
+    #[test]
+    fn multicart() {
+        let mut mbc = mbc1(64, 0, true);
+        assert!(mbc.multicart);
+        mbc.writerom(0x4000, 0x01);
+        mbc.writerom(0x2000, 0x02);
+        assert_eq!(mbc.readrom(0x4000), 0x12);
+        // Bit 4 of bank 1 is not connected, but still counts for the zero check
+        mbc.writerom(0x2000, 0x12);
+        assert_eq!(mbc.readrom(0x4000), 0x12);
+        mbc.writerom(0x2000, 0x10);
+        assert_eq!(mbc.readrom(0x4000), 0x10);
+        mbc.writerom(0x6000, 0x01);
+        assert_eq!(mbc.readrom(0x0000), 0x10);
+
+        assert!(!mbc1(64, 0, false).multicart);
     }
 }
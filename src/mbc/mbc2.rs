@@ -0,0 +1,166 @@
+use crate::mbc::MBC;
+use crate::state::{StateReader, StateWriter};
+use crate::StrResult;
+
+use std::fs::File;
+use std::io::prelude::*;
+use std::{io, path};
+
+const RAM_SIZE: usize = 0x200;
+
+pub struct MBC2 {
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+    ram_on: bool,
+    rombank: usize,
+    savepath: Option<path::PathBuf>,
+}
+
+impl MBC2 {
+    pub fn new(data: Vec<u8>, file: path::PathBuf) -> StrResult<MBC2> {
+        let svpath = match data[0x147] {
+            0x06 => Some(file.with_extension("gbsave")),
+            _ => None,
+        };
+
+        let mut res = MBC2 {
+            rom: data,
+            ram: vec![0; RAM_SIZE],
+            ram_on: false,
+            rombank: 1,
+            savepath: svpath,
+        };
+        res.loadram().map(|_| res)
+    }
+
+    fn loadram(&mut self) -> StrResult<()> {
+        match self.savepath {
+            None => Ok(()),
+            Some(ref savepath) => {
+                let mut data = vec![];
+                match File::open(savepath).and_then(|mut f| f.read_to_end(&mut data)) {
+                    Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+                    Err(_) => Err("Could not read RAM"),
+                    Ok(..) => {
+                        let len = ::std::cmp::min(data.len(), RAM_SIZE);
+                        self.ram[..len].copy_from_slice(&data[..len]);
+                        Ok(())
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Drop for MBC2 {
+    fn drop(&mut self) {
+        match self.savepath {
+            None => {}
+            Some(ref path) => {
+                let _ = File::create(path).and_then(|mut f| f.write_all(&self.ram));
+            }
+        };
+    }
+}
+
+impl MBC for MBC2 {
+    fn readrom(&self, a: u16) -> u8 {
+        let idx = if a < 0x4000 {
+            a as usize
+        } else {
+            (self.rombank * 0x4000) | ((a as usize) & 0x3FFF)
+        };
+        *self.rom.get(idx).unwrap_or(&0)
+    }
+    fn readram(&mut self, a: u16) -> u8 {
+        if !self.ram_on {
+            return 0xFF;
+        }
+        // Only the lower nibble exists, the RAM is repeated over the whole area
+        self.ram[(a as usize) & 0x1FF] | 0xF0
+    }
+    fn writerom(&mut self, a: u16, v: u8) {
+        match a {
+            // Address bit 8 selects between the RAM enable and ROM bank registers
+            0x0000..=0x3FFF if a & 0x100 == 0 => self.ram_on = v & 0x0F == 0x0A,
+            0x0000..=0x3FFF => {
+                self.rombank = match (v as usize) & 0x0F {
+                    0 => 1,
+                    n => n,
+                }
+            }
+            0x4000..=0x7FFF => {}
+            _ => panic!("Could not write to {:04X} (MBC2)", a),
+        }
+    }
+    fn writeram(&mut self, a: u16, v: u8) {
+        if !self.ram_on {
+            return;
+        }
+        self.ram[(a as usize) & 0x1FF] = v & 0x0F;
+    }
+
+    fn rombank(&self) -> usize {
+        self.rombank
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_bool(self.ram_on);
+        w.write_u32(self.rombank as u32);
+        w.write_vec(&self.ram);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> StrResult<()> {
+        self.ram_on = r.read_bool()?;
+        self.rombank = match r.read_u32()? as usize & 0x0F {
+            0 => 1,
+            n => n,
+        };
+        r.read_vec_into(&mut self.ram)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::MBC2;
+    use crate::mbc::MBC;
+    use std::path::PathBuf;
+
+    fn mbc2() -> MBC2 {
+        let mut rom = vec![0; 0x40000];
+        for bank in 0..16 {
+            rom[bank * 0x4000] = bank as u8;
+        }
+        rom[0x147] = 0x05;
+        MBC2::new(rom, PathBuf::from("mbc2.gb")).unwrap()
+    }
+
+    #[test]
+    fn rom_banking() {
+        let mut mbc = mbc2();
+        assert_eq!(mbc.readrom(0x4000), 1);
+        mbc.writerom(0x2100, 0x05);
+        assert_eq!(mbc.readrom(0x4000), 5);
+        mbc.writerom(0x2100, 0x10);
+        assert_eq!(mbc.readrom(0x4000), 1);
+        // Writes with address bit 8 clear do not switch banks
+        mbc.writerom(0x2000, 0x03);
+        assert_eq!(mbc.readrom(0x4000), 1);
+    }
+
+    #[test]
+    fn half_byte_ram() {
+        let mut mbc = mbc2();
+        mbc.writeram(0xA000, 0x12);
+        assert_eq!(mbc.readram(0xA000), 0xFF);
+
+        mbc.writerom(0x0000, 0x0A);
+        mbc.writeram(0xA000, 0x12);
+        assert_eq!(mbc.readram(0xA000), 0xF2);
+        assert_eq!(mbc.readram(0xA200), 0xF2);
+        assert_eq!(mbc.readram(0xBE00), 0xF2);
+
+        mbc.writerom(0x0000, 0x00);
+        assert_eq!(mbc.readram(0xA000), 0xFF);
+    }
+}
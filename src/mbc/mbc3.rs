@@ -1,4 +1,5 @@
 use crate::mbc::{ram_size, MBC};
+use crate::state::{StateReader, StateWriter};
 use crate::StrResult;
 
 use std::io::prelude::*;
@@ -152,7 +153,7 @@ impl MBC for MBC3 {
         };
         *self.rom.get(idx).unwrap_or(&0)
     }
-    fn readram(&self, a: u16) -> u8 {
+    fn readram(&mut self, a: u16) -> u8 {
         if !self.ram_on {
             return 0;
         }
@@ -196,4 +197,33 @@ impl MBC for MBC3 {
             self.calc_rtc_zero();
         }
     }
+
+    fn rombank(&self) -> usize {
+        self.rombank
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_u32(self.rombank as u32);
+        w.write_u32(self.rambank as u32);
+        w.write_bool(self.ram_on);
+        w.write_bytes(&self.rtc_ram);
+        w.write_bool(self.rtc_lock);
+        w.write_bool(self.rtc_zero.is_some());
+        w.write_u64(self.rtc_zero.unwrap_or(0));
+        w.write_vec(&self.ram);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> StrResult<()> {
+        self.rombank = r.read_u32()? as usize & 0x7F;
+        self.rambank = r.read_u32()? as usize & 0x0F;
+        self.ram_on = r.read_bool()?;
+        r.read_bytes(&mut self.rtc_ram)?;
+        self.rtc_lock = r.read_bool()?;
+        let has_rtc = r.read_bool()?;
+        let rtc_zero = r.read_u64()?;
+        if self.rtc_zero.is_some() && has_rtc {
+            self.rtc_zero = Some(rtc_zero);
+        }
+        r.read_vec_into(&mut self.ram)
+    }
 }
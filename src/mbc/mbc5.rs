@@ -1,4 +1,5 @@
-use crate::mbc::{ram_size, MBC};
+use crate::mbc::{ram_size, RumbleCallback, MBC};
+use crate::state::{StateReader, StateWriter};
 use crate::StrResult;
 
 use std::fs::File;
@@ -12,6 +13,9 @@ pub struct MBC5 {
     rambank: usize,
     ram_on: bool,
     savepath: Option<path::PathBuf>,
+    has_rumble: bool,
+    rumble_on: bool,
+    rumble: Option<RumbleCallback>,
 }
 
 impl MBC5 {
@@ -33,10 +37,23 @@ impl MBC5 {
             rambank: 0,
             ram_on: false,
             savepath: svpath,
+            has_rumble: (0x1C..=0x1E).contains(&subtype),
+            rumble_on: false,
+            rumble: None,
         };
         res.loadram().map(|_| res)
     }
 
+    // Tells the frontend when the motor starts or stops
+    fn set_rumble(&mut self, on: bool) {
+        if on != self.rumble_on {
+            self.rumble_on = on;
+            if let Some(ref mut callback) = self.rumble {
+                callback(on);
+            }
+        }
+    }
+
     fn loadram(&mut self) -> StrResult<()> {
         match self.savepath {
             None => Ok(()),
@@ -75,7 +92,7 @@ impl MBC for MBC5 {
         };
         *self.rom.get(idx).unwrap_or(&0)
     }
-    fn readram(&self, a: u16) -> u8 {
+    fn readram(&mut self, a: u16) -> u8 {
         if !self.ram_on {
             return 0;
         }
@@ -86,6 +103,11 @@ impl MBC for MBC5 {
             0x0000..=0x1FFF => self.ram_on = v == 0x0A,
             0x2000..=0x2FFF => self.rombank = (self.rombank & 0x100) | (v as usize),
             0x3000..=0x3FFF => self.rombank = (self.rombank & 0x0FF) | (((v & 0x1) as usize) << 8),
+            0x4000..=0x5FFF if self.has_rumble => {
+                // Bit 3 drives the rumble motor instead of selecting a bank
+                self.rambank = (v & 0x07) as usize;
+                self.set_rumble(v & 0x08 != 0);
+            }
             0x4000..=0x5FFF => self.rambank = (v & 0x0F) as usize,
             0x6000..=0x7FFF => { /* ? */ }
             _ => panic!("Could not write to {:04X} (MBC5)", a),
@@ -97,4 +119,59 @@ impl MBC for MBC5 {
         }
         self.ram[self.rambank * 0x2000 | ((a as usize) & 0x1FFF)] = v;
     }
+
+    fn rombank(&self) -> usize {
+        self.rombank
+    }
+
+    fn set_rumble_callback(&mut self, callback: RumbleCallback) {
+        self.rumble = Some(callback);
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_u32(self.rombank as u32);
+        w.write_u32(self.rambank as u32);
+        w.write_bool(self.ram_on);
+        w.write_bool(self.rumble_on);
+        w.write_vec(&self.ram);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> StrResult<()> {
+        self.rombank = r.read_u32()? as usize & 0x1FF;
+        self.rambank = r.read_u32()? as usize & 0x0F;
+        self.ram_on = r.read_bool()?;
+        let rumble_on = r.read_bool()?;
+        r.read_vec_into(&mut self.ram)?;
+        self.set_rumble(rumble_on);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::MBC5;
+    use crate::mbc::MBC;
+    use crate::state::{StateReader, StateWriter};
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn rumble_state() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x147] = 0x1C;
+        let mut mbc = MBC5::new(rom.clone(), "mbc5.gb".into()).unwrap();
+        mbc.writerom(0x4000, 0x08);
+        let mut w = StateWriter::new();
+        mbc.save_state(&mut w);
+        let data = w.into_inner();
+
+        // The motor is started again when the state is loaded
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let mut loaded = MBC5::new(rom, "mbc5.gb".into()).unwrap();
+        let log = calls.clone();
+        loaded.set_rumble_callback(Box::new(move |on| log.lock().unwrap().push(on)));
+        loaded.load_state(&mut StateReader::new(&data)).unwrap();
+        assert_eq!(*calls.lock().unwrap(), vec![true]);
+        loaded.writerom(0x4000, 0x00);
+        assert_eq!(*calls.lock().unwrap(), vec![true, false]);
+    }
 }
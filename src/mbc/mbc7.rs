@@ -0,0 +1,399 @@
+use crate::mbc::MBC;
+use crate::state::{StateReader, StateWriter};
+use crate::StrResult;
+
+use std::fs::File;
+use std::io::prelude::*;
+use std::{io, path};
+
+const EEPROM_WORDS: usize = 128;
+const ACCEL_CENTER: f32 = 0x81D0 as f32;
+const ACCEL_GRAVITY: f32 = 0x70 as f32;
+
+pub struct MBC7 {
+    rom: Vec<u8>,
+    rombank: usize,
+    ram_on: bool,
+    ram_on2: bool,
+    tilt_x: u16,
+    tilt_y: u16,
+    latch_x: u16,
+    latch_y: u16,
+    latch_ready: bool,
+    eeprom: Eeprom,
+    savepath: Option<path::PathBuf>,
+}
+
+// 93LC56 serial EEPROM, organised as 128 words of 16 bits
+struct Eeprom {
+    data: [u16; EEPROM_WORDS],
+    cs: bool,
+    clk: bool,
+    di: bool,
+    dout: bool,
+    write_enabled: bool,
+    // Bits shifted in, starting with the start bit
+    command: u32,
+    command_bits: u32,
+    // Bits to shift out for a read
+    output: u16,
+    output_bits: u32,
+}
+
+impl MBC7 {
+    pub fn new(data: Vec<u8>, file: path::PathBuf) -> StrResult<MBC7> {
+        let mut res = MBC7 {
+            rom: data,
+            rombank: 1,
+            ram_on: false,
+            ram_on2: false,
+            tilt_x: ACCEL_CENTER as u16,
+            tilt_y: ACCEL_CENTER as u16,
+            latch_x: 0x8000,
+            latch_y: 0x8000,
+            latch_ready: false,
+            eeprom: Eeprom::new(),
+            savepath: Some(file.with_extension("gbsave")),
+        };
+        res.loadram().map(|_| res)
+    }
+
+    fn loadram(&mut self) -> StrResult<()> {
+        match self.savepath {
+            None => Ok(()),
+            Some(ref savepath) => {
+                let mut data = vec![];
+                match File::open(savepath).and_then(|mut f| f.read_to_end(&mut data)) {
+                    Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+                    Err(_) => Err("Could not read RAM"),
+                    Ok(..) => {
+                        for (word, bytes) in self.eeprom.data.iter_mut().zip(data.chunks(2)) {
+                            if bytes.len() == 2 {
+                                *word = ((bytes[0] as u16) << 8) | (bytes[1] as u16);
+                            }
+                        }
+                        Ok(())
+                    }
+                }
+            }
+        }
+    }
+
+    fn eeprom_bytes(&self) -> Vec<u8> {
+        self.eeprom.data.iter().flat_map(|w| vec![(w >> 8) as u8, *w as u8]).collect()
+    }
+}
+
+impl Drop for MBC7 {
+    fn drop(&mut self) {
+        match self.savepath {
+            None => {}
+            Some(ref path) => {
+                let _ = File::create(path).and_then(|mut f| f.write_all(&self.eeprom_bytes()));
+            }
+        };
+    }
+}
+
+impl MBC for MBC7 {
+    fn readrom(&self, a: u16) -> u8 {
+        let idx = if a < 0x4000 {
+            a as usize
+        } else {
+            (self.rombank * 0x4000) | ((a as usize) & 0x3FFF)
+        };
+        *self.rom.get(idx).unwrap_or(&0xFF)
+    }
+    fn readram(&mut self, a: u16) -> u8 {
+        if !self.ram_on || !self.ram_on2 || a >= 0xB000 {
+            return 0xFF;
+        }
+        match (a >> 4) & 0x0F {
+            0x2 => self.latch_x as u8,
+            0x3 => (self.latch_x >> 8) as u8,
+            0x4 => self.latch_y as u8,
+            0x5 => (self.latch_y >> 8) as u8,
+            0x6 => 0x00,
+            0x8 => self.eeprom.read(),
+            _ => 0xFF,
+        }
+    }
+    fn writerom(&mut self, a: u16, v: u8) {
+        match a {
+            0x0000..=0x1FFF => self.ram_on = v == 0x0A,
+            0x2000..=0x3FFF => self.rombank = (v & 0x7F) as usize,
+            0x4000..=0x5FFF => self.ram_on2 = v == 0x40,
+            0x6000..=0x7FFF => {}
+            _ => panic!("Could not write to {:04X} (MBC7)", a),
+        }
+    }
+    fn writeram(&mut self, a: u16, v: u8) {
+        if !self.ram_on || !self.ram_on2 || a >= 0xB000 {
+            return;
+        }
+        match (a >> 4) & 0x0F {
+            0x0 if v == 0x55 => {
+                self.latch_x = 0x8000;
+                self.latch_y = 0x8000;
+                self.latch_ready = true;
+            }
+            0x1 if v == 0xAA && self.latch_ready => {
+                self.latch_x = self.tilt_x;
+                self.latch_y = self.tilt_y;
+                self.latch_ready = false;
+            }
+            0x8 => self.eeprom.write(v),
+            _ => {}
+        }
+    }
+
+    fn rombank(&self) -> usize {
+        self.rombank
+    }
+
+    fn set_tilt(&mut self, x: f32, y: f32) {
+        self.tilt_x = (ACCEL_CENTER - x.clamp(-1.0, 1.0) * ACCEL_GRAVITY) as u16;
+        self.tilt_y = (ACCEL_CENTER + y.clamp(-1.0, 1.0) * ACCEL_GRAVITY) as u16;
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_u8(self.rombank as u8);
+        w.write_bool(self.ram_on);
+        w.write_bool(self.ram_on2);
+        w.write_u16(self.latch_x);
+        w.write_u16(self.latch_y);
+        w.write_bool(self.latch_ready);
+        self.eeprom.save_state(w);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> StrResult<()> {
+        self.rombank = (r.read_u8()? & 0x7F) as usize;
+        self.ram_on = r.read_bool()?;
+        self.ram_on2 = r.read_bool()?;
+        self.latch_x = r.read_u16()?;
+        self.latch_y = r.read_u16()?;
+        self.latch_ready = r.read_bool()?;
+        self.eeprom.load_state(r)
+    }
+}
+
+impl Eeprom {
+    fn new() -> Eeprom {
+        Eeprom {
+            data: [0xFFFF; EEPROM_WORDS],
+            cs: false,
+            clk: false,
+            di: false,
+            dout: true,
+            write_enabled: false,
+            command: 0,
+            command_bits: 0,
+            output: 0,
+            output_bits: 0,
+        }
+    }
+
+    fn read(&self) -> u8 {
+        (if self.cs { 0x80 } else { 0 })
+            | (if self.clk { 0x40 } else { 0 })
+            | (if self.di { 0x02 } else { 0 })
+            | (if self.dout { 0x01 } else { 0 })
+    }
+
+    fn write(&mut self, v: u8) {
+        let cs = v & 0x80 != 0;
+        let clk = v & 0x40 != 0;
+        self.di = v & 0x02 != 0;
+
+        if !cs {
+            self.command = 0;
+            self.command_bits = 0;
+            self.output_bits = 0;
+        } else if clk && !self.clk {
+            self.clock();
+        }
+        self.cs = cs;
+        self.clk = clk;
+    }
+
+    fn clock(&mut self) {
+        if self.output_bits > 0 {
+            self.dout = self.output & 0x8000 != 0;
+            self.output <<= 1;
+            self.output_bits -= 1;
+            return;
+        }
+
+        // Wait for the start bit
+        if self.command_bits == 0 && !self.di {
+            return;
+        }
+        self.command = (self.command << 1) | (self.di as u32);
+        self.command_bits += 1;
+
+        // Start bit, 2 opcode bits and 8 address bits, optionally followed by 16 data bits
+        if self.command_bits == 11 {
+            let opcode = (self.command >> 8) & 0x3;
+            let address = (self.command & 0x7F) as usize;
+            match opcode {
+                0b10 => {
+                    // A dummy zero bit precedes the data
+                    self.dout = false;
+                    self.output = self.data[address];
+                    self.output_bits = 16;
+                    self.finish();
+                }
+                0b11 => {
+                    if self.write_enabled {
+                        self.data[address] = 0xFFFF;
+                    }
+                    self.finish();
+                }
+                0b00 => match (self.command >> 6) & 0x3 {
+                    0b00 => {
+                        self.write_enabled = false;
+                        self.finish();
+                    }
+                    0b10 => {
+                        if self.write_enabled {
+                            self.data = [0xFFFF; EEPROM_WORDS];
+                        }
+                        self.finish();
+                    }
+                    0b11 => {
+                        self.write_enabled = true;
+                        self.finish();
+                    }
+                    _ => {}
+                },
+                _ => {}
+            }
+        } else if self.command_bits == 27 {
+            let value = self.command as u16;
+            let address = ((self.command >> 16) & 0x7F) as usize;
+            if self.write_enabled {
+                match (self.command >> 24) & 0x3 {
+                    0b01 => self.data[address] = value,
+                    _ => self.data = [value; EEPROM_WORDS],
+                }
+            }
+            self.finish();
+        }
+    }
+
+    // Writes complete immediately, so the chip reports ready right away
+    fn finish(&mut self) {
+        if self.output_bits == 0 {
+            self.dout = true;
+        }
+        self.command = 0;
+        self.command_bits = 0;
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        for &word in self.data.iter() {
+            w.write_u16(word);
+        }
+        w.write_bool(self.cs);
+        w.write_bool(self.clk);
+        w.write_bool(self.di);
+        w.write_bool(self.dout);
+        w.write_bool(self.write_enabled);
+        w.write_u32(self.command);
+        w.write_u32(self.command_bits);
+        w.write_u16(self.output);
+        w.write_u32(self.output_bits);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> StrResult<()> {
+        for word in self.data.iter_mut() {
+            *word = r.read_u16()?;
+        }
+        self.cs = r.read_bool()?;
+        self.clk = r.read_bool()?;
+        self.di = r.read_bool()?;
+        self.dout = r.read_bool()?;
+        self.write_enabled = r.read_bool()?;
+        self.command = r.read_u32()?;
+        self.command_bits = r.read_u32()? % 27;
+        self.output = r.read_u16()?;
+        self.output_bits = r.read_u32()? & 0x1F;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Eeprom, MBC7};
+    use crate::mbc::MBC;
+
+    fn send(eeprom: &mut Eeprom, bits: &[u8]) {
+        for &bit in bits {
+            eeprom.write(0x80 | (bit << 1));
+            eeprom.write(0xC0 | (bit << 1));
+        }
+    }
+
+    fn receive(eeprom: &mut Eeprom) -> u16 {
+        let mut value = 0;
+        for _ in 0..16 {
+            eeprom.write(0x80);
+            eeprom.write(0xC0);
+            value = (value << 1) | (eeprom.read() & 1) as u16;
+        }
+        value
+    }
+
+    #[test]
+    fn eeprom_write_read() {
+        let mut eeprom = Eeprom::new();
+        // EWEN, then WRITE 0x1234 to word 5
+        send(&mut eeprom, &[1, 0, 0, 1, 1, 0, 0, 0, 0, 0, 0]);
+        eeprom.write(0x00);
+        send(&mut eeprom, &[1, 0, 1, 0, 0, 0, 0, 0, 1, 0, 1]);
+        send(&mut eeprom, &[0, 0, 0, 1, 0, 0, 1, 0, 0, 0, 1, 1, 0, 1, 0, 0]);
+        eeprom.write(0x00);
+        assert_eq!(eeprom.data[5], 0x1234);
+
+        // READ word 5
+        send(&mut eeprom, &[1, 1, 0, 0, 0, 0, 0, 0, 1, 0, 1]);
+        assert_eq!(eeprom.read() & 1, 0);
+        assert_eq!(receive(&mut eeprom), 0x1234);
+    }
+
+    #[test]
+    fn eeprom_write_protected() {
+        let mut eeprom = Eeprom::new();
+        send(&mut eeprom, &[1, 0, 1, 0, 0, 0, 0, 0, 1, 0, 1]);
+        send(&mut eeprom, &[0; 16]);
+        eeprom.write(0x00);
+        assert_eq!(eeprom.data[5], 0xFFFF);
+    }
+
+    #[test]
+    fn accelerometer_latch() {
+        let mut mbc = MBC7::new(vec![0; 0x8000], "mbc7.gb".into()).unwrap();
+        mbc.savepath = None;
+        mbc.writerom(0x0000, 0x0A);
+        mbc.writerom(0x4000, 0x40);
+        mbc.set_tilt(0.5, -1.0);
+        assert_eq!(mbc.readram(0xA030), 0x80);
+
+        // Erasing with 0x55 and latching with 0xAA
+        mbc.writeram(0xA000, 0x55);
+        mbc.writeram(0xA010, 0xAA);
+        let read = |mbc: &mut MBC7, a: u16| {
+            mbc.readram(a) as u16 | (mbc.readram(a + 0x10) as u16) << 8
+        };
+        assert_eq!(read(&mut mbc, 0xA020), 0x81D0 - 0x38);
+        assert_eq!(read(&mut mbc, 0xA040), 0x81D0 - 0x70);
+
+        // A new value is only latched after another erase
+        mbc.set_tilt(0.0, 0.0);
+        mbc.writeram(0xA010, 0xAA);
+        assert_eq!(read(&mut mbc, 0xA020), 0x81D0 - 0x38);
+        mbc.writeram(0xA000, 0x55);
+        mbc.writeram(0xA010, 0xAA);
+        assert_eq!(read(&mut mbc, 0xA020), 0x81D0);
+    }
+}
@@ -1,18 +1,54 @@
+use crate::state::{StateReader, StateWriter};
 use crate::StrResult;
 use std::fs::File;
 use std::io::prelude::*;
 use std::path;
 
+mod camera;
+mod huc1;
+mod huc3;
 mod mbc0;
 mod mbc1;
+mod mbc2;
 mod mbc3;
 mod mbc5;
+mod mbc7;
+
+pub type RumbleCallback = Box<dyn FnMut(bool) + Send>;
+// Called with the state of the cartridge IR LED, returns whether light is received
+pub type InfraredCallback = Box<dyn FnMut(bool) -> bool + Send>;
+// Fills a 128x112 grayscale image for the camera sensor, 0 is black and 255 is white
+pub type CameraCallback = Box<dyn FnMut(&mut [u8]) + Send>;
+
+pub use self::camera::load_pgm;
+#[cfg(test)]
+pub use self::camera::test as camera_test;
 
 pub trait MBC: Send {
     fn readrom(&self, a: u16) -> u8;
-    fn readram(&self, a: u16) -> u8;
+    fn readram(&mut self, a: u16) -> u8;
     fn writerom(&mut self, a: u16, v: u8);
     fn writeram(&mut self, a: u16, v: u8);
+    fn rombank(&self) -> usize;
+    fn save_state(&self, w: &mut StateWriter);
+    fn load_state(&mut self, r: &mut StateReader) -> StrResult<()>;
+
+    // Reads RAM for the debugger, without side effects such as polling the IR sensor
+    fn peekram(&mut self, a: u16) -> u8 {
+        self.readram(a)
+    }
+
+    fn set_rumble_callback(&mut self, _callback: RumbleCallback) {}
+
+    fn set_infrared_callback(&mut self, _callback: InfraredCallback) {}
+
+    // Tilt for cartridges with an accelerometer, in g for each axis
+    fn set_tilt(&mut self, _x: f32, _y: f32) {}
+
+    fn set_camera_callback(&mut self, _callback: CameraCallback) {}
+
+    // Advances hardware on the cartridge, in ticks of the 4MHz clock
+    fn do_cycle(&mut self, _ticks: u32) {}
 
     fn romname(&self) -> String {
         const TITLE_START: u16 = 0x134;
@@ -50,8 +86,13 @@ pub fn get_mbc(file: path::PathBuf, skip_checksum: bool) -> StrResult<Box<dyn MB
     match data[0x147] {
         0x00 => mbc0::MBC0::new(data).map(|v| Box::new(v) as Box<dyn MBC>),
         0x01..=0x03 => mbc1::MBC1::new(data, file).map(|v| Box::new(v) as Box<dyn MBC>),
+        0x05..=0x06 => mbc2::MBC2::new(data, file).map(|v| Box::new(v) as Box<dyn MBC>),
         0x0F..=0x13 => mbc3::MBC3::new(data, file).map(|v| Box::new(v) as Box<dyn MBC>),
         0x19..=0x1E => mbc5::MBC5::new(data, file).map(|v| Box::new(v) as Box<dyn MBC>),
+        0x22 => mbc7::MBC7::new(data, file).map(|v| Box::new(v) as Box<dyn MBC>),
+        0xFC => camera::Camera::new(data, file).map(|v| Box::new(v) as Box<dyn MBC>),
+        0xFE => huc3::HuC3::new(data, file).map(|v| Box::new(v) as Box<dyn MBC>),
+        0xFF => huc1::HuC1::new(data, file).map(|v| Box::new(v) as Box<dyn MBC>),
         _ => Err("Unsupported MBC type"),
     }
 }
@@ -1,9 +1,13 @@
-use crate::gbmode::{GbMode, GbSpeed};
+use crate::debug::{BreakReason, WatchType};
+use crate::gbmode::{GbMode, GbSpeed, Model};
 use crate::gpu::GPU;
-use crate::keypad::Keypad;
+use crate::keypad::{Keypad, KeypadKey};
 use crate::mbc;
+use crate::palette::{self, CompatPalette};
 use crate::serial::{Serial, SerialCallback};
+use crate::sgb::Sgb;
 use crate::sound::Sound;
+use crate::state::{StateReader, StateWriter};
 use crate::timer::Timer;
 use crate::StrResult;
 use std::path;
@@ -29,20 +33,44 @@ pub struct MMU<'a> {
     pub keypad: Keypad,
     pub gpu: GPU,
     pub sound: Option<Sound>,
+    pub sgb: Option<Sgb>,
     hdma_status: DMAType,
     hdma_src: u16,
     hdma_dst: u16,
     hdma_len: u8,
+    // OAM DMA copies a byte every machine cycle, and a new transfer replaces
+    // the running one after its start delay
+    oamdma_src: u16,
+    oamdma_pos: u16,
+    oamdma_value: u8,
+    oamdma_start: u16,
+    oamdma_delay: u8,
+    oamdma_ticks: u32,
     wrambank: usize,
+    // The keys held at power on choose a compatibility palette
+    compat_select: bool,
+    // The boot ROM covers the start of the cartridge until 0xFF50 is written
+    boot_rom: Vec<u8>,
+    boot_rom_mapped: bool,
     pub mbc: Box<dyn mbc::MBC + 'static>,
+    pub model: Model,
     pub gbmode: GbMode,
     gbspeed: GbSpeed,
     speed_switch_req: bool,
+    watchpoints: Vec<(u16, WatchType)>,
+    pub watch_hit: Option<BreakReason>,
+    // Machine cycles of the current instruction before its next bus access,
+    // and the PPU ticks already run for them. Accesses made outside of an
+    // instruction, like the power on setup, are not counted.
+    in_instruction: bool,
+    bus_cycles: u32,
+    bus_gputicks: u32,
 }
 
 impl<'a> MMU<'a> {
-    pub fn new(
+    pub fn new_model(
         romname: &str,
+        model: Model,
         serial_callback: Option<SerialCallback<'a>>,
         skip_checksum: bool,
     ) -> StrResult<MMU<'a>> {
@@ -54,8 +82,11 @@ impl<'a> MMU<'a> {
         let mut res = MMU {
             wram: [0; WRAM_SIZE],
             zram: [0; ZRAM_SIZE],
-            hdma: [0; 4],
             wrambank: 1,
+            compat_select: false,
+            boot_rom: Vec::new(),
+            boot_rom_mapped: false,
+            hdma: [0; 4],
             inte: 0,
             intf: 0,
             serial: serial,
@@ -63,7 +94,9 @@ impl<'a> MMU<'a> {
             keypad: Keypad::new(),
             gpu: GPU::new(),
             sound: None,
+            sgb: None,
             mbc: mmu_mbc,
+            model,
             gbmode: GbMode::Classic,
             gbspeed: GbSpeed::Single,
             speed_switch_req: false,
@@ -71,46 +104,33 @@ impl<'a> MMU<'a> {
             hdma_dst: 0,
             hdma_status: DMAType::NoDMA,
             hdma_len: 0xFF,
+            oamdma_src: 0,
+            oamdma_pos: 0xA0,
+            oamdma_value: 0xFF,
+            oamdma_start: 0,
+            oamdma_delay: 0,
+            oamdma_ticks: 0,
+            watchpoints: Vec::new(),
+            watch_hit: None,
+            in_instruction: false,
+            bus_cycles: 0,
+            bus_gputicks: 0,
         };
-        if res.rb(0x0143) == 0xC0 {
+        res.gpu.model = model;
+        if !model.is_cgb() && res.rb(0x0143) == 0xC0 {
             return Err("This game does not work in Classic mode");
         }
-        res.set_initial();
-        Ok(res)
-    }
-
-    pub fn new_cgb(
-        romname: &str,
-        serial_callback: Option<SerialCallback<'a>>,
-        skip_checksum: bool,
-    ) -> StrResult<MMU<'a>> {
-        let mmu_mbc = mbc::get_mbc(path::PathBuf::from(romname), skip_checksum)?;
-        let serial = match serial_callback {
-            Some(cb) => Serial::new_with_callback(cb),
-            None => Serial::new(),
-        };
-        let mut res = MMU {
-            wram: [0; WRAM_SIZE],
-            zram: [0; ZRAM_SIZE],
-            wrambank: 1,
-            hdma: [0; 4],
-            inte: 0,
-            intf: 0,
-            serial: serial,
-            timer: Timer::new(),
-            keypad: Keypad::new(),
-            gpu: GPU::new_cgb(),
-            sound: None,
-            mbc: mmu_mbc,
-            gbmode: GbMode::Color,
-            gbspeed: GbSpeed::Single,
-            speed_switch_req: false,
-            hdma_src: 0,
-            hdma_dst: 0,
-            hdma_status: DMAType::NoDMA,
-            hdma_len: 0xFF,
-        };
-        res.determine_mode();
+        if model.is_cgb() {
+            res.determine_mode();
+        }
+        if model.is_sgb() {
+            // The SGB only listens to cartridges with the SGB flag and the new licensee code
+            let supported = res.mbc.readrom(0x146) == 0x03 && res.mbc.readrom(0x14B) == 0x33;
+            if supported {
+                res.keypad.enable_sgb();
+            }
+            res.sgb = Some(Sgb::new(supported));
+        }
         res.set_initial();
         Ok(res)
     }
@@ -147,6 +167,17 @@ impl<'a> MMU<'a> {
         self.wb(0xFF49, 0xFF);
         self.wb(0xFF4A, 0);
         self.wb(0xFF4B, 0);
+
+        // The boot ROMs take a different time to run, which shows in the divider.
+        // The SGB boot ROM waits for the SNES, so there is no fixed value.
+        let div = match (self.model, self.gbmode) {
+            (Model::Dmg0, _) => 0x1830,
+            (Model::Dmg, _) | (Model::Mgb, _) => 0xABCC,
+            (Model::Sgb, _) | (Model::Sgb2, _) => 0x0000,
+            (_, GbMode::Color) => 0x1EA0,
+            _ => 0x267C,
+        };
+        self.timer.set_div(div);
     }
 
     fn determine_mode(&mut self) {
@@ -156,6 +187,195 @@ impl<'a> MMU<'a> {
         };
         self.gbmode = mode;
         self.gpu.gbmode = mode;
+
+        if mode == GbMode::ColorAsClassic {
+            let header: Vec<u8> = (0x134..0x150).map(|a| self.mbc.readrom(a)).collect();
+            self.gpu.set_compat_palette(palette::title_compat_colors(&header));
+            self.compat_select = true;
+        }
+    }
+
+    // Maps a DMG, MGB or CGB boot ROM and puts the hardware in its power on
+    // state, so the boot ROM can run from address 0
+    pub fn set_boot_rom(&mut self, data: Vec<u8>) -> StrResult<()> {
+        match (data.len(), self.gbmode) {
+            (0x100, GbMode::Classic) => {}
+            (0x900, GbMode::Color) | (0x900, GbMode::ColorAsClassic) => {}
+            (0x100, _) => return Err("A DMG boot ROM cannot run in CGB mode"),
+            (0x900, _) => return Err("A CGB boot ROM cannot run in classic mode"),
+            _ => return Err("Boot ROM must be 256 or 2304 bytes"),
+        }
+        if self.gbmode != GbMode::Classic {
+            // The boot ROM switches to DMG compatibility mode itself
+            self.gbmode = GbMode::Color;
+            self.gpu.gbmode = GbMode::Color;
+            self.compat_select = false;
+        }
+        self.boot_rom = data;
+        self.boot_rom_mapped = true;
+
+        // Undo the registers set_initial gave the state after the boot ROM
+        self.timer = Timer::new();
+        for a in 0xFF05..=0xFF07 {
+            self.wb(a, 0);
+        }
+        for a in 0xFF10..=0xFF25 {
+            self.wb(a, 0);
+        }
+        self.wb(0xFF26, 0);
+        for &a in [0xFF40, 0xFF42, 0xFF43, 0xFF45, 0xFF47, 0xFF48, 0xFF49, 0xFF4A, 0xFF4B].iter() {
+            self.wb(a, 0);
+        }
+        Ok(())
+    }
+
+    fn boot_rom_at(&self, address: u16) -> Option<u8> {
+        if !self.boot_rom_mapped {
+            return None;
+        }
+        match address {
+            0x0000..=0x00FF => Some(self.boot_rom[address as usize]),
+            0x0200..=0x08FF if self.boot_rom.len() > 0x100 => Some(self.boot_rom[address as usize]),
+            _ => None,
+        }
+    }
+
+    // Reads the keys once, before the first instruction. Keys pressed later
+    // are left to the game.
+    pub fn do_compat_select(&mut self) {
+        if !self.compat_select {
+            return;
+        }
+        self.compat_select = false;
+        let keypad = &self.keypad;
+        let selected = CompatPalette::from_keys(
+            keypad.is_pressed(KeypadKey::Up),
+            keypad.is_pressed(KeypadKey::Down),
+            keypad.is_pressed(KeypadKey::Left),
+            keypad.is_pressed(KeypadKey::Right),
+            keypad.is_pressed(KeypadKey::A),
+            keypad.is_pressed(KeypadKey::B),
+        );
+        if let Some(palette) = selected {
+            self.gpu.set_compat_palette(palette.colors());
+        }
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write_u8(self.model as u8);
+        w.write_u8(match self.gbmode {
+            GbMode::Classic => 0,
+            GbMode::Color => 1,
+            GbMode::ColorAsClassic => 2,
+        });
+        w.write_bytes(&self.wram);
+        w.write_bytes(&self.zram);
+        w.write_bytes(&self.hdma);
+        w.write_u8(self.inte);
+        w.write_u8(self.intf);
+        w.write_u8(match self.hdma_status {
+            DMAType::NoDMA => 0,
+            DMAType::GDMA => 1,
+            DMAType::HDMA => 2,
+        });
+        w.write_u16(self.hdma_src);
+        w.write_u16(self.hdma_dst);
+        w.write_u8(self.hdma_len);
+        w.write_u16(self.oamdma_src);
+        w.write_u16(self.oamdma_pos);
+        w.write_u8(self.oamdma_value);
+        w.write_u16(self.oamdma_start);
+        w.write_u8(self.oamdma_delay);
+        w.write_u32(self.oamdma_ticks);
+        w.write_u8(self.wrambank as u8);
+        w.write_bool(self.gbspeed == GbSpeed::Double);
+        w.write_bool(self.speed_switch_req);
+        w.write_bool(self.boot_rom_mapped);
+
+        self.serial.save_state(w);
+        self.timer.save_state(w);
+        self.keypad.save_state(w);
+        self.gpu.save_state(w);
+        if let Some(ref sgb) = self.sgb {
+            sgb.save_state(w);
+        }
+
+        // The sound state is optional, so it is stored as a separate block
+        let mut sound_state = StateWriter::new();
+        if let Some(ref sound) = self.sound {
+            sound.save_state(&mut sound_state);
+        }
+        w.write_vec(&sound_state.into_inner());
+
+        self.mbc.save_state(w);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> StrResult<()> {
+        if r.read_u8()? != self.model as u8 {
+            return Err("Save state was made for a different Gameboy model");
+        }
+        let mode = match r.read_u8()? {
+            0 => GbMode::Classic,
+            1 => GbMode::Color,
+            _ => GbMode::ColorAsClassic,
+        };
+        // A CGB boot ROM decides between the two CGB modes while it runs
+        let booting_cgb = !self.boot_rom.is_empty() && self.gbmode != GbMode::Classic;
+        if mode != self.gbmode && !(booting_cgb && mode != GbMode::Classic) {
+            return Err("Save state was made in a different Gameboy mode");
+        }
+        self.gbmode = mode;
+        self.gpu.gbmode = mode;
+        r.read_bytes(&mut self.wram)?;
+        r.read_bytes(&mut self.zram)?;
+        r.read_bytes(&mut self.hdma)?;
+        self.inte = r.read_u8()?;
+        self.intf = r.read_u8()?;
+        self.hdma_status = match r.read_u8()? {
+            1 => DMAType::GDMA,
+            2 => DMAType::HDMA,
+            _ => DMAType::NoDMA,
+        };
+        self.hdma_src = r.read_u16()?;
+        self.hdma_dst = r.read_u16()?;
+        self.hdma_len = r.read_u8()?;
+        self.oamdma_src = r.read_u16()?;
+        self.oamdma_pos = r.read_u16()?.min(0xA0);
+        self.oamdma_value = r.read_u8()?;
+        self.oamdma_start = r.read_u16()?;
+        self.oamdma_delay = r.read_u8()?;
+        self.oamdma_ticks = r.read_u32()?;
+        self.wrambank = match r.read_u8()? & 0x7 {
+            0 => 1,
+            n => n as usize,
+        };
+        self.gbspeed = if r.read_bool()? {
+            GbSpeed::Double
+        } else {
+            GbSpeed::Single
+        };
+        self.speed_switch_req = r.read_bool()?;
+        self.boot_rom_mapped = r.read_bool()?;
+        if self.boot_rom_mapped && self.boot_rom.is_empty() {
+            return Err("Save state was made while running the boot ROM");
+        }
+
+        self.serial.load_state(r)?;
+        self.timer.load_state(r)?;
+        self.keypad.load_state(r)?;
+        self.gpu.load_state(r)?;
+        if let Some(ref mut sgb) = self.sgb {
+            sgb.load_state(r)?;
+        }
+
+        let sound_state = r.read_vec()?;
+        if let Some(ref mut sound) = self.sound {
+            if !sound_state.is_empty() {
+                sound.load_state(&mut StateReader::new(&sound_state))?;
+            }
+        }
+
+        self.mbc.load_state(r)
     }
 
     pub fn do_cycle(&mut self, ticks: u32) -> u32 {
@@ -167,6 +387,8 @@ impl<'a> MMU<'a> {
         let gputicks = ticks / cpudivider + vramticks;
         let cputicks = ticks + vramticks * cpudivider;
 
+        self.do_oamdma(cputicks);
+
         self.timer.do_cycle(cputicks);
         self.intf |= self.timer.interrupt;
         self.timer.interrupt = 0;
@@ -174,19 +396,81 @@ impl<'a> MMU<'a> {
         self.intf |= self.keypad.interrupt;
         self.keypad.interrupt = 0;
 
-        self.gpu.do_cycle(gputicks);
+        self.gpu.do_cycle(gputicks.saturating_sub(self.bus_gputicks));
+        self.in_instruction = false;
+        self.bus_cycles = 0;
+        self.bus_gputicks = 0;
+        if self.gpu.interrupt & 0x01 != 0 {
+            if let Some(ref mut sgb) = self.sgb {
+                sgb.render(&self.gpu.shades);
+            }
+        }
         self.intf |= self.gpu.interrupt;
         self.gpu.interrupt = 0;
 
         self.sound.as_mut().map_or((), |s| s.do_cycle(gputicks));
 
+        self.mbc.do_cycle(gputicks);
+
         self.intf |= self.serial.interrupt;
         self.serial.interrupt = 0;
 
         return gputicks;
     }
 
+    // Called by the CPU before each instruction, do_cycle ends it
+    pub fn start_instruction(&mut self) {
+        self.in_instruction = true;
+    }
+
+    // The CPU runs a whole instruction before the rest of the machine catches up.
+    // The PPU is run up to every access so that it is checked against the
+    // current mode.
+    fn catch_up_gpu(&mut self) {
+        if !self.in_instruction {
+            return;
+        }
+        let gputicks = match self.gbspeed {
+            GbSpeed::Single => self.bus_cycles * 4,
+            GbSpeed::Double => self.bus_cycles * 2,
+        };
+        if gputicks > self.bus_gputicks {
+            self.gpu.do_cycle(gputicks - self.bus_gputicks);
+            self.bus_gputicks = gputicks;
+        }
+        self.bus_cycles += 1;
+    }
+
     pub fn rb(&mut self, address: u16) -> u8 {
+        self.catch_up_gpu();
+        let value = if self.oamdma_conflict(address) {
+            match address {
+                0xFE00..=0xFEFF => 0xFF,
+                _ => self.oamdma_value,
+            }
+        } else if self.gpu.is_blocked(address) {
+            0xFF
+        } else {
+            self.readbyte(address)
+        };
+        if !self.watchpoints.is_empty() {
+            self.check_watchpoint(address, value, false);
+        }
+        value
+    }
+
+    // Reads memory for the debugger, leaving the cartridge hardware alone
+    pub fn peekbyte(&mut self, address: u16) -> u8 {
+        match address {
+            0xA000..=0xBFFF => self.mbc.peekram(address),
+            _ => self.readbyte(address),
+        }
+    }
+
+    pub fn readbyte(&mut self, address: u16) -> u8 {
+        if let Some(value) = self.boot_rom_at(address) {
+            return value;
+        }
         match address {
             0x0000..=0x7FFF => self.mbc.readrom(address),
             0x8000..=0x9FFF => self.gpu.rb(address),
@@ -201,6 +485,8 @@ impl<'a> MMU<'a> {
             0xFF04..=0xFF07 => self.timer.rb(address),
             0xFF0F => self.intf,
             0xFF10..=0xFF3F => self.sound.as_mut().map_or(0, |s| s.rb(address)),
+            // Speed switching only exists in CGB mode
+            0xFF4D if self.gbmode != GbMode::Color => 0xFF,
             0xFF4D => {
                 (if self.gbspeed == GbSpeed::Double {
                     0x80
@@ -208,9 +494,10 @@ impl<'a> MMU<'a> {
                     0
                 }) | (if self.speed_switch_req { 1 } else { 0 })
             }
+            0xFF4C | 0xFF50 => 0xFF,
             0xFF40..=0xFF4F => self.gpu.rb(address),
             0xFF51..=0xFF55 => self.hdma_read(address),
-            0xFF68..=0xFF6B => self.gpu.rb(address),
+            0xFF68..=0xFF6C => self.gpu.rb(address),
             0xFF70 => self.wrambank as u8,
             0xFF80..=0xFFFE => self.zram[address as usize & 0x007F],
             0xFFFF => self.inte,
@@ -223,6 +510,16 @@ impl<'a> MMU<'a> {
     }
 
     pub fn wb(&mut self, address: u16, value: u8) {
+        self.catch_up_gpu();
+        if !self.watchpoints.is_empty() {
+            self.check_watchpoint(address, value, true);
+        }
+        if !self.oamdma_conflict(address) && !self.gpu.is_blocked(address) {
+            self.writebyte(address, value);
+        }
+    }
+
+    pub fn writebyte(&mut self, address: u16, value: u8) {
         match address {
             0x0000..=0x7FFF => self.mbc.writerom(address, value),
             0x8000..=0x9FFF => self.gpu.wb(address, value),
@@ -232,19 +529,35 @@ impl<'a> MMU<'a> {
                 self.wram[(self.wrambank * 0x1000) | (address as usize & 0x0FFF)] = value
             }
             0xFE00..=0xFE9F => self.gpu.wb(address, value),
-            0xFF00 => self.keypad.wb(value),
+            0xFF00 => {
+                self.keypad.wb(value);
+                if let Some(ref mut sgb) = self.sgb {
+                    if let Some(packet) = self.keypad.take_sgb_packet() {
+                        sgb.receive(&packet);
+                        self.keypad.set_players(sgb.players());
+                    }
+                }
+            }
             0xFF01..=0xFF02 => self.serial.wb(address, value),
             0xFF04..=0xFF07 => self.timer.wb(address, value),
             0xFF10..=0xFF3F => self.sound.as_mut().map_or((), |s| s.wb(address, value)),
             0xFF46 => self.oamdma(value),
+            0xFF4D if self.gbmode != GbMode::Color => {}
             0xFF4D => {
                 if value & 0x1 == 0x1 {
                     self.speed_switch_req = true;
                 }
             }
+            // KEY0, the CGB boot ROM selects DMG compatibility mode for old cartridges
+            0xFF4C if self.boot_rom_mapped && self.gbmode == GbMode::Color && value & 0x04 != 0 => {
+                self.gbmode = GbMode::ColorAsClassic;
+                self.gpu.gbmode = GbMode::ColorAsClassic;
+            }
+            0xFF4C => {}
             0xFF40..=0xFF4F => self.gpu.wb(address, value),
+            0xFF50 if value != 0 => self.boot_rom_mapped = false,
             0xFF51..=0xFF55 => self.hdma_write(address, value),
-            0xFF68..=0xFF6B => self.gpu.wb(address, value),
+            0xFF68..=0xFF6C => self.gpu.wb(address, value),
             0xFF0F => self.intf = value,
             0xFF70 => {
                 self.wrambank = match value & 0x7 {
@@ -263,6 +576,38 @@ impl<'a> MMU<'a> {
         self.wb(address + 1, (value >> 8) as u8);
     }
 
+    pub fn add_watchpoint(&mut self, address: u16, watchtype: WatchType) {
+        self.remove_watchpoint(address);
+        self.watchpoints.push((address, watchtype));
+    }
+
+    pub fn remove_watchpoint(&mut self, address: u16) -> bool {
+        let len = self.watchpoints.len();
+        self.watchpoints.retain(|&(a, _)| a != address);
+        self.watchpoints.len() != len
+    }
+
+    pub fn watchpoints(&self) -> &[(u16, WatchType)] {
+        &self.watchpoints
+    }
+
+    fn check_watchpoint(&mut self, address: u16, value: u8, write: bool) {
+        if self.watch_hit.is_some() {
+            return;
+        }
+        let hit = self
+            .watchpoints
+            .iter()
+            .any(|&(a, t)| a == address && t.matches(write));
+        if hit {
+            self.watch_hit = Some(BreakReason::Watchpoint {
+                address,
+                value,
+                write,
+            });
+        }
+    }
+
     pub fn switch_speed(&mut self) {
         if self.speed_switch_req {
             if self.gbspeed == GbSpeed::Double {
@@ -275,10 +620,64 @@ impl<'a> MMU<'a> {
     }
 
     fn oamdma(&mut self, value: u8) {
-        let base = (value as u16) << 8;
-        for i in 0..0xA0 {
-            let b = self.rb(base + i);
-            self.wb(0xFE00 + i, b);
+        self.oamdma_start = (value as u16) << 8;
+        self.oamdma_delay = 2;
+    }
+
+    fn oamdma_active(&self) -> bool {
+        self.oamdma_pos < 0xA0
+    }
+
+    fn do_oamdma(&mut self, ticks: u32) {
+        if !self.oamdma_active() && self.oamdma_delay == 0 {
+            return;
+        }
+        self.oamdma_ticks += ticks;
+        while self.oamdma_ticks >= 4 {
+            self.oamdma_ticks -= 4;
+            if self.oamdma_active() {
+                // Sources above 0xDFFF read from the echo of work RAM
+                let source = match self.oamdma_src + self.oamdma_pos {
+                    a @ 0xE000..=0xFFFF => a - 0x2000,
+                    a => a,
+                };
+                let b = self.readbyte(source);
+                self.gpu.wb(0xFE00 + self.oamdma_pos, b);
+                self.oamdma_value = b;
+                self.oamdma_pos += 1;
+            }
+            if self.oamdma_delay > 0 {
+                self.oamdma_delay -= 1;
+                if self.oamdma_delay == 0 {
+                    self.oamdma_src = self.oamdma_start;
+                    self.oamdma_pos = 0;
+                }
+            }
+        }
+        if !self.oamdma_active() && self.oamdma_delay == 0 {
+            self.oamdma_ticks = 0;
+        }
+    }
+
+    // While OAM DMA runs the CPU can only use HRAM and IO, and the bus that
+    // DMA reads from returns the byte being copied
+    fn oamdma_conflict(&self, address: u16) -> bool {
+        if !self.oamdma_active() {
+            return false;
+        }
+        match address {
+            0xFE00..=0xFEFF => true,
+            0xFF00..=0xFFFF => false,
+            _ => self.bus(address) == self.bus(self.oamdma_src),
+        }
+    }
+
+    fn bus(&self, address: u16) -> u8 {
+        match address {
+            0x8000..=0x9FFF => 1,
+            // Work RAM has a bus of its own on the CGB
+            0xC000..=0xFDFF if self.model.is_cgb() => 2,
+            _ => 0,
         }
     }
 
@@ -364,7 +763,7 @@ impl<'a> MMU<'a> {
     fn perform_vramdma_row(&mut self) {
         let mmu_src = self.hdma_src;
         for j in 0..0x10 {
-            let b: u8 = self.rb(mmu_src + j);
+            let b: u8 = self.readbyte(mmu_src + j);
             self.gpu.wb(self.hdma_dst + j, b);
         }
         self.hdma_src += 0x10;
@@ -377,3 +776,139 @@ impl<'a> MMU<'a> {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::MMU;
+    use crate::gbmode::{GbMode, Model};
+    use crate::keypad::KeypadKey;
+    use crate::palette::CompatPalette;
+    use crate::StrResult;
+
+    // Loads a ROM of zeros with some bytes set, through a temporary file
+    fn load_rom(name: &str, model: Model, bytes: &[(usize, u8)]) -> StrResult<MMU<'static>> {
+        let path = std::env::temp_dir().join(name);
+        let mut rom = vec![0; 0x8000];
+        for &(address, value) in bytes {
+            rom[address] = value;
+        }
+        std::fs::write(&path, rom).unwrap();
+        let mmu = MMU::new_model(path.to_str().unwrap(), model, None, true);
+        let _ = std::fs::remove_file(&path);
+        mmu
+    }
+
+    #[test]
+    fn oam_dma() {
+        let mut mmu = load_rom("rboy_oam_dma.gb", Model::Dmg, &[]).unwrap();
+        mmu.wb(0xFF40, 0x00);
+        for i in 0..0xA0 {
+            mmu.wb(0xC000 + i, i as u8);
+        }
+        mmu.zram[0] = 0x42;
+
+        mmu.wb(0xFF46, 0xC0);
+        mmu.do_cycle(4);
+        assert_eq!(mmu.rb(0xFE00), 0x00);
+        mmu.do_cycle(4 * 11);
+
+        // OAM reads 0xFF, the source bus returns the byte being copied
+        assert_eq!(mmu.rb(0xFE00), 0xFF);
+        assert_eq!(mmu.rb(0x0100), 0x09);
+        assert_eq!(mmu.rb(0x8000), 0x00);
+        assert_eq!(mmu.rb(0xFF80), 0x42);
+        mmu.wb(0xC000, 0x55);
+        assert_eq!(mmu.readbyte(0xC000), 0x00);
+
+        mmu.do_cycle(4 * 150);
+        assert_eq!(mmu.rb(0xFE9F), 0x9F);
+        assert_eq!(mmu.rb(0xFE10), 0x10);
+    }
+
+    #[test]
+    fn compat_select_at_power_on() {
+        let mut mmu = load_rom("rboy_compat_select.gb", Model::Cgb, &[]).unwrap();
+        let background = |mmu: &mut MMU| -> Vec<u16> {
+            (0..4u8)
+                .map(|i| {
+                    mmu.gpu.wb(0xFF68, i * 2);
+                    let lo = mmu.gpu.rb(0xFF69) as u16;
+                    mmu.gpu.wb(0xFF68, i * 2 + 1);
+                    lo | (mmu.gpu.rb(0xFF69) as u16) << 8
+                })
+                .collect()
+        };
+
+        mmu.keypad.keydown(KeypadKey::Left);
+        mmu.keypad.keydown(KeypadKey::A);
+        mmu.do_compat_select();
+        assert_eq!(background(&mut mmu), CompatPalette::DarkBlue.colors()[0]);
+
+        // A direction held during the game does not change the colors
+        mmu.keypad.keyup(KeypadKey::Left);
+        mmu.keypad.keydown(KeypadKey::Right);
+        mmu.do_compat_select();
+        mmu.do_cycle(4194304);
+        assert_eq!(background(&mut mmu), CompatPalette::DarkBlue.colors()[0]);
+    }
+
+    #[test]
+    fn access_blocking_within_instruction() {
+        let mut mmu = load_rom("rboy_access_blocking.gb", Model::Dmg, &[]).unwrap();
+        // Accesses between instructions leave the PPU where it is
+        let stat = mmu.gpu.rb(0xFF41);
+        for _ in 0..50 {
+            mmu.rb(0x0000);
+        }
+        assert_eq!(mmu.gpu.rb(0xFF41), stat);
+
+        mmu.wb(0xFF40, 0x00);
+        mmu.wb(0x8000, 0x12);
+        mmu.wb(0xFF40, 0x91);
+        mmu.do_cycle(456 * 4);
+
+        // Every access takes a machine cycle, so a long run of them without
+        // the rest of the machine catching up still reaches mode 3
+        mmu.start_instruction();
+        let reads: Vec<u8> = (0..114).map(|_| mmu.rb(0x8000)).collect();
+        assert_eq!(reads[0], 0x12);
+        assert!(reads.contains(&0xFF));
+        assert_eq!(reads[113], 0x12);
+    }
+
+    #[test]
+    fn boot_rom() {
+        let rom = [(0x0000, 0x11), (0x0300, 0x33)];
+        let mut mmu = load_rom("rboy_boot_rom.gb", Model::Dmg, &rom).unwrap();
+        assert!(mmu.set_boot_rom(vec![0; 0x900]).is_err());
+        mmu.set_boot_rom(vec![0xBB; 0x100]).unwrap();
+        assert_eq!(mmu.rb(0x0000), 0xBB);
+        // The boot ROM starts from power on, not from the state it leaves behind
+        assert_eq!(mmu.rb(0xFF04), 0x00);
+        assert_eq!(mmu.timer.rb(0xFF07), 0x00);
+        assert_eq!(mmu.rb(0xFF40), 0x00);
+        assert_eq!(mmu.rb(0xFF47), 0x00);
+        assert_eq!(mmu.rb(0x0100), 0x00);
+        mmu.wb(0xFF50, 0x01);
+        assert_eq!(mmu.rb(0x0000), 0x11);
+
+        // The CGB boot ROM also covers 0x200 to 0x8FF
+        let mut mmu = load_rom("rboy_boot_rom.gb", Model::Cgb, &rom).unwrap();
+        mmu.set_boot_rom(vec![0xCC; 0x900]).unwrap();
+        assert_eq!(mmu.rb(0x0300), 0xCC);
+        mmu.wb(0xFF4C, 0x04);
+        mmu.wb(0xFF50, 0x11);
+        assert_eq!(mmu.rb(0x0300), 0x33);
+        assert!(mmu.gbmode == GbMode::ColorAsClassic);
+    }
+
+    #[test]
+    fn cgb_only_cartridge() {
+        let rom = [(0x0143, 0xC0)];
+        for &model in [Model::Dmg0, Model::Dmg, Model::Mgb, Model::Sgb, Model::Sgb2].iter() {
+            assert!(load_rom("rboy_cgb_only.gb", model, &rom).is_err());
+        }
+        let mmu = load_rom("rboy_cgb_only.gb", Model::Cgb, &rom).unwrap();
+        assert!(mmu.gbmode == GbMode::Color);
+    }
+}
@@ -0,0 +1,467 @@
+use crate::StrResult;
+
+use std::path::Path;
+
+// RGB colors for the four shades of a monochrome palette, lightest first
+pub type Shades = [[u8; 3]; 4];
+
+// The colors shown for the BG, OBJ0 and OBJ1 palettes in classic mode
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct DmgPalette {
+    pub bg: Shades,
+    pub obj0: Shades,
+    pub obj1: Shades,
+}
+
+const GREY: Shades = [[255, 255, 255], [192, 192, 192], [96, 96, 96], [0, 0, 0]];
+const DMG: Shades = [[0x9B, 0xBC, 0x0F], [0x8B, 0xAC, 0x0F], [0x30, 0x62, 0x30], [0x0F, 0x38, 0x0F]];
+const POCKET: Shades = [[0xC4, 0xCF, 0xA1], [0x8B, 0x95, 0x6D], [0x4D, 0x53, 0x3C], [0x1F, 0x1F, 0x1F]];
+const LIGHT: Shades = [[0x00, 0xB5, 0x81], [0x00, 0x9A, 0x71], [0x00, 0x69, 0x4A], [0x00, 0x4F, 0x3B]];
+
+impl DmgPalette {
+    pub fn uniform(shades: Shades) -> DmgPalette {
+        DmgPalette {
+            bg: shades,
+            obj0: shades,
+            obj1: shades,
+        }
+    }
+
+    pub fn preset(name: &str) -> Option<DmgPalette> {
+        let shades = match name {
+            "grey" => GREY,
+            "dmg" => DMG,
+            "pocket" => POCKET,
+            "light" => LIGHT,
+            _ => return None,
+        };
+        Some(DmgPalette::uniform(shades))
+    }
+
+    pub fn load(path: &Path) -> StrResult<DmgPalette> {
+        let text = std::fs::read_to_string(path).map_err(|_| "Could not read palette file")?;
+        DmgPalette::parse(&text)
+    }
+
+    // Parses hexadecimal RRGGBB colors, optionally prefixed with '#', separated by
+    // whitespace or commas. Anything after a ';' is a comment. Four colors are used
+    // for all palettes, twelve colors give the BG, OBJ0 and OBJ1 palettes in order.
+    pub fn parse(text: &str) -> StrResult<DmgPalette> {
+        let mut colors = Vec::new();
+        for line in text.lines() {
+            let line = line.split(';').next().unwrap_or("");
+            for token in line.split(|c: char| c.is_whitespace() || c == ',') {
+                if token.is_empty() {
+                    continue;
+                }
+                colors.push(parse_color(token)?);
+            }
+        }
+
+        let shades = |i: usize| [colors[i], colors[i + 1], colors[i + 2], colors[i + 3]];
+        match colors.len() {
+            4 => Ok(DmgPalette::uniform(shades(0))),
+            12 => Ok(DmgPalette {
+                bg: shades(0),
+                obj0: shades(4),
+                obj1: shades(8),
+            }),
+            _ => Err("Palette must have 4 or 12 colors"),
+        }
+    }
+}
+
+// How CGB colors are converted from RGB555 to the RGB888 output
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub enum ColorCorrection {
+    // Scales the channels without correction
+    Raw,
+    // The matrix from the Gambatte emulator
+    #[default]
+    Gambatte,
+    // Models the colors and gamma of the CGB LCD
+    Accurate,
+    // Models the darker screen of the GBA and GBA SP (AGS-001)
+    Agb,
+}
+
+impl ColorCorrection {
+    pub fn from_name(name: &str) -> Option<ColorCorrection> {
+        match name {
+            "raw" => Some(ColorCorrection::Raw),
+            "gambatte" => Some(ColorCorrection::Gambatte),
+            "accurate" => Some(ColorCorrection::Accurate),
+            "agb" => Some(ColorCorrection::Agb),
+            _ => None,
+        }
+    }
+
+    // Computes the output color of every RGB555 value, indexed by r | g << 5 | b << 10
+    pub fn lut(self) -> Vec<[u8; 3]> {
+        (0..0x8000u32)
+            .map(|c| {
+                let (r, g, b) = (c & 0x1F, (c >> 5) & 0x1F, (c >> 10) & 0x1F);
+                match self {
+                    ColorCorrection::Raw => [scale5(r), scale5(g), scale5(b)],
+                    ColorCorrection::Gambatte => [
+                        ((r * 13 + g * 2 + b) >> 1) as u8,
+                        ((g * 3 + b) << 1) as u8,
+                        ((r * 3 + g * 2 + b * 11) >> 1) as u8,
+                    ],
+                    // Both curves are from the LCD shaders by Pokefan531
+                    ColorCorrection::Accurate => lcd_curve(
+                        [r, g, b],
+                        2.2,
+                        0.94,
+                        [[0.82, 0.125, 0.195], [0.24, 0.665, 0.075], [-0.06, 0.21, 0.73]],
+                    ),
+                    ColorCorrection::Agb => lcd_curve(
+                        [r, g, b],
+                        2.7,
+                        0.93,
+                        [[0.80, 0.135, 0.195], [0.275, 0.64, 0.155], [-0.075, 0.225, 0.65]],
+                    ),
+                }
+            })
+            .collect()
+    }
+}
+
+fn scale5(v: u32) -> u8 {
+    ((v << 3) | (v >> 2)) as u8
+}
+
+// Converts an RGB555 color without correction
+pub fn rgb555(color: u16) -> [u8; 3] {
+    let c = color as u32;
+    [scale5(c & 0x1F), scale5((c >> 5) & 0x1F), scale5((c >> 10) & 0x1F)]
+}
+
+// Linearizes the color with the gamma of the screen, mixes the channels and
+// encodes the result for a display with a gamma of 2.2
+fn lcd_curve(color: [u32; 3], gamma: f32, luminance: f32, matrix: [[f32; 3]; 3]) -> [u8; 3] {
+    let linear: Vec<f32> = color
+        .iter()
+        .map(|&v| (v as f32 / 31.0).powf(gamma) * luminance)
+        .collect();
+    let mut result = [0; 3];
+    for (out, row) in result.iter_mut().zip(matrix.iter()) {
+        let mixed = row[0] * linear[0] + row[1] * linear[1] + row[2] * linear[2];
+        *out = (mixed.clamp(0.0, 1.0).powf(1.0 / 2.2) * 255.0).round() as u8;
+    }
+    result
+}
+
+// RGB555 colors for the BG, OBJ0 and OBJ1 palettes of a monochrome game on the CGB
+pub type CompatColors = [[u16; 4]; 3];
+
+// The palettes the CGB boot ROM offers when a direction is held, together with
+// A or B, while the logo is shown
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum CompatPalette {
+    Green,     // Right
+    Blue,      // Left
+    Brown,     // Up
+    Pastel,    // Down
+    DarkGreen, // Right + A
+    DarkBlue,  // Left + A
+    Red,       // Up + A
+    Orange,    // Down + A
+    Inverted,  // Right + B
+    Grayscale, // Left + B
+    DarkBrown, // Up + B
+    Yellow,    // Down + B
+}
+
+// The palette memory of the CGB boot ROM
+const COMPAT_COLORS: [u16; 120] = [
+    0x7FFF, 0x32BF, 0x00D0, 0x0000, 0x639F, 0x4279, 0x15B0, 0x04CB, 0x7FFF, 0x6E31, 0x454A, 0x0000,
+    0x7FFF, 0x1BEF, 0x0200, 0x0000, 0x7FFF, 0x421F, 0x1CF2, 0x0000, 0x7FFF, 0x5294, 0x294A, 0x0000,
+    0x7FFF, 0x03FF, 0x012F, 0x0000, 0x7FFF, 0x03EF, 0x01D6, 0x0000, 0x7FFF, 0x42B5, 0x3DC8, 0x0000,
+    0x7E74, 0x03FF, 0x0180, 0x0000, 0x67FF, 0x77AC, 0x1A13, 0x2D6B, 0x7ED6, 0x4BFF, 0x2175, 0x0000,
+    0x53FF, 0x4A5F, 0x7E52, 0x0000, 0x4FFF, 0x7ED2, 0x3A4C, 0x1CE0, 0x03ED, 0x7FFF, 0x255F, 0x0000,
+    0x036A, 0x021F, 0x03FF, 0x7FFF, 0x7FFF, 0x01DF, 0x0112, 0x0000, 0x231F, 0x035F, 0x00F2, 0x0009,
+    0x7FFF, 0x03EA, 0x011F, 0x0000, 0x299F, 0x001A, 0x000C, 0x0000, 0x7FFF, 0x027F, 0x001F, 0x0000,
+    0x7FFF, 0x03E0, 0x0206, 0x0120, 0x7FFF, 0x7EEB, 0x001F, 0x7C00, 0x7FFF, 0x3FFF, 0x7E00, 0x001F,
+    0x7FFF, 0x03FF, 0x001F, 0x0000, 0x03FF, 0x001F, 0x000C, 0x0000, 0x7FFF, 0x033F, 0x0193, 0x0000,
+    0x0000, 0x4200, 0x037F, 0x7FFF, 0x7FFF, 0x7E8C, 0x7C00, 0x0000, 0x7FFF, 0x1BEF, 0x6180, 0x0000,
+];
+
+// Offsets into the palette memory of the OBJ0, OBJ1 and BG palettes. Most
+// start on a palette boundary, but some games use colors from two palettes.
+const COMPAT_COMBINATIONS: [[usize; 3]; 51] = [
+    [16, 16, 116],
+    [72, 72, 72],
+    [80, 80, 80],
+    [96, 96, 96],
+    [36, 36, 36],
+    [0, 0, 0],
+    [108, 108, 108],
+    [20, 20, 20],
+    [48, 48, 48],
+    [104, 104, 104],
+    [64, 32, 32],
+    [16, 112, 112],
+    [16, 8, 8],
+    [12, 16, 16],
+    [16, 116, 116],
+    [112, 16, 112],
+    [8, 68, 8],
+    [64, 64, 32],
+    [16, 16, 28],
+    [16, 16, 72],
+    [16, 16, 80],
+    [76, 76, 36],
+    [15, 15, 44],
+    [68, 68, 8],
+    [16, 16, 8],
+    [16, 16, 12],
+    [112, 112, 0],
+    [12, 12, 0],
+    [0, 0, 4],
+    [72, 72, 20],
+    [80, 88, 80],
+    [96, 88, 96],
+    [72, 88, 72],
+    [68, 16, 52],
+    [111, 0, 56],
+    [111, 16, 60],
+    [76, 91, 36],
+    [64, 112, 40],
+    [16, 112, 92],
+    [68, 88, 8],
+    [16, 0, 8],
+    [16, 12, 108],
+    [112, 16, 12],
+    [12, 112, 16],
+    [16, 112, 84],
+    [12, 112, 0],
+    [100, 12, 112],
+    [0, 112, 32],
+    [16, 16, 112],
+    [112, 12, 24],
+    [16, 112, 116],
+];
+
+// Title checksums of Nintendo games with their palette combination
+const COMPAT_TITLES: [(u8, usize); 63] = [
+    (0x88, 4), (0x16, 5), (0x36, 35), (0xD1, 34), (0xDB, 3), (0xF2, 31), (0x3C, 15),
+    (0x8C, 10), (0x92, 5), (0x3D, 19), (0x5C, 36), (0x58, 7), (0xC9, 37), (0x3E, 30),
+    (0x70, 44), (0x1D, 21), (0x59, 32), (0x69, 31), (0x19, 20), (0x35, 5), (0xA8, 33),
+    (0x14, 13), (0xAA, 14), (0x75, 5), (0x95, 29), (0x99, 5), (0x34, 18), (0x6F, 9),
+    (0x15, 3), (0xFF, 2), (0x97, 26), (0x4B, 25), (0x90, 25), (0x17, 41), (0x10, 42),
+    (0x39, 26), (0xF7, 45), (0xF6, 42), (0xA2, 45), (0x49, 36), (0x4E, 38), (0x43, 26),
+    (0x68, 42), (0xE0, 30), (0x8B, 41), (0xF0, 34), (0xCE, 34), (0x0C, 5), (0x29, 42),
+    (0xE8, 6), (0xB7, 5), (0x86, 33), (0x9A, 25), (0x52, 42), (0x01, 42), (0x9D, 40),
+    (0x71, 2), (0x9C, 16), (0xBD, 25), (0x5D, 42), (0x6D, 42), (0x67, 5), (0x3F, 0),
+];
+
+// Titles sharing a checksum are told apart by their fourth letter
+const COMPAT_TITLES_BY_LETTER: [(u8, u8, usize); 29] = [
+    (0xB3, b'B', 36), (0x46, b'E', 22), (0x28, b'F', 25), (0xA5, b'A', 6), (0xC6, b'A', 32),
+    (0xD3, b'R', 12), (0x27, b'B', 36), (0x61, b'E', 11), (0x18, b'K', 39), (0x66, b'E', 18),
+    (0x6A, b'K', 39), (0xBF, b' ', 24), (0x0D, b'R', 31), (0xF4, b'-', 50), (0xB3, b'U', 17),
+    (0x46, b'R', 46), (0x28, b'A', 6), (0xA5, b'R', 27), (0xC6, b' ', 0), (0xD3, b'I', 47),
+    (0x27, b'N', 41), (0x61, b'A', 41), (0x18, b'I', 0), (0x66, b'L', 0), (0x6A, b'I', 19),
+    (0xBF, b'C', 34), (0x0D, b'E', 23), (0xF4, b' ', 18), (0xB3, b'R', 29),
+];
+
+impl CompatPalette {
+    pub fn from_name(name: &str) -> Option<CompatPalette> {
+        let palette = match name {
+            "green" => CompatPalette::Green,
+            "blue" => CompatPalette::Blue,
+            "brown" => CompatPalette::Brown,
+            "pastel" => CompatPalette::Pastel,
+            "dark-green" => CompatPalette::DarkGreen,
+            "dark-blue" => CompatPalette::DarkBlue,
+            "red" => CompatPalette::Red,
+            "orange" => CompatPalette::Orange,
+            "inverted" => CompatPalette::Inverted,
+            "grayscale" => CompatPalette::Grayscale,
+            "dark-brown" => CompatPalette::DarkBrown,
+            "yellow" => CompatPalette::Yellow,
+            _ => return None,
+        };
+        Some(palette)
+    }
+
+    // The palette selected by holding a direction, optionally with A or B
+    pub fn from_keys(
+        up: bool,
+        down: bool,
+        left: bool,
+        right: bool,
+        a: bool,
+        b: bool,
+    ) -> Option<CompatPalette> {
+        use self::CompatPalette::*;
+        let choices = if right {
+            [Green, DarkGreen, Inverted]
+        } else if left {
+            [Blue, DarkBlue, Grayscale]
+        } else if up {
+            [Brown, Red, DarkBrown]
+        } else if down {
+            [Pastel, Orange, Yellow]
+        } else {
+            return None;
+        };
+        Some(match (a, b) {
+            (true, _) => choices[1],
+            (false, true) => choices[2],
+            _ => choices[0],
+        })
+    }
+
+    pub fn colors(self) -> CompatColors {
+        let combination = match self {
+            CompatPalette::Green => 1,
+            CompatPalette::Blue => 48,
+            CompatPalette::Brown => 5,
+            CompatPalette::Pastel => 8,
+            CompatPalette::DarkGreen => 0,
+            CompatPalette::DarkBlue => 40,
+            CompatPalette::Red => 43,
+            CompatPalette::Orange => 3,
+            CompatPalette::Inverted => 6,
+            CompatPalette::Grayscale => 7,
+            CompatPalette::DarkBrown => 28,
+            CompatPalette::Yellow => 49,
+        };
+        combination_colors(combination)
+    }
+}
+
+fn combination_colors(index: usize) -> CompatColors {
+    let [obj0, obj1, bg] = COMPAT_COMBINATIONS[index];
+    let palette = |offset: usize| {
+        let mut colors = [0; 4];
+        colors.copy_from_slice(&COMPAT_COLORS[offset..offset + 4]);
+        colors
+    };
+    [palette(bg), palette(obj0), palette(obj1)]
+}
+
+// Chooses the palette like the CGB boot ROM does, from the cartridge header
+// bytes 0x134 to 0x14F. Only games from Nintendo are recognized.
+pub fn title_compat_colors(header: &[u8]) -> CompatColors {
+    let nintendo = header[0x17] == 0x01 || (header[0x17] == 0x33 && &header[0x10..0x12] == b"01");
+    let checksum = header[..0x10].iter().fold(0u8, |acc, &v| acc.wrapping_add(v));
+    let fourth_letter = header[3];
+
+    let combination = COMPAT_TITLES
+        .iter()
+        .find(|&&(sum, _)| sum == checksum)
+        .map(|&(_, combination)| combination)
+        .or_else(|| {
+            COMPAT_TITLES_BY_LETTER
+                .iter()
+                .find(|&&(sum, letter, _)| sum == checksum && letter == fourth_letter)
+                .map(|&(_, _, combination)| combination)
+        });
+    match combination {
+        Some(combination) if nintendo => combination_colors(combination),
+        _ => combination_colors(0),
+    }
+}
+
+impl Default for DmgPalette {
+    fn default() -> DmgPalette {
+        DmgPalette::uniform(GREY)
+    }
+}
+
+fn parse_color(token: &str) -> StrResult<[u8; 3]> {
+    let hex = token.trim_start_matches('#');
+    if hex.len() != 6 {
+        return Err("Palette colors must be 6 hexadecimal digits");
+    }
+    let value = u32::from_str_radix(hex, 16).map_err(|_| "Invalid color in palette")?;
+    Ok([(value >> 16) as u8, (value >> 8) as u8, value as u8])
+}
+
+#[cfg(test)]
+mod test {
+    use super::{title_compat_colors, ColorCorrection, CompatPalette, DmgPalette, GREY};
+
+    #[test]
+    fn parse_palette() {
+        let palette = DmgPalette::parse("; four shades\n#FFFFFF, aaaaaa\n555555 #000000 ; dark\n").unwrap();
+        assert_eq!(palette.bg, palette.obj1);
+        assert_eq!(palette.bg[1], [0xAA, 0xAA, 0xAA]);
+
+        let text = "FFFFFF C0C0C0 606060 000000\n".repeat(2) + "E0F8D0 88C070 346856 081820";
+        let palette = DmgPalette::parse(&text).unwrap();
+        assert_eq!(palette.obj0, GREY);
+        assert_eq!(palette.obj1[3], [0x08, 0x18, 0x20]);
+
+        assert!(DmgPalette::parse("FFFFFF 000000").is_err());
+        assert!(DmgPalette::parse("FFFFFF C0C0C0 606060 00000G").is_err());
+    }
+
+    #[test]
+    fn color_correction() {
+        let white = 0x7FFF;
+        let red = 0x001F;
+        assert_eq!(ColorCorrection::Raw.lut()[white], [255, 255, 255]);
+        assert_eq!(ColorCorrection::Raw.lut()[red], [255, 0, 0]);
+        assert_eq!(ColorCorrection::Gambatte.lut()[red], [201, 0, 46]);
+        for &mode in [ColorCorrection::Accurate, ColorCorrection::Agb].iter() {
+            let lut = mode.lut();
+            assert_eq!(lut.len(), 0x8000);
+            assert_eq!(lut[0], [0, 0, 0]);
+            assert!(lut[white].iter().all(|&v| v > 200));
+            assert!(lut[red][0] > lut[red][1] && lut[red][0] > lut[red][2]);
+        }
+    }
+
+    #[test]
+    fn compat_palettes() {
+        let mut header = [0; 0x1C];
+        header[..11].copy_from_slice(b"POKEMON RED");
+        header[0x17] = 0x01;
+        // Red background with green sprites in OBJ0
+        let colors = title_compat_colors(&header);
+        assert_eq!(colors[0], [0x7FFF, 0x421F, 0x1CF2, 0x0000]);
+        assert_eq!(colors[1], [0x7FFF, 0x1BEF, 0x0200, 0x0000]);
+
+        // Other publishers get the default palette
+        header[0x17] = 0x33;
+        header[0x10..0x12].copy_from_slice(b"08");
+        assert_eq!(title_compat_colors(&header), CompatPalette::DarkGreen.colors());
+
+        let mut header = [0; 0x1C];
+        header[..15].copy_from_slice(b"SUPER MARIOLAND");
+        header[0x17] = 0x01;
+        assert_eq!(title_compat_colors(&header)[0], [0x7ED6, 0x4BFF, 0x2175, 0x0000]);
+        header[3] = b'Z';
+        assert_eq!(title_compat_colors(&header), CompatPalette::DarkGreen.colors());
+
+        let palette = CompatPalette::from_keys(false, true, false, false, false, true);
+        assert_eq!(palette, Some(CompatPalette::Yellow));
+        assert_eq!(CompatPalette::Grayscale.colors()[2], [0x7FFF, 0x5294, 0x294A, 0x0000]);
+    }
+
+    #[test]
+    fn compat_titles() {
+        let colors = |title: &[u8]| {
+            let mut header = [0; 0x1C];
+            header[..title.len()].copy_from_slice(title);
+            header[0x17] = 0x01;
+            title_compat_colors(&header)
+        };
+
+        // Green background with the default sprite colors
+        let zelda = colors(b"ZELDA");
+        assert_eq!(zelda[0], [0x7FFF, 0x03E0, 0x0206, 0x0120]);
+        assert_eq!(zelda[1], [0x7FFF, 0x421F, 0x1CF2, 0x0000]);
+
+        // OBJ1 starts on the last color of a palette
+        let kirby = colors(b"KIRBY DREAM LAND");
+        assert_eq!(kirby[0], [0x7E74, 0x03FF, 0x0180, 0x0000]);
+        assert_eq!(kirby[2], [0x7C00, 0x7FFF, 0x3FFF, 0x7E00]);
+
+        assert_ne!(colors(b"METROID2"), CompatPalette::DarkGreen.colors());
+    }
+}
@@ -0,0 +1,292 @@
+// A small PNG encoder for RGB images. The image data is compressed with
+// fixed Huffman codes, which already works well for Gameboy screens.
+
+use std::io::{self, Write};
+
+const SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A];
+const WINDOW_SIZE: usize = 32768;
+const HASH_SIZE: usize = 1 << 15;
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+const DISTANCE_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537, 2049,
+    3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DISTANCE_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13,
+];
+
+// Writes an image of width * height RGB pixels
+pub fn write_png<W: Write>(w: &mut W, data: &[u8], width: usize, height: usize) -> io::Result<()> {
+    if data.len() != width * height * 3 {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "Image size does not match"));
+    }
+    w.write_all(&SIGNATURE)?;
+
+    let mut header = Vec::with_capacity(13);
+    header.extend_from_slice(&(width as u32).to_be_bytes());
+    header.extend_from_slice(&(height as u32).to_be_bytes());
+    // 8 bits per channel, RGB, no interlacing
+    header.extend_from_slice(&[8, 2, 0, 0, 0]);
+    write_chunk(w, b"IHDR", &header)?;
+
+    // Every row starts with its filter type, which is always none
+    let mut raw = Vec::with_capacity((width * 3 + 1) * height);
+    for row in data.chunks(width * 3) {
+        raw.push(0);
+        raw.extend_from_slice(row);
+    }
+    write_chunk(w, b"IDAT", &zlib(&raw))?;
+    write_chunk(w, b"IEND", &[])
+}
+
+fn write_chunk<W: Write>(w: &mut W, kind: &[u8; 4], data: &[u8]) -> io::Result<()> {
+    w.write_all(&(data.len() as u32).to_be_bytes())?;
+    w.write_all(kind)?;
+    w.write_all(data)?;
+    let crc = crc32(crc32(0, kind), data);
+    w.write_all(&crc.to_be_bytes())
+}
+
+fn crc32(crc: u32, data: &[u8]) -> u32 {
+    let mut crc = !crc;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB88320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for chunk in data.chunks(5552) {
+        for &byte in chunk {
+            a += byte as u32;
+            b += a;
+        }
+        a %= 65521;
+        b %= 65521;
+    }
+    (b << 16) | a
+}
+
+struct BitWriter {
+    out: Vec<u8>,
+    bits: u32,
+    count: u32,
+}
+
+impl BitWriter {
+    fn write(&mut self, value: u32, count: u32) {
+        self.bits |= value << self.count;
+        self.count += count;
+        while self.count >= 8 {
+            self.out.push(self.bits as u8);
+            self.bits >>= 8;
+            self.count -= 8;
+        }
+    }
+
+    // Huffman codes are stored starting with their most significant bit
+    fn write_code(&mut self, code: u32, count: u32) {
+        let reversed = code.reverse_bits() >> (32 - count);
+        self.write(reversed, count);
+    }
+
+    fn literal(&mut self, symbol: u32) {
+        match symbol {
+            0..=143 => self.write_code(0x30 + symbol, 8),
+            144..=255 => self.write_code(0x190 + symbol - 144, 9),
+            256..=279 => self.write_code(symbol - 256, 7),
+            _ => self.write_code(0xC0 + symbol - 280, 8),
+        }
+    }
+
+    fn copy(&mut self, length: usize, distance: usize) {
+        let i = LENGTH_BASE.iter().rposition(|&base| base as usize <= length).unwrap();
+        self.literal(257 + i as u32);
+        self.write((length - LENGTH_BASE[i] as usize) as u32, LENGTH_EXTRA[i] as u32);
+        let i = DISTANCE_BASE.iter().rposition(|&base| base as usize <= distance).unwrap();
+        self.write_code(i as u32, 5);
+        self.write((distance - DISTANCE_BASE[i] as usize) as u32, DISTANCE_EXTRA[i] as u32);
+    }
+}
+
+// Compresses the data as a single fixed Huffman block, finding repeats with
+// the most recent position of every three byte sequence
+fn zlib(data: &[u8]) -> Vec<u8> {
+    let mut w = BitWriter {
+        out: vec![0x78, 0x01],
+        bits: 0,
+        count: 0,
+    };
+    w.write(1, 1);
+    w.write(1, 2);
+
+    let hash = |i: usize| {
+        let v = (data[i] as usize) << 16 | (data[i + 1] as usize) << 8 | data[i + 2] as usize;
+        (v.wrapping_mul(2654435761) >> 8) % HASH_SIZE
+    };
+    let mut last = vec![usize::MAX; HASH_SIZE];
+    let mut pos = 0;
+    while pos < data.len() {
+        let mut length = 0;
+        if pos + MIN_MATCH <= data.len() {
+            let h = hash(pos);
+            let candidate = last[h];
+            last[h] = pos;
+            if candidate != usize::MAX && pos - candidate <= WINDOW_SIZE {
+                let max = (data.len() - pos).min(MAX_MATCH);
+                while length < max && data[candidate + length] == data[pos + length] {
+                    length += 1;
+                }
+                if length >= MIN_MATCH {
+                    w.copy(length, pos - candidate);
+                }
+            }
+        }
+        if length >= MIN_MATCH {
+            for i in pos + 1..(pos + length).min(data.len().saturating_sub(MIN_MATCH - 1)) {
+                last[hash(i)] = i;
+            }
+            pos += length;
+        } else {
+            w.literal(data[pos] as u32);
+            pos += 1;
+        }
+    }
+    w.literal(256);
+    if w.count > 0 {
+        w.write(0, 8 - w.count);
+    }
+
+    let mut out = w.out;
+    out.extend_from_slice(&adler32(data).to_be_bytes());
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::{adler32, crc32, write_png, zlib};
+    use super::{DISTANCE_BASE, DISTANCE_EXTRA, LENGTH_BASE, LENGTH_EXTRA};
+
+    struct BitReader<'a> {
+        data: &'a [u8],
+        pos: usize,
+    }
+
+    impl<'a> BitReader<'a> {
+        fn bits(&mut self, count: u32) -> usize {
+            let mut value = 0;
+            for i in 0..count as usize {
+                value |= ((self.data[self.pos / 8] >> (self.pos % 8)) as usize & 1) << i;
+                self.pos += 1;
+            }
+            value
+        }
+
+        // Huffman codes start with their most significant bit
+        fn code(&mut self, count: u32) -> usize {
+            (0..count).fold(0, |code, _| code << 1 | self.bits(1))
+        }
+
+        fn fixed_symbol(&mut self) -> usize {
+            let code = self.code(7);
+            if code < 0x18 {
+                return 256 + code;
+            }
+            let code = code << 1 | self.bits(1);
+            match code {
+                0x30..=0xBF => code - 0x30,
+                0xC0..=0xC7 => 280 + code - 0xC0,
+                _ => 144 + (code << 1 | self.bits(1)) - 0x190,
+            }
+        }
+    }
+
+    // Decodes a zlib stream of fixed Huffman blocks the way a PNG reader does
+    fn inflate(data: &[u8]) -> Vec<u8> {
+        assert_eq!(data[0] & 0x0F, 8);
+        assert_eq!(((data[0] as u16) << 8 | data[1] as u16) % 31, 0);
+        let mut r = BitReader { data: &data[2..], pos: 0 };
+        let mut out: Vec<u8> = Vec::new();
+        loop {
+            let last = r.bits(1);
+            assert_eq!(r.bits(2), 1);
+            loop {
+                let symbol = r.fixed_symbol();
+                match symbol {
+                    0..=255 => out.push(symbol as u8),
+                    256 => break,
+                    _ => {
+                        let i = symbol - 257;
+                        let length = LENGTH_BASE[i] as usize + r.bits(LENGTH_EXTRA[i] as u32);
+                        let i = r.code(5);
+                        let distance = DISTANCE_BASE[i] as usize + r.bits(DISTANCE_EXTRA[i] as u32);
+                        for _ in 0..length {
+                            out.push(out[out.len() - distance]);
+                        }
+                    }
+                }
+            }
+            if last == 1 {
+                break;
+            }
+        }
+        let end = 2 + r.pos.div_ceil(8);
+        assert_eq!(&data[end..], &adler32(&out).to_be_bytes());
+        out
+    }
+
+    #[test]
+    fn png_chunks() {
+        let data: Vec<u8> = (0..16 * 8 * 3).map(|i| (i / 48) as u8 * 32).collect();
+        let mut out = Vec::new();
+        write_png(&mut out, &data, 16, 8).unwrap();
+        assert_eq!(&out[..8], b"\x89PNG\r\n\x1a\n");
+        assert_eq!(&out[12..16], b"IHDR");
+        assert_eq!(&out[16..24], &[0, 0, 0, 16, 0, 0, 0, 8]);
+
+        // Walk the chunks and check their CRCs
+        let mut pos = 8;
+        let mut kinds = Vec::new();
+        while pos < out.len() {
+            let len = u32::from_be_bytes([out[pos], out[pos + 1], out[pos + 2], out[pos + 3]]) as usize;
+            let body = &out[pos + 4..pos + 8 + len];
+            let crc = &out[pos + 8 + len..pos + 12 + len];
+            assert_eq!(crc32(0, body).to_be_bytes(), crc);
+            kinds.push(body[..4].to_vec());
+            if &body[..4] == b"IDAT" {
+                let raw = inflate(&body[4..]);
+                // Every row starts with filter type 0
+                let rows = data.chunks(48).flat_map(|row| [&[0], row].concat());
+                assert_eq!(raw, rows.collect::<Vec<u8>>());
+            }
+            pos += 12 + len;
+        }
+        assert_eq!(kinds, vec![b"IHDR".to_vec(), b"IDAT".to_vec(), b"IEND".to_vec()]);
+        assert!(write_png(&mut Vec::new(), &data, 16, 9).is_err());
+    }
+
+    #[test]
+    fn deflate_round_trip() {
+        assert_eq!(adler32(b"Wikipedia"), 0x11E6_0398);
+        // Long runs, repeats far apart and every byte value
+        let mut data: Vec<u8> = (0..=255).collect();
+        data.extend(std::iter::repeat_n(7, 1000));
+        data.extend((0..40000u32).map(|i| (i * i / 7) as u8));
+        data.extend_from_within(100..1300);
+        assert_eq!(inflate(&zlib(&data)), data);
+        assert_eq!(inflate(&zlib(&[])), Vec::<u8>::new());
+    }
+}
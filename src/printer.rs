@@ -67,10 +67,17 @@ impl GbPrinter {
         let mut f = OpenOptions::new().create(true).write(true).truncate(true).open(&filename)?;
 
         write!(f, "P5 160 {} 3\n", image_height)?;
+        f.write_all(&self.image(self.packet[8]))?;
 
-        let palbyte = self.packet[8];
+        Ok(filename)
+    }
+
+    // The received tiles as 160 pixels wide rows of shades from 0 (black) to 3 (white)
+    fn image(&self, palbyte: u8) -> Vec<u8> {
+        let image_height = self.datacount / 40;
         let palette = [3 - ((palbyte >> 0) & 3), 3 - ((palbyte >> 2) & 3), 3 - ((palbyte >> 4) & 3), 3 - ((palbyte >> 6) & 3)];
 
+        let mut image = Vec::with_capacity(160 * image_height);
         for y in 0..image_height {
             for x in 0..160 {
                 let tilenumber = ((y >> 3) * 20) + (x >> 3);
@@ -79,11 +86,10 @@ impl GbPrinter {
 
                 let colourindex = ((self.data[tileoffset] >> bx) & 1) | (((self.data[tileoffset + 1] >> bx) << 1) & 2);
 
-                f.write_all(&[palette[colourindex as usize]])?;
+                image.push(palette[colourindex as usize]);
             }
         }
-
-        Ok(filename)
+        image
     }
 
     fn receive(&mut self) {
@@ -201,3 +207,51 @@ impl GbPrinter {
         self.result
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::GbPrinter;
+    use crate::mbc::camera_test::{camera, capture};
+    use crate::mbc::MBC;
+
+    // Sends a packet the way the Game Boy does, returning the status byte
+    fn send_packet(printer: &mut GbPrinter, command: u8, data: &[u8]) -> u8 {
+        let mut packet = vec![0x88, 0x33, command, 0x00, data.len() as u8, (data.len() >> 8) as u8];
+        packet.extend_from_slice(data);
+        let crc = packet[2..].iter().fold(0u16, |crc, &v| crc.wrapping_add(v as u16));
+        packet.extend_from_slice(&[crc as u8, (crc >> 8) as u8, 0x00, 0x00]);
+        packet.iter().map(|&v| printer.send(v)).last().unwrap()
+    }
+
+    #[test]
+    fn camera_to_printer() {
+        // White on the left half of the sensor, black on the right
+        let mut mbc = camera(|i| if i % 128 < 64 { 0xFF } else { 0x00 });
+        capture(&mut mbc, 456);
+
+        // The 16x14 tiles of the photo go in the middle of 20 tile wide rows
+        let mut tiles = vec![0; 20 * 14 * 16];
+        for row in 0..14 {
+            for column in 0..16 {
+                for i in 0..16 {
+                    let source = 0xA100 + ((row * 16 + column) * 16 + i) as u16;
+                    tiles[(row * 20 + column + 2) * 16 + i] = mbc.readram(source);
+                }
+            }
+        }
+
+        let mut printer = GbPrinter::new();
+        send_packet(&mut printer, 0x01, &[]);
+        for band in tiles.chunks(0x280) {
+            assert_eq!(send_packet(&mut printer, 0x04, band), 0x00);
+        }
+        let image = printer.image(0xE4);
+        assert_eq!(image.len(), 160 * 112);
+        for y in [0, 50, 111] {
+            let row = &image[y * 160..(y + 1) * 160];
+            assert!(row[..80].iter().all(|&v| v == 3));
+            assert!(row[80..144].iter().all(|&v| v == 0));
+            assert!(row[144..].iter().all(|&v| v == 3));
+        }
+    }
+}
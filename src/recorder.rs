@@ -0,0 +1,748 @@
+// Records the screen and the sound to a video file. Frames are placed on a
+// grid of 70224 clock cycles, the length of a frame while the LCD is on, so
+// the picture stays in sync with the sound when frames are missing.
+
+use crate::audio::{wave_format, SampleFormat, WavWriter};
+use crate::StrResult;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+
+const CLOCKS_PER_SECOND: u64 = 1 << 22;
+const FRAME_CYCLES: u64 = 70224;
+// The RIFF sizes have 32 bits, this leaves room for the index
+const AVI_LIMIT: u64 = 0xF000_0000;
+// Browsers show GIF frames with shorter delays for a tenth of a second
+const GIF_MIN_DELAY: u64 = 2;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum RecordFormat {
+    // Uncompressed RGB frames and 16 bit PCM sound
+    Avi,
+    // Raw 4:4:4 YUV frames, with the sound in a WAV file next to it
+    Y4m,
+    // Animated GIF without sound, meant for short clips
+    Gif,
+}
+
+impl RecordFormat {
+    // Picks the format from the extension of the file name
+    pub fn from_path(path: &Path) -> Option<RecordFormat> {
+        let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+        match extension.as_str() {
+            "avi" => Some(RecordFormat::Avi),
+            "y4m" => Some(RecordFormat::Y4m),
+            "gif" => Some(RecordFormat::Gif),
+            _ => None,
+        }
+    }
+
+    pub fn has_audio(self) -> bool {
+        self != RecordFormat::Gif
+    }
+}
+
+enum Output {
+    Avi(AviWriter<BufWriter<File>>),
+    Y4m(BufWriter<File>, Option<WavWriter>),
+    Gif(GifWriter<BufWriter<File>>),
+}
+
+pub struct Recorder {
+    output: Output,
+    width: usize,
+    height: usize,
+    samples_rate: Option<u32>,
+    // Clock cycles since the recording started
+    cycles: u64,
+    // The grid starts at the first frame
+    start: Option<u64>,
+    frames: u64,
+    last_frame: Vec<u8>,
+    // Stereo samples written, and those to drop from before the first frame
+    samples: u64,
+    skip_samples: u64,
+    error: Option<&'static str>,
+    finished: bool,
+}
+
+fn create(path: &Path) -> StrResult<BufWriter<File>> {
+    File::create(path)
+        .map(BufWriter::new)
+        .map_err(|_| "Could not create recording file")
+}
+
+impl Recorder {
+    // Without a sample rate only the picture is recorded
+    pub fn new(
+        path: &Path,
+        width: usize,
+        height: usize,
+        samples_rate: Option<u32>,
+    ) -> StrResult<Recorder> {
+        let format = RecordFormat::from_path(path).ok_or("Unknown recording format")?;
+        let samples_rate = samples_rate.filter(|_| format.has_audio());
+        let output = match format {
+            RecordFormat::Avi => {
+                Output::Avi(AviWriter::new(create(path)?, width, height, samples_rate))
+            }
+            RecordFormat::Y4m => {
+                let y4m = create(path)?;
+                let wav = match samples_rate {
+                    Some(rate) => {
+                        let path = path.with_extension("wav");
+                        Some(WavWriter::create(&path, rate, SampleFormat::Int16)?)
+                    }
+                    None => None,
+                };
+                Output::Y4m(y4m, wav)
+            }
+            RecordFormat::Gif => Output::Gif(GifWriter::new(create(path)?, width, height)),
+        };
+        let mut recorder = Recorder {
+            output,
+            width,
+            height,
+            samples_rate,
+            cycles: 0,
+            start: None,
+            frames: 0,
+            last_frame: Vec::new(),
+            samples: 0,
+            skip_samples: 0,
+            error: None,
+            finished: false,
+        };
+        let result = recorder.write_header();
+        recorder.check(result);
+        Ok(recorder)
+    }
+
+    pub fn add_cycles(&mut self, cycles: u32) {
+        self.cycles += cycles as u64;
+    }
+
+    // Adds a completed frame of RGB pixels. Frames that came too late are
+    // repeated, and a frame in the same place of the grid as the one before it
+    // is only used for those repeats.
+    pub fn add_frame(&mut self, data: &[u8]) {
+        if self.start.is_none() {
+            self.start = Some(self.cycles);
+            self.skip_samples = self.samples_at(self.cycles);
+        }
+        let slot = self.slot();
+        if slot >= self.frames {
+            self.repeat_until(slot);
+            self.write_frame(data);
+        }
+        self.last_frame.clear();
+        self.last_frame.extend_from_slice(data);
+    }
+
+    // Adds interleaved stereo samples, in the sample rate given to new
+    pub fn add_audio(&mut self, samples: &[f32]) {
+        let skip = (self.skip_samples as usize * 2).min(samples.len());
+        self.skip_samples -= skip as u64 / 2;
+        self.write_audio(&samples[skip..]);
+    }
+
+    pub fn finish(mut self) -> StrResult<()> {
+        self.finish_output()
+    }
+
+    // The grid position closest to the current time
+    fn slot(&self) -> u64 {
+        let start = self.start.unwrap_or(0);
+        (self.cycles - start + FRAME_CYCLES / 2) / FRAME_CYCLES
+    }
+
+    fn samples_at(&self, cycles: u64) -> u64 {
+        let rate = self.samples_rate.unwrap_or(0) as u64;
+        cycles * rate / CLOCKS_PER_SECOND
+    }
+
+    fn repeat_until(&mut self, slot: u64) {
+        let last_frame = std::mem::take(&mut self.last_frame);
+        while self.frames < slot && self.error.is_none() {
+            self.write_frame(&last_frame);
+        }
+        self.last_frame = last_frame;
+    }
+
+    fn finish_output(&mut self) -> StrResult<()> {
+        if self.finished {
+            return Ok(());
+        }
+        self.finished = true;
+        if self.start.is_some() {
+            let slot = self.slot();
+            self.repeat_until(slot);
+        }
+        // Fill up the sound to the end of the last frame
+        let total = self.samples_at(self.frames * FRAME_CYCLES);
+        if total > self.samples {
+            let silence = vec![0.0; (total - self.samples) as usize * 2];
+            self.write_audio(&silence);
+        }
+        let (frames, samples) = (self.frames, self.samples);
+        let result = match self.output {
+            Output::Avi(ref mut avi) => avi.finish(frames, samples),
+            Output::Y4m(ref mut y4m, ref mut wav) => y4m
+                .flush()
+                .and_then(|_| wav.as_mut().map_or(Ok(()), |w| w.finish())),
+            Output::Gif(ref mut gif) => gif.finish(frames),
+        };
+        self.check(result);
+        match self.error {
+            Some(message) => Err(message),
+            None => Ok(()),
+        }
+    }
+
+    fn write_header(&mut self) -> io::Result<()> {
+        let (width, height) = (self.width, self.height);
+        match self.output {
+            Output::Avi(ref mut avi) => avi.write_header(0, 0),
+            Output::Y4m(ref mut y4m, _) => {
+                let header = format!(
+                    "YUV4MPEG2 W{} H{} F{}:{} Ip A1:1 C444\n",
+                    width, height, CLOCKS_PER_SECOND, FRAME_CYCLES
+                );
+                y4m.write_all(header.as_bytes())
+            }
+            Output::Gif(ref mut gif) => gif.write_header(),
+        }
+    }
+
+    fn write_frame(&mut self, data: &[u8]) {
+        if self.error.is_some() {
+            return;
+        }
+        if data.len() != self.width * self.height * 3 {
+            self.error = Some("Frame size does not match the recording");
+            return;
+        }
+        let frame = self.frames;
+        self.frames += 1;
+        let (width, height) = (self.width, self.height);
+        let result = match self.output {
+            Output::Avi(ref mut avi) => avi.write_frame(data, width, height),
+            Output::Y4m(ref mut y4m, _) => write_y4m_frame(y4m, data),
+            Output::Gif(ref mut gif) => gif.write_frame(data, frame),
+        };
+        self.check(result);
+    }
+
+    fn write_audio(&mut self, samples: &[f32]) {
+        if self.error.is_some() || self.samples_rate.is_none() || samples.is_empty() {
+            return;
+        }
+        self.samples += samples.len() as u64 / 2;
+        let result = match self.output {
+            Output::Avi(ref mut avi) => avi.write_audio(samples),
+            Output::Y4m(_, Some(ref mut wav)) => wav.write_interleaved(samples),
+            _ => Ok(()),
+        };
+        self.check(result);
+    }
+
+    // After an error the rest of the recording is ignored, and the error is
+    // returned by finish
+    fn check(&mut self, result: io::Result<()>) {
+        if result.is_err() && self.error.is_none() {
+            self.error = Some("Could not write recording");
+        }
+    }
+}
+
+impl Drop for Recorder {
+    fn drop(&mut self) {
+        let _ = self.finish_output();
+    }
+}
+
+// BT.601 YCbCr, each plane stored at full resolution
+fn write_y4m_frame<W: Write>(w: &mut W, data: &[u8]) -> io::Result<()> {
+    let pixels = data.len() / 3;
+    let mut planes = vec![0; pixels * 3];
+    for (i, p) in data.chunks(3).enumerate() {
+        let (r, g, b) = (p[0] as i32, p[1] as i32, p[2] as i32);
+        planes[i] = (((66 * r + 129 * g + 25 * b + 128) >> 8) + 16) as u8;
+        planes[pixels + i] = (((-38 * r - 74 * g + 112 * b + 128) >> 8) + 128) as u8;
+        planes[pixels * 2 + i] = (((112 * r - 94 * g - 18 * b + 128) >> 8) + 128) as u8;
+    }
+    w.write_all(b"FRAME\n")?;
+    w.write_all(&planes)
+}
+
+struct AviWriter<W: Write + Seek> {
+    w: W,
+    width: usize,
+    height: usize,
+    samples_rate: Option<u32>,
+    // Position of the chunks within the movi list, for the index
+    index: Vec<([u8; 4], u32, u32)>,
+    movi_size: u64,
+}
+
+impl<W: Write + Seek> AviWriter<W> {
+    fn new(w: W, width: usize, height: usize, samples_rate: Option<u32>) -> AviWriter<W> {
+        AviWriter {
+            w,
+            width,
+            height,
+            samples_rate,
+            index: Vec::new(),
+            movi_size: 4,
+        }
+    }
+
+    fn frame_size(&self) -> u32 {
+        ((self.width * 3 + 3) & !3) as u32 * self.height as u32
+    }
+
+    // The headers have the same size every time, so they can be written again
+    // with the final lengths
+    fn write_header(&mut self, frames: u64, samples: u64) -> io::Result<()> {
+        let frame_size = self.frame_size();
+        let (width, height) = (self.width as u32, self.height as u32);
+        let streams = if self.samples_rate.is_some() { 2 } else { 1 };
+
+        let mut avih = Vec::new();
+        let fields = [
+            (1_000_000 * FRAME_CYCLES / CLOCKS_PER_SECOND) as u32,
+            (frame_size as u64 * CLOCKS_PER_SECOND / FRAME_CYCLES) as u32,
+            0,
+            // Has an index and is interleaved
+            0x110,
+            frames as u32,
+            0,
+            streams,
+            frame_size,
+            width,
+            height,
+            0,
+            0,
+            0,
+            0,
+        ];
+        for field in fields.iter() {
+            avih.extend_from_slice(&field.to_le_bytes());
+        }
+
+        let mut video = stream_header(
+            b"vids",
+            b"DIB ",
+            FRAME_CYCLES as u32,
+            CLOCKS_PER_SECOND as u32,
+        );
+        video.extend(stream_rest(frames, frame_size, 0, width, height));
+        let mut bitmap = Vec::new();
+        for field in [40, width, height].iter() {
+            bitmap.extend_from_slice(&field.to_le_bytes());
+        }
+        bitmap.extend_from_slice(&1u16.to_le_bytes());
+        bitmap.extend_from_slice(&24u16.to_le_bytes());
+        for field in [0, frame_size, 0, 0, 0, 0].iter() {
+            bitmap.extend_from_slice(&field.to_le_bytes());
+        }
+        let mut strl = chunk(b"strh", &video);
+        strl.extend(chunk(b"strf", &bitmap));
+
+        let mut hdrl = chunk(b"avih", &avih);
+        hdrl.extend(list(b"strl", &strl));
+        if let Some(rate) = self.samples_rate {
+            let mut audio = stream_header(b"auds", &[0; 4], 4, rate * 4);
+            audio.extend(stream_rest(samples, rate, 4, 0, 0));
+            let mut strl = chunk(b"strh", &audio);
+            strl.extend(chunk(b"strf", &wave_format(rate, SampleFormat::Int16)));
+            hdrl.extend(list(b"strl", &strl));
+        }
+        let hdrl = list(b"hdrl", &hdrl);
+
+        let riff_size =
+            4 + hdrl.len() as u64 + 8 + self.movi_size + 8 + self.index.len() as u64 * 16;
+        self.w.write_all(b"RIFF")?;
+        self.w.write_all(&(riff_size as u32).to_le_bytes())?;
+        self.w.write_all(b"AVI ")?;
+        self.w.write_all(&hdrl)?;
+        self.w.write_all(b"LIST")?;
+        self.w.write_all(&(self.movi_size as u32).to_le_bytes())?;
+        self.w.write_all(b"movi")
+    }
+
+    fn write_chunk(&mut self, id: &[u8; 4], data: &[u8]) -> io::Result<()> {
+        let padded = data.len() as u64 + (data.len() as u64 & 1);
+        if self.movi_size + 8 + padded + (self.index.len() as u64 + 1) * 16 > AVI_LIMIT {
+            return Err(io::Error::other("Recording is too large"));
+        }
+        self.index
+            .push((*id, self.movi_size as u32, data.len() as u32));
+        self.w.write_all(id)?;
+        self.w.write_all(&(data.len() as u32).to_le_bytes())?;
+        self.w.write_all(data)?;
+        if data.len() & 1 == 1 {
+            self.w.write_all(&[0])?;
+        }
+        self.movi_size += 8 + padded;
+        Ok(())
+    }
+
+    // Rows are stored from the bottom up in BGR order, padded to four bytes
+    fn write_frame(&mut self, data: &[u8], width: usize, height: usize) -> io::Result<()> {
+        let stride = (width * 3 + 3) & !3;
+        let mut frame = vec![0; stride * height];
+        for (y, row) in data.chunks(width * 3).enumerate() {
+            let out = &mut frame[(height - 1 - y) * stride..];
+            for (o, p) in out.chunks_mut(3).zip(row.chunks(3)) {
+                o.copy_from_slice(&[p[2], p[1], p[0]]);
+            }
+        }
+        self.write_chunk(b"00dc", &frame)
+    }
+
+    // 16 bit samples, as in the stream header
+    fn write_audio(&mut self, samples: &[f32]) -> io::Result<()> {
+        let pcm: Vec<u8> = samples
+            .iter()
+            .flat_map(|&v| ((v.clamp(-1.0, 1.0) * 32767.0) as i16).to_le_bytes())
+            .collect();
+        self.write_chunk(b"01wb", &pcm)
+    }
+
+    fn finish(&mut self, frames: u64, samples: u64) -> io::Result<()> {
+        self.w.write_all(b"idx1")?;
+        self.w
+            .write_all(&(self.index.len() as u32 * 16).to_le_bytes())?;
+        for &(id, offset, size) in self.index.iter() {
+            // Every frame is a key frame
+            let flags: u32 = if &id == b"00dc" { 0x10 } else { 0 };
+            self.w.write_all(&id)?;
+            self.w.write_all(&flags.to_le_bytes())?;
+            self.w.write_all(&offset.to_le_bytes())?;
+            self.w.write_all(&size.to_le_bytes())?;
+        }
+        self.w.seek(SeekFrom::Start(0))?;
+        self.write_header(frames, samples)?;
+        self.w.flush()
+    }
+}
+
+fn chunk(id: &[u8; 4], data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len() + 9);
+    out.extend_from_slice(id);
+    out.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    out.extend_from_slice(data);
+    if data.len() & 1 == 1 {
+        out.push(0);
+    }
+    out
+}
+
+fn list(kind: &[u8; 4], data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len() + 12);
+    out.extend_from_slice(b"LIST");
+    out.extend_from_slice(&(data.len() as u32 + 4).to_le_bytes());
+    out.extend_from_slice(kind);
+    out.extend_from_slice(data);
+    out
+}
+
+// Type, handler, flags, priority, language, initial frames, scale and rate
+fn stream_header(kind: &[u8; 4], handler: &[u8; 4], scale: u32, rate: u32) -> Vec<u8> {
+    let mut out = Vec::with_capacity(56);
+    out.extend_from_slice(kind);
+    out.extend_from_slice(handler);
+    for field in [0, 0, 0, scale, rate].iter() {
+        out.extend_from_slice(&field.to_le_bytes());
+    }
+    out
+}
+
+// Start, length, buffer size, quality, sample size and the frame rectangle
+fn stream_rest(length: u64, buffer: u32, sample_size: u32, width: u32, height: u32) -> Vec<u8> {
+    let mut out = Vec::with_capacity(32);
+    for field in [0, length as u32, buffer, u32::MAX, sample_size].iter() {
+        out.extend_from_slice(&field.to_le_bytes());
+    }
+    for field in [0, 0, width as u16, height as u16].iter() {
+        out.extend_from_slice(&field.to_le_bytes());
+    }
+    out
+}
+
+// Frames are only written once the next different frame arrives, so a still
+// picture becomes a single frame with a long delay
+struct GifWriter<W: Write> {
+    w: W,
+    width: usize,
+    height: usize,
+    pending: Option<(Vec<u8>, u64)>,
+}
+
+impl<W: Write> GifWriter<W> {
+    fn new(w: W, width: usize, height: usize) -> GifWriter<W> {
+        GifWriter {
+            w,
+            width,
+            height,
+            pending: None,
+        }
+    }
+
+    fn write_header(&mut self) -> io::Result<()> {
+        self.w.write_all(b"GIF89a")?;
+        self.w.write_all(&(self.width as u16).to_le_bytes())?;
+        self.w.write_all(&(self.height as u16).to_le_bytes())?;
+        // No global color table, the frames have their own
+        self.w.write_all(&[0, 0, 0])?;
+        // Loop forever
+        self.w
+            .write_all(b"\x21\xFF\x0BNETSCAPE2.0\x03\x01\x00\x00\x00")
+    }
+
+    fn write_frame(&mut self, data: &[u8], frame: u64) -> io::Result<()> {
+        match self.pending {
+            Some((ref previous, _)) if previous[..] == *data => Ok(()),
+            Some((ref mut previous, start))
+                if centiseconds(frame) - centiseconds(start) < GIF_MIN_DELAY =>
+            {
+                // Too soon after the previous frame, which is replaced instead
+                previous.copy_from_slice(data);
+                Ok(())
+            }
+            _ => {
+                self.write_pending(frame)?;
+                self.pending = Some((data.to_vec(), frame));
+                Ok(())
+            }
+        }
+    }
+
+    fn write_pending(&mut self, end: u64) -> io::Result<()> {
+        let (data, start) = match self.pending.take() {
+            Some(pending) => pending,
+            None => return Ok(()),
+        };
+        let delay = (centiseconds(end) - centiseconds(start)).clamp(GIF_MIN_DELAY, 0xFFFF);
+        let (colors, indices) = gif_palette(&data);
+        // The color table has 2^(bits + 1) entries
+        let bits = (0..8).find(|&b| 2 << b >= colors.len()).unwrap();
+
+        self.w.write_all(&[0x21, 0xF9, 4, 0x04])?;
+        self.w.write_all(&(delay as u16).to_le_bytes())?;
+        self.w.write_all(&[0, 0])?;
+
+        self.w.write_all(&[0x2C, 0, 0, 0, 0])?;
+        self.w.write_all(&(self.width as u16).to_le_bytes())?;
+        self.w.write_all(&(self.height as u16).to_le_bytes())?;
+        self.w.write_all(&[0x80 | bits as u8])?;
+        let mut table = vec![0; 3 << (bits + 1)];
+        for (entry, color) in table.chunks_mut(3).zip(colors.iter()) {
+            entry.copy_from_slice(color);
+        }
+        self.w.write_all(&table)?;
+
+        let min_code_size = (bits + 1).max(2);
+        self.w.write_all(&[min_code_size as u8])?;
+        for block in lzw(&indices, min_code_size).chunks(255) {
+            self.w.write_all(&[block.len() as u8])?;
+            self.w.write_all(block)?;
+        }
+        self.w.write_all(&[0])
+    }
+
+    fn finish(&mut self, frames: u64) -> io::Result<()> {
+        self.write_pending(frames)?;
+        self.w.write_all(&[0x3B])?;
+        self.w.flush()
+    }
+}
+
+// Time at which a frame starts, rounded to the GIF delay unit
+fn centiseconds(frame: u64) -> u64 {
+    (frame * FRAME_CYCLES * 100 + CLOCKS_PER_SECOND / 2) / CLOCKS_PER_SECOND
+}
+
+// Gives the colors of the frame and the index of every pixel. Frames with
+// more than 256 colors lose precision until they fit.
+fn gif_palette(data: &[u8]) -> (Vec<[u8; 3]>, Vec<u8>) {
+    let mut shift = 0;
+    loop {
+        let mask = 0xFFu8 << shift;
+        let mut colors = Vec::new();
+        let mut lookup = HashMap::new();
+        let mut indices = Vec::with_capacity(data.len() / 3);
+        for p in data.chunks(3) {
+            let color = [p[0] & mask, p[1] & mask, p[2] & mask];
+            let index = *lookup.entry(color).or_insert_with(|| {
+                colors.push(color);
+                colors.len() - 1
+            });
+            if index > 255 {
+                break;
+            }
+            indices.push(index as u8);
+        }
+        if indices.len() == data.len() / 3 {
+            return (colors, indices);
+        }
+        shift += 1;
+    }
+}
+
+// Variable length LZW codes as used by GIF, starting with a clear code
+fn lzw(indices: &[u8], min_code_size: u32) -> Vec<u8> {
+    let clear = 1u32 << min_code_size;
+    let mut out = Vec::new();
+    let (mut bits, mut count) = (0u32, 0u32);
+    let mut write = |code: u32, size: u32| {
+        bits |= code << count;
+        count += size;
+        while count >= 8 {
+            out.push(bits as u8);
+            bits >>= 8;
+            count -= 8;
+        }
+    };
+
+    let mut table: HashMap<(u32, u8), u32> = HashMap::new();
+    let mut size = min_code_size + 1;
+    let mut max_code = clear + 1;
+    write(clear, size);
+    let mut prefix = match indices.first() {
+        Some(&first) => first as u32,
+        None => return Vec::new(),
+    };
+    for &index in indices[1..].iter() {
+        if let Some(&code) = table.get(&(prefix, index)) {
+            prefix = code;
+            continue;
+        }
+        write(prefix, size);
+        max_code += 1;
+        table.insert((prefix, index), max_code);
+        if max_code >= 1 << size {
+            size += 1;
+        }
+        if max_code == 4095 {
+            write(clear, size);
+            table.clear();
+            size = min_code_size + 1;
+            max_code = clear + 1;
+        }
+        prefix = index as u32;
+    }
+    write(prefix, size);
+    write(clear + 1, size);
+    write(0, 7);
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::{lzw, Recorder, FRAME_CYCLES};
+
+    fn read_u32(data: &[u8], pos: usize) -> u32 {
+        u32::from_le_bytes([data[pos], data[pos + 1], data[pos + 2], data[pos + 3]])
+    }
+
+    #[test]
+    fn avi_frames_follow_the_clock() {
+        let path = std::env::temp_dir().join(format!("rboy-test-{}.avi", std::process::id()));
+        let mut recorder = Recorder::new(&path, 4, 2, Some(48000)).unwrap();
+        let frame = |v: u8| vec![v; 4 * 2 * 3];
+        recorder.add_cycles(1000);
+        recorder.add_frame(&frame(1));
+        recorder.add_cycles(FRAME_CYCLES as u32);
+        recorder.add_frame(&frame(2));
+        // The LCD was off for three frames, which repeat the second one
+        recorder.add_cycles(FRAME_CYCLES as u32 * 4);
+        recorder.add_frame(&frame(3));
+        recorder.add_audio(&vec![0.5; 1000]);
+        recorder.add_cycles(FRAME_CYCLES as u32);
+        recorder.finish().unwrap();
+
+        let data = std::fs::read(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(&data[..4], b"RIFF");
+        assert_eq!(read_u32(&data, 4) as usize, data.len() - 8);
+        // Total frames in the main header
+        assert_eq!(read_u32(&data, 48), 6);
+
+        let index = data.windows(4).rposition(|w| w == b"idx1").unwrap();
+        let entries: Vec<&[u8]> = data[index + 8..].chunks(16).collect();
+        let frames = entries.iter().filter(|e| &e[..4] == b"00dc").count();
+        assert_eq!(frames, 6);
+        // The sound is made as long as the picture, about 6 / 59.73 seconds
+        let samples: u32 = entries
+            .iter()
+            .filter(|e| &e[..4] == b"01wb")
+            .map(|e| read_u32(e, 12) / 4)
+            .sum();
+        assert_eq!(samples as u64, 6 * FRAME_CYCLES * 48000 / (1 << 22));
+    }
+
+    // Decodes the codes back to indices the way a GIF reader does
+    fn unlzw(data: &[u8], min_code_size: u32) -> Vec<u8> {
+        let clear = 1usize << min_code_size;
+        let mut table: Vec<Vec<u8>> = Vec::new();
+        let mut size = min_code_size + 1;
+        let (mut pos, mut out, mut previous) = (0, Vec::new(), None::<Vec<u8>>);
+        loop {
+            let mut code = 0;
+            for i in 0..size as usize {
+                code |= ((data[(pos + i) / 8] >> ((pos + i) % 8)) as usize & 1) << i;
+            }
+            pos += size as usize;
+            if code == clear {
+                table = (0..clear).map(|i| vec![i as u8]).collect();
+                table.push(Vec::new());
+                table.push(Vec::new());
+                size = min_code_size + 1;
+                previous = None;
+                continue;
+            }
+            if code == clear + 1 {
+                return out;
+            }
+            let entry = match previous {
+                Some(ref p) if code == table.len() => [&p[..], &p[..1]].concat(),
+                _ => table[code].clone(),
+            };
+            if let Some(p) = previous {
+                if table.len() < 4096 {
+                    table.push([&p[..], &entry[..1]].concat());
+                }
+            }
+            if table.len() == 1 << size && size < 12 {
+                size += 1;
+            }
+            out.extend_from_slice(&entry);
+            previous = Some(entry);
+        }
+    }
+
+    #[test]
+    fn gif_lzw_round_trip() {
+        let mut seed = 1u32;
+        let mut indices = Vec::new();
+        for _ in 0..20000 {
+            seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+            // Long runs with some noise, like a game screen
+            let v = if seed >> 28 == 0 {
+                (seed >> 16) as u8 % 4
+            } else {
+                *indices.last().unwrap_or(&0)
+            };
+            indices.push(v);
+        }
+        assert_eq!(unlzw(&lzw(&indices, 2), 2), indices);
+
+        let noise: Vec<u8> = (0..20000u32)
+            .map(|i| (i.wrapping_mul(2654435761) >> 24) as u8)
+            .collect();
+        assert_eq!(unlzw(&lzw(&noise, 8), 8), noise);
+    }
+}
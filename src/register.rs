@@ -1,3 +1,8 @@
+use crate::gbmode::Model;
+use crate::state::{StateReader, StateWriter};
+use crate::StrResult;
+use std::fmt;
+
 #[derive(Copy, Clone)]
 pub struct Registers {
     pub a: u8,
@@ -23,24 +28,47 @@ pub enum CpuFlag
 
 impl Registers {
     pub fn new() -> Registers {
-        Registers {
-            a: 0x01,
-            f: 0xB0,
-            b: 0x00,
-            c: 0x13,
-            d: 0x00,
-            e: 0xD8,
-            h: 0x01,
-            l: 0x4D,
-            pc: 0x0100,
-            sp: 0xFFFE,
-        }
+        Registers::new_model(Model::Dmg, false)
+    }
+
+    // The values the boot ROM of the model leaves behind. On CGB and AGB they
+    // also depend on whether the cartridge runs in color mode.
+    pub fn new_model(model: Model, color: bool) -> Registers {
+        let (af, bc, de, hl) = match model {
+            Model::Dmg0 => (0x0100, 0xFF13, 0x00C1, 0x8403),
+            Model::Dmg => (0x01B0, 0x0013, 0x00D8, 0x014D),
+            Model::Mgb => (0xFFB0, 0x0013, 0x00D8, 0x014D),
+            Model::Sgb => (0x0100, 0x0014, 0x0000, 0xC060),
+            Model::Sgb2 => (0xFF00, 0x0014, 0x0000, 0xC060),
+            Model::Cgb if color => (0x1180, 0x0000, 0xFF56, 0x000D),
+            Model::Cgb => (0x1180, 0x0000, 0x0008, 0x007C),
+            // The AGB boot ROM sets bit 0 of B, which games use to detect it
+            Model::Agb if color => (0x1100, 0x0100, 0xFF56, 0x000D),
+            Model::Agb => (0x1100, 0x0100, 0x0008, 0x007C),
+        };
+        let mut reg = Registers::power_on();
+        reg.setaf(af);
+        reg.setbc(bc);
+        reg.setde(de);
+        reg.sethl(hl);
+        reg.pc = 0x0100;
+        reg.sp = 0xFFFE;
+        reg
     }
 
-    pub fn new_cgb() -> Registers {
+    // The state before the boot ROM runs
+    pub fn power_on() -> Registers {
         Registers {
-            a: 0x11,
-            ..Registers::new()
+            a: 0,
+            f: 0,
+            b: 0,
+            c: 0,
+            d: 0,
+            e: 0,
+            h: 0,
+            l: 0,
+            pc: 0,
+            sp: 0,
         }
     }
 
@@ -98,6 +126,28 @@ impl Registers {
         self.f & mask > 0
     }
 
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write_bytes(&[self.a, self.f, self.b, self.c, self.d, self.e, self.h, self.l]);
+        w.write_u16(self.pc);
+        w.write_u16(self.sp);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> StrResult<()> {
+        let mut regs = [0; 8];
+        r.read_bytes(&mut regs)?;
+        self.a = regs[0];
+        self.f = regs[1] & 0xF0;
+        self.b = regs[2];
+        self.c = regs[3];
+        self.d = regs[4];
+        self.e = regs[5];
+        self.h = regs[6];
+        self.l = regs[7];
+        self.pc = r.read_u16()?;
+        self.sp = r.read_u16()?;
+        Ok(())
+    }
+
     #[cfg(test)]
     fn setf(&mut self, flags: u8)
     {
@@ -105,6 +155,21 @@ impl Registers {
     }
 }
 
+impl Default for Registers {
+    fn default() -> Registers {
+        Registers::new()
+    }
+}
+
+impl fmt::Display for Registers {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let flag = |flag: CpuFlag, c: char| if self.getflag(flag) { c } else { '-' };
+        write!(f, "AF={:04X} BC={:04X} DE={:04X} HL={:04X} SP={:04X} PC={:04X} [{}{}{}{}]",
+               self.af(), self.bc(), self.de(), self.hl(), self.sp, self.pc,
+               flag(CpuFlag::Z, 'Z'), flag(CpuFlag::N, 'N'), flag(CpuFlag::H, 'H'), flag(CpuFlag::C, 'C'))
+    }
+}
+
 #[cfg(test)]
 mod test
 {
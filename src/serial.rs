@@ -1,3 +1,6 @@
+use crate::state::{StateReader, StateWriter};
+use crate::StrResult;
+
 pub type SerialCallback<'a> = Box<dyn FnMut(u8) -> Option<u8> + Send + 'a>;
 
 fn noop(_: u8) -> Option<u8> {
@@ -48,6 +51,19 @@ impl<'a> Serial<'a> {
         }
     }
 
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write_u8(self.data);
+        w.write_u8(self.control);
+        w.write_u8(self.interrupt);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> StrResult<()> {
+        self.data = r.read_u8()?;
+        self.control = r.read_u8()?;
+        self.interrupt = r.read_u8()?;
+        Ok(())
+    }
+
     pub fn set_callback(&mut self, cb: SerialCallback<'static>) {
         self.callback = cb;
     }
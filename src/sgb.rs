@@ -0,0 +1,536 @@
+use crate::gpu::{SCREEN_H, SCREEN_W};
+use crate::palette::rgb555;
+use crate::state::{StateReader, StateWriter};
+use crate::StrResult;
+
+pub const SGB_SCREEN_W: usize = 256;
+pub const SGB_SCREEN_H: usize = 224;
+
+// The game screen is shown in the middle of the border
+const SCREEN_X: usize = (SGB_SCREEN_W - SCREEN_W) / 2;
+const SCREEN_Y: usize = (SGB_SCREEN_H - SCREEN_H) / 2;
+const ATTR_W: usize = SCREEN_W / 8;
+const ATTR_H: usize = SCREEN_H / 8;
+const ATTR_FILES: usize = 45;
+const ATTR_FILE_SIZE: usize = ATTR_W * ATTR_H / 4;
+const SYSTEM_PALETTES: usize = 512;
+const BORDER_TILES: usize = 256;
+const BORDER_MAP_SIZE: usize = 32 * 32;
+// Frames between a transfer command and the frame whose contents are sent
+const TRANSFER_DELAY: u8 = 3;
+
+const DEFAULT_PALETTE: [u16; 4] = [0x67BF, 0x265B, 0x10B5, 0x2866];
+
+#[derive(Copy, Clone, PartialEq)]
+enum Transfer {
+    None,
+    Palettes,
+    Tiles(usize),
+    Border,
+    Attributes,
+}
+
+// The Super Game Boy, which receives commands from the game through the
+// joypad register and draws the screen with its palettes inside a border
+pub struct Sgb {
+    // Commands are only accepted from cartridges that declare SGB support
+    supported: bool,
+    command: Vec<u8>,
+    packets_left: usize,
+    palettes: [[u16; 4]; 4],
+    system_palettes: Vec<[u16; 4]>,
+    // Palette of every 8x8 block of the game screen
+    attr_map: [u8; ATTR_W * ATTR_H],
+    attr_files: Vec<u8>,
+    mask: u8,
+    players: u8,
+    // Border tiles in the SNES 4bpp format, the tile map and palettes 4 to 7
+    border_tiles: Vec<u8>,
+    border_map: Vec<u16>,
+    border_palettes: [[u16; 16]; 4],
+    transfer: Transfer,
+    transfer_delay: u8,
+    pub data: Vec<u8>,
+}
+
+impl Sgb {
+    pub fn new(supported: bool) -> Sgb {
+        let mut res = Sgb {
+            supported,
+            command: Vec::new(),
+            packets_left: 0,
+            palettes: [DEFAULT_PALETTE; 4],
+            system_palettes: vec![[0; 4]; SYSTEM_PALETTES],
+            attr_map: [0; ATTR_W * ATTR_H],
+            attr_files: vec![0; ATTR_FILES * ATTR_FILE_SIZE],
+            mask: 0,
+            players: 1,
+            border_tiles: vec![0; BORDER_TILES * 32],
+            border_map: vec![0; BORDER_MAP_SIZE],
+            border_palettes: [[0; 16]; 4],
+            transfer: Transfer::None,
+            transfer_delay: 0,
+            data: vec![0; SGB_SCREEN_W * SGB_SCREEN_H * 3],
+        };
+        res.render(&[0; SCREEN_W * SCREEN_H]);
+        res
+    }
+
+    pub fn players(&self) -> u8 {
+        self.players
+    }
+
+    // Collects the packets of a command, the first byte holds the command and
+    // the number of packets
+    pub fn receive(&mut self, packet: &[u8; 16]) {
+        if !self.supported {
+            return;
+        }
+        if self.packets_left == 0 {
+            self.command.clear();
+            self.packets_left = ((packet[0] & 0x07) as usize).max(1);
+        }
+        self.command.extend_from_slice(packet);
+        self.packets_left -= 1;
+        if self.packets_left == 0 {
+            self.execute();
+        }
+    }
+
+    fn execute(&mut self) {
+        let data = std::mem::take(&mut self.command);
+        match data[0] >> 3 {
+            0x00 => self.set_palette_pair(&data, 0, 1),
+            0x01 => self.set_palette_pair(&data, 2, 3),
+            0x02 => self.set_palette_pair(&data, 0, 3),
+            0x03 => self.set_palette_pair(&data, 1, 2),
+            0x04 => self.attr_blk(&data),
+            0x05 => self.attr_lin(&data),
+            0x06 => self.attr_div(&data),
+            0x07 => self.attr_chr(&data),
+            0x0A => self.pal_set(&data),
+            0x0B => self.start_transfer(Transfer::Palettes),
+            0x11 => {
+                self.players = match data[1] & 0x03 {
+                    1 => 2,
+                    3 => 4,
+                    _ => 1,
+                }
+            }
+            0x13 => self.start_transfer(Transfer::Tiles((data[1] & 0x01) as usize)),
+            0x14 => self.start_transfer(Transfer::Border),
+            0x15 => self.start_transfer(Transfer::Attributes),
+            0x16 => self.attr_set(data[1]),
+            0x17 => self.mask = data[1] & 0x03,
+            // Sound, SNES memory and the other commands are not emulated
+            _ => {}
+        }
+        self.command = data;
+    }
+
+    // Color 0 is shared by all palettes
+    fn set_palette_pair(&mut self, data: &[u8], first: usize, second: usize) {
+        let color = |i: usize| (data[1 + i * 2] as u16) | ((data[2 + i * 2] as u16) << 8);
+        for palette in self.palettes.iter_mut() {
+            palette[0] = color(0);
+        }
+        for i in 1..4 {
+            self.palettes[first][i] = color(i);
+            self.palettes[second][i] = color(i + 3);
+        }
+    }
+
+    fn attr_blk(&mut self, data: &[u8]) {
+        let count = (data[1] as usize).min(18);
+        for set in data[2..].chunks(6).take(count) {
+            if set.len() < 6 {
+                break;
+            }
+            let control = set[0] & 0x07;
+            let inside = set[1] & 0x03;
+            let outside = (set[1] >> 4) & 0x03;
+            // Changing only one side also colors the edge of the block
+            let edge = match control {
+                0x01 => inside,
+                0x04 => outside,
+                _ => (set[1] >> 2) & 0x03,
+            };
+            let (x1, y1, x2, y2) = (set[2] as usize, set[3] as usize, set[4] as usize, set[5] as usize);
+            for y in 0..ATTR_H {
+                for x in 0..ATTR_W {
+                    let within = x >= x1 && x <= x2 && y >= y1 && y <= y2;
+                    let inner = x > x1 && x < x2 && y > y1 && y < y2;
+                    let palette = if inner {
+                        if control & 0x01 == 0 {
+                            continue;
+                        }
+                        inside
+                    } else if within {
+                        if control & 0x02 == 0 && control != 0x01 && control != 0x04 {
+                            continue;
+                        }
+                        edge
+                    } else {
+                        if control & 0x04 == 0 {
+                            continue;
+                        }
+                        outside
+                    };
+                    self.attr_map[y * ATTR_W + x] = palette;
+                }
+            }
+        }
+    }
+
+    fn attr_lin(&mut self, data: &[u8]) {
+        let count = data[1] as usize;
+        for &line in data[2..].iter().take(count) {
+            let n = (line & 0x1F) as usize;
+            let palette = (line >> 5) & 0x03;
+            if line & 0x80 != 0 {
+                if n < ATTR_H {
+                    for x in 0..ATTR_W {
+                        self.attr_map[n * ATTR_W + x] = palette;
+                    }
+                }
+            } else if n < ATTR_W {
+                for y in 0..ATTR_H {
+                    self.attr_map[y * ATTR_W + n] = palette;
+                }
+            }
+        }
+    }
+
+    fn attr_div(&mut self, data: &[u8]) {
+        let after = data[1] & 0x03;
+        let before = (data[1] >> 2) & 0x03;
+        let on_line = (data[1] >> 4) & 0x03;
+        let horizontal = data[1] & 0x40 != 0;
+        let split = data[2] as usize;
+        for y in 0..ATTR_H {
+            for x in 0..ATTR_W {
+                let position = if horizontal { y } else { x };
+                self.attr_map[y * ATTR_W + x] = match position {
+                    p if p < split => before,
+                    p if p == split => on_line,
+                    _ => after,
+                };
+            }
+        }
+    }
+
+    fn attr_chr(&mut self, data: &[u8]) {
+        let (mut x, mut y) = (data[1] as usize, data[2] as usize);
+        let count = ((data[3] as usize) | ((data[4] as usize) << 8)).min(ATTR_W * ATTR_H);
+        let vertical = data[5] & 0x01 != 0;
+        for i in 0..count {
+            let byte = match data.get(6 + i / 4) {
+                Some(&byte) => byte,
+                None => break,
+            };
+            if x >= ATTR_W || y >= ATTR_H {
+                break;
+            }
+            self.attr_map[y * ATTR_W + x] = (byte >> (6 - (i % 4) * 2)) & 0x03;
+            if vertical {
+                y += 1;
+                if y == ATTR_H {
+                    y = 0;
+                    x += 1;
+                }
+            } else {
+                x += 1;
+                if x == ATTR_W {
+                    x = 0;
+                    y += 1;
+                }
+            }
+        }
+    }
+
+    fn pal_set(&mut self, data: &[u8]) {
+        for i in 0..4 {
+            let index = ((data[1 + i * 2] as usize) | ((data[2 + i * 2] as usize) << 8)) % SYSTEM_PALETTES;
+            self.palettes[i] = self.system_palettes[index];
+        }
+        let shared = self.palettes[0][0];
+        for palette in self.palettes.iter_mut() {
+            palette[0] = shared;
+        }
+        if data[9] & 0x80 != 0 {
+            self.attr_set(data[9]);
+        } else if data[9] & 0x40 != 0 {
+            self.mask = 0;
+        }
+    }
+
+    // Loads an attribute file, bit 6 also cancels the screen mask
+    fn attr_set(&mut self, v: u8) {
+        let file = (v & 0x3F) as usize;
+        if file < ATTR_FILES {
+            let start = file * ATTR_FILE_SIZE;
+            for i in 0..ATTR_W * ATTR_H {
+                let byte = self.attr_files[start + i / 4];
+                self.attr_map[i] = (byte >> (6 - (i % 4) * 2)) & 0x03;
+            }
+        }
+        if v & 0x40 != 0 {
+            self.mask = 0;
+        }
+    }
+
+    fn start_transfer(&mut self, transfer: Transfer) {
+        self.transfer = transfer;
+        self.transfer_delay = TRANSFER_DELAY;
+    }
+
+    // The SGB receives transfers through the picture on the screen: the first
+    // 256 tiles in reading order, 20 per line, as 2bpp tile data
+    fn screen_data(shades: &[u8]) -> Vec<u8> {
+        let mut data = vec![0; 0x1000];
+        for tile in 0..BORDER_TILES {
+            let (tx, ty) = (tile % ATTR_W, tile / ATTR_W);
+            for row in 0..8 {
+                let line = &shades[(ty * 8 + row) * SCREEN_W + tx * 8..][..8];
+                for (bit, &shade) in line.iter().enumerate() {
+                    let mask = 0x80 >> bit;
+                    if shade & 0x01 != 0 {
+                        data[tile * 16 + row * 2] |= mask;
+                    }
+                    if shade & 0x02 != 0 {
+                        data[tile * 16 + row * 2 + 1] |= mask;
+                    }
+                }
+            }
+        }
+        data
+    }
+
+    fn finish_transfer(&mut self, shades: &[u8]) {
+        let data = Sgb::screen_data(shades);
+        let word = |i: usize| (data[i * 2] as u16) | ((data[i * 2 + 1] as u16) << 8);
+        match self.transfer {
+            Transfer::None => {}
+            Transfer::Palettes => {
+                for (i, palette) in self.system_palettes.iter_mut().enumerate() {
+                    for (j, color) in palette.iter_mut().enumerate() {
+                        *color = word(i * 4 + j);
+                    }
+                }
+            }
+            Transfer::Tiles(half) => {
+                let start = half * 0x1000;
+                self.border_tiles[start..start + 0x1000].copy_from_slice(&data);
+            }
+            Transfer::Border => {
+                for (i, entry) in self.border_map.iter_mut().enumerate() {
+                    *entry = word(i);
+                }
+                for (i, palette) in self.border_palettes.iter_mut().enumerate() {
+                    for (j, color) in palette.iter_mut().enumerate() {
+                        *color = word(0x400 + i * 16 + j);
+                    }
+                }
+            }
+            Transfer::Attributes => {
+                self.attr_files.copy_from_slice(&data[..ATTR_FILES * ATTR_FILE_SIZE]);
+            }
+        }
+        self.transfer = Transfer::None;
+    }
+
+    // Draws a frame from the shades of the game screen, called at every vblank
+    pub fn render(&mut self, shades: &[u8]) {
+        if self.transfer != Transfer::None {
+            self.transfer_delay = self.transfer_delay.saturating_sub(1);
+            if self.transfer_delay == 0 {
+                self.finish_transfer(shades);
+            }
+        }
+
+        let colors: Vec<[[u8; 3]; 4]> = self.palettes.iter().map(|p| p.map(rgb555)).collect();
+        if self.mask != 1 {
+            for y in 0..SCREEN_H {
+                for x in 0..SCREEN_W {
+                    let color = match self.mask {
+                        2 => [0; 3],
+                        3 => colors[0][0],
+                        _ => {
+                            let palette = self.attr_map[(y / 8) * ATTR_W + x / 8] as usize;
+                            colors[palette][shades[y * SCREEN_W + x] as usize & 0x03]
+                        }
+                    };
+                    self.setcolor(SCREEN_X + x, SCREEN_Y + y, color);
+                }
+            }
+        }
+        self.render_border(colors[0][0]);
+    }
+
+    // Color 0 of the border shows the backdrop, which is color 0 of the palettes
+    fn render_border(&mut self, backdrop: [u8; 3]) {
+        for ty in 0..SGB_SCREEN_H / 8 {
+            for tx in 0..SGB_SCREEN_W / 8 {
+                let game = (SCREEN_X / 8..(SCREEN_X + SCREEN_W) / 8).contains(&tx)
+                    && (SCREEN_Y / 8..(SCREEN_Y + SCREEN_H) / 8).contains(&ty);
+                if game {
+                    continue;
+                }
+                let entry = self.border_map[ty * 32 + tx];
+                let mut tile = [0; 32];
+                tile.copy_from_slice(&self.border_tiles[(entry & 0xFF) as usize * 32..][..32]);
+                let palette = ((entry >> 10) & 0x03) as usize;
+                for row in 0..8 {
+                    let y = if entry & 0x8000 != 0 { 7 - row } else { row };
+                    let planes = [tile[y * 2], tile[y * 2 + 1], tile[16 + y * 2], tile[17 + y * 2]];
+                    for col in 0..8 {
+                        let bit = if entry & 0x4000 != 0 { col } else { 7 - col };
+                        let index = planes
+                            .iter()
+                            .enumerate()
+                            .fold(0, |acc, (i, &p)| acc | (((p >> bit) & 1) as usize) << i);
+                        let color = match index {
+                            0 => backdrop,
+                            i => rgb555(self.border_palettes[palette][i]),
+                        };
+                        self.setcolor(tx * 8 + col, ty * 8 + row, color);
+                    }
+                }
+            }
+        }
+    }
+
+    fn setcolor(&mut self, x: usize, y: usize, color: [u8; 3]) {
+        let baseidx = (y * SGB_SCREEN_W + x) * 3;
+        self.data[baseidx..baseidx + 3].copy_from_slice(&color);
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write_vec(&self.command);
+        w.write_u8(self.packets_left as u8);
+        let palettes = self.palettes.iter().chain(self.system_palettes.iter());
+        for &color in palettes.flatten() {
+            w.write_u16(color);
+        }
+        w.write_bytes(&self.attr_map);
+        w.write_bytes(&self.attr_files);
+        w.write_u8(self.mask);
+        w.write_u8(self.players);
+        w.write_bytes(&self.border_tiles);
+        for &entry in self.border_map.iter().chain(self.border_palettes.iter().flatten()) {
+            w.write_u16(entry);
+        }
+        w.write_u8(match self.transfer {
+            Transfer::None => 0,
+            Transfer::Palettes => 1,
+            Transfer::Tiles(0) => 2,
+            Transfer::Tiles(_) => 3,
+            Transfer::Border => 4,
+            Transfer::Attributes => 5,
+        });
+        w.write_u8(self.transfer_delay);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> StrResult<()> {
+        self.command = r.read_vec()?;
+        self.packets_left = (r.read_u8()? & 0x07) as usize;
+        if self.packets_left > 0 && self.command.is_empty() {
+            return Err("Invalid SGB command in save state");
+        }
+        let palettes = self.palettes.iter_mut().chain(self.system_palettes.iter_mut());
+        for color in palettes.flatten() {
+            *color = r.read_u16()?;
+        }
+        r.read_bytes(&mut self.attr_map)?;
+        for palette in self.attr_map.iter_mut() {
+            *palette &= 0x03;
+        }
+        r.read_bytes(&mut self.attr_files)?;
+        self.mask = r.read_u8()? & 0x03;
+        // The keypad counts down from 0x0F by the selected player
+        self.players = match r.read_u8()? {
+            2 => 2,
+            4 => 4,
+            _ => 1,
+        };
+        r.read_bytes(&mut self.border_tiles)?;
+        for entry in self.border_map.iter_mut().chain(self.border_palettes.iter_mut().flatten()) {
+            *entry = r.read_u16()?;
+        }
+        self.transfer = match r.read_u8()? {
+            1 => Transfer::Palettes,
+            2 => Transfer::Tiles(0),
+            3 => Transfer::Tiles(1),
+            4 => Transfer::Border,
+            5 => Transfer::Attributes,
+            _ => Transfer::None,
+        };
+        self.transfer_delay = r.read_u8()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Sgb, SCREEN_X, SCREEN_Y, SGB_SCREEN_W};
+    use crate::gpu::{SCREEN_H, SCREEN_W};
+    use crate::state::{StateReader, StateWriter};
+
+    fn pixel(sgb: &Sgb, x: usize, y: usize) -> [u8; 3] {
+        let i = ((SCREEN_Y + y) * SGB_SCREEN_W + SCREEN_X + x) * 3;
+        [sgb.data[i], sgb.data[i + 1], sgb.data[i + 2]]
+    }
+
+    #[test]
+    fn palettes_and_attributes() {
+        let mut sgb = Sgb::new(true);
+        // PAL01 with a red color 3 in palette 0 and a blue color 3 in palette 1
+        let mut packet = [0; 16];
+        packet[0] = 0x01;
+        packet[7] = 0x1F;
+        packet[13] = 0x00;
+        packet[14] = 0x7C;
+        sgb.receive(&packet);
+
+        // ATTR_BLK setting the inside and edge of the right half to palette 1
+        let mut packet = [0; 16];
+        packet[..8].copy_from_slice(&[0x21, 0x01, 0x03, 0x05, 10, 0, 19, 17]);
+        sgb.receive(&packet);
+
+        sgb.render(&[3; SCREEN_W * SCREEN_H]);
+        assert_eq!(pixel(&sgb, 0, 0), [255, 0, 0]);
+        assert_eq!(pixel(&sgb, 80, 0), [0, 0, 255]);
+        assert_eq!(pixel(&sgb, SCREEN_W - 1, SCREEN_H - 1), [0, 0, 255]);
+
+        // MASK_EN black
+        let mut packet = [0; 16];
+        packet[..2].copy_from_slice(&[0xB9, 0x02]);
+        sgb.receive(&packet);
+        sgb.render(&[3; SCREEN_W * SCREEN_H]);
+        assert_eq!(pixel(&sgb, 0, 0), [0, 0, 0]);
+    }
+
+    #[test]
+    fn unsupported_cartridge() {
+        let mut sgb = Sgb::new(false);
+        let mut packet = [0; 16];
+        packet[..3].copy_from_slice(&[0x01, 0x00, 0x00]);
+        sgb.receive(&packet);
+        sgb.render(&[0; SCREEN_W * SCREEN_H]);
+        assert_eq!(pixel(&sgb, 0, 0), [0xFF, 0xEF, 0xCE]);
+    }
+
+    #[test]
+    fn load_player_count() {
+        for (saved, loaded) in [(2, 2), (4, 4), (0, 1), (3, 1), (200, 1)] {
+            let mut sgb = Sgb::new(true);
+            sgb.players = saved;
+            let mut w = StateWriter::new();
+            sgb.save_state(&mut w);
+            let data = w.into_inner();
+            let mut sgb = Sgb::new(true);
+            sgb.load_state(&mut StateReader::new(&data)).unwrap();
+            assert_eq!(sgb.players(), loaded);
+        }
+    }
+}
@@ -1,3 +1,6 @@
+use crate::gbmode::Model;
+use crate::state::{StateReader, StateWriter};
+use crate::StrResult;
 use blip_buf::BlipBuf;
 
 const WAVE_PATTERN: [[i32; 8]; 4] = [
@@ -50,6 +53,23 @@ impl VolumeEnvelope {
         }
     }
 
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_u8(self.period);
+        w.write_bool(self.goes_up);
+        w.write_u8(self.delay);
+        w.write_u8(self.initial_volume);
+        w.write_u8(self.volume);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> StrResult<()> {
+        self.period = r.read_u8()?;
+        self.goes_up = r.read_bool()?;
+        self.delay = r.read_u8()?;
+        self.initial_volume = r.read_u8()?;
+        self.volume = r.read_u8()?;
+        Ok(())
+    }
+
     fn step(&mut self) {
         if self.delay > 1 {
             self.delay -= 1;
@@ -158,6 +178,42 @@ impl SquareChannel {
         }
     }
 
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_bool(self.enabled);
+        w.write_u8(self.duty);
+        w.write_u8(self.phase);
+        w.write_u8(self.length);
+        w.write_u8(self.new_length);
+        w.write_bool(self.length_enabled);
+        w.write_u16(self.frequency);
+        w.write_u32(self.delay);
+        w.write_u16(self.sweep_frequency);
+        w.write_u8(self.sweep_delay);
+        w.write_u8(self.sweep_period);
+        w.write_u8(self.sweep_shift);
+        w.write_bool(self.sweep_frequency_increase);
+        self.volume_envelope.save_state(w);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> StrResult<()> {
+        self.enabled = r.read_bool()?;
+        self.duty = r.read_u8()? & 0x03;
+        self.phase = r.read_u8()? & 0x07;
+        self.length = r.read_u8()?;
+        self.new_length = r.read_u8()?;
+        self.length_enabled = r.read_bool()?;
+        self.frequency = r.read_u16()?;
+        self.calculate_period();
+        self.delay = r.read_u32()?;
+        self.sweep_frequency = r.read_u16()?;
+        self.sweep_delay = r.read_u8()?;
+        self.sweep_period = r.read_u8()?;
+        self.sweep_shift = r.read_u8()?;
+        self.sweep_frequency_increase = r.read_bool()?;
+        self.last_amp = 0;
+        self.volume_envelope.load_state(r)
+    }
+
     // This assumes no volume or sweep adjustments need to be done in the meantime
     fn run(&mut self, start_time: u32, end_time: u32) {
         if !self.enabled || self.period == 0 || self.volume_envelope.volume == 0 {
@@ -305,6 +361,38 @@ impl WaveChannel {
         }
     }
 
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_bool(self.enabled);
+        w.write_bool(self.enabled_flag);
+        w.write_u16(self.length);
+        w.write_u16(self.new_length);
+        w.write_bool(self.length_enabled);
+        w.write_u16(self.frequency);
+        w.write_u32(self.delay);
+        w.write_u8(self.volume_shift);
+        w.write_bytes(&self.waveram);
+        w.write_u8(self.current_wave);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> StrResult<()> {
+        self.enabled = r.read_bool()?;
+        self.enabled_flag = r.read_bool()?;
+        self.length = r.read_u16()?;
+        self.new_length = r.read_u16()?;
+        self.length_enabled = r.read_bool()?;
+        self.frequency = r.read_u16()?;
+        self.calculate_period();
+        self.delay = r.read_u32()?;
+        self.volume_shift = r.read_u8()? & 0x03;
+        r.read_bytes(&mut self.waveram)?;
+        for v in self.waveram.iter_mut() {
+            *v &= 0x0F;
+        }
+        self.current_wave = r.read_u8()? % 32;
+        self.last_amp = 0;
+        Ok(())
+    }
+
     fn on(&self) -> bool {
         self.enabled
     }
@@ -416,6 +504,31 @@ impl NoiseChannel {
         self.enabled
     }
 
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_bool(self.enabled);
+        w.write_u8(self.length);
+        w.write_u8(self.new_length);
+        w.write_bool(self.length_enabled);
+        w.write_u32(self.period);
+        w.write_u8(self.shift_width);
+        w.write_u16(self.state);
+        w.write_u32(self.delay);
+        self.volume_envelope.save_state(w);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> StrResult<()> {
+        self.enabled = r.read_bool()?;
+        self.length = r.read_u8()?;
+        self.new_length = r.read_u8()?;
+        self.length_enabled = r.read_bool()?;
+        self.period = r.read_u32()?;
+        self.shift_width = if r.read_u8()? == 6 { 6 } else { 14 };
+        self.state = r.read_u16()?;
+        self.delay = r.read_u32()?;
+        self.last_amp = 0;
+        self.volume_envelope.load_state(r)
+    }
+
     fn run(&mut self, start_time: u32, end_time: u32) {
         if !self.enabled || self.volume_envelope.volume == 0 {
             if self.last_amp != 0 {
@@ -459,6 +572,7 @@ impl NoiseChannel {
 
 pub struct Sound {
     on: bool,
+    model: Model,
     registerdata: [u8; 0x17],
     time: u32,
     prev_time: u32,
@@ -473,10 +587,14 @@ pub struct Sound {
     volume_right: u8,
     need_sync: bool,
     player: Box<dyn AudioPlayer>,
+    // Interleaved stereo samples kept for a recording
+    recorded: Option<Vec<f32>>,
+    // Clock cycles the sound was off for while recording
+    silent_time: u32,
 }
 
 impl Sound {
-    pub fn new(player: Box<dyn AudioPlayer>) -> Sound {
+    pub fn new(player: Box<dyn AudioPlayer>, model: Model) -> Sound {
         let blipbuf1 = create_blipbuf(player.samples_rate());
         let blipbuf2 = create_blipbuf(player.samples_rate());
         let blipbuf3 = create_blipbuf(player.samples_rate());
@@ -487,6 +605,7 @@ impl Sound {
 
         Sound {
             on: false,
+            model,
             registerdata: [0; 0x17],
             time: 0,
             prev_time: 0,
@@ -501,6 +620,8 @@ impl Sound {
             volume_right: 7,
             need_sync: false,
             player: player,
+            recorded: None,
+            silent_time: 0,
         }
     }
 
@@ -525,6 +646,16 @@ impl Sound {
 
     pub fn wb(&mut self, a: u16, v: u8) {
         if a != 0xFF26 && !self.on {
+            // The monochrome models keep the length counters writable while off
+            if !self.model.is_cgb() {
+                match a {
+                    0xFF11 => self.channel1.wb(a, v & 0x3F),
+                    0xFF16 => self.channel2.wb(a, v & 0x3F),
+                    0xFF1B => self.channel3.wb(a, v),
+                    0xFF20 => self.channel4.wb(a, v),
+                    _ => {}
+                }
+            }
             return;
         }
         self.run();
@@ -540,14 +671,68 @@ impl Sound {
                 self.volume_left = v & 0x7;
                 self.volume_right = (v >> 4) & 0x7;
             }
-            0xFF26 => self.on = v & 0x80 == 0x80,
+            0xFF26 => {
+                if self.recorded.is_some() {
+                    if self.on && v & 0x80 == 0 {
+                        // Keep the sound made so far in front of the silence
+                        self.do_output();
+                    } else if !self.on && v & 0x80 != 0 {
+                        self.flush_silence();
+                        self.silent_time = 0;
+                    }
+                }
+                self.on = v & 0x80 == 0x80;
+            }
             0xFF30..=0xFF3F => self.channel3.wb(a, v),
             _ => (),
         }
     }
 
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write_bool(self.on);
+        w.write_bytes(&self.registerdata);
+        w.write_u32(self.time);
+        w.write_u32(self.prev_time);
+        w.write_u32(self.next_time);
+        w.write_u8(self.time_divider);
+        w.write_u8(self.volume_left);
+        w.write_u8(self.volume_right);
+        self.channel1.save_state(w);
+        self.channel2.save_state(w);
+        self.channel3.save_state(w);
+        self.channel4.save_state(w);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> StrResult<()> {
+        self.on = r.read_bool()?;
+        r.read_bytes(&mut self.registerdata)?;
+        let time = r.read_u32()?;
+        let prev_time = r.read_u32()?;
+        let next_time = r.read_u32()?;
+        if prev_time > time || prev_time > next_time {
+            return Err("Invalid sound state");
+        }
+        // The blip buffers cannot be restored, so restart them at the point
+        // up to which the channels had been run
+        self.time = time - prev_time;
+        self.prev_time = 0;
+        self.next_time = next_time - prev_time;
+        self.time_divider = r.read_u8()? % 4;
+        self.volume_left = r.read_u8()? & 0x7;
+        self.volume_right = r.read_u8()? & 0x7;
+        self.channel1.load_state(r)?;
+        self.channel2.load_state(r)?;
+        self.channel3.load_state(r)?;
+        self.channel4.load_state(r)?;
+        self.clear_buffers();
+        Ok(())
+    }
+
     pub fn do_cycle(&mut self, cycles: u32) {
         if !self.on {
+            if self.recorded.is_some() {
+                self.record_silence(cycles);
+            }
             return;
         }
 
@@ -562,6 +747,48 @@ impl Sound {
         self.need_sync = true;
     }
 
+    pub fn samples_rate(&self) -> u32 {
+        self.player.samples_rate()
+    }
+
+    // Keeps a copy of all sound from now on, also the samples the player
+    // skips to catch up
+    pub fn start_recording(&mut self) {
+        if self.on {
+            self.do_output();
+        }
+        self.silent_time = 0;
+        self.recorded = Some(Vec::new());
+    }
+
+    // Returns the interleaved stereo samples recorded since the last call
+    pub fn take_recorded(&mut self) -> Vec<f32> {
+        self.recorded.as_mut().map_or(Vec::new(), std::mem::take)
+    }
+
+    pub fn stop_recording(&mut self) -> Vec<f32> {
+        if self.on {
+            self.do_output();
+        }
+        self.recorded.take().unwrap_or_default()
+    }
+
+    fn record_silence(&mut self, cycles: u32) {
+        self.silent_time += cycles;
+        if self.silent_time >= self.output_period {
+            self.flush_silence();
+        }
+    }
+
+    fn flush_silence(&mut self) {
+        let rate = self.player.samples_rate() as u64;
+        let samples = self.silent_time as u64 * rate / CLOCKS_PER_SECOND as u64;
+        self.silent_time -= (samples * CLOCKS_PER_SECOND as u64 / rate) as u32;
+        if let Some(ref mut recorded) = self.recorded {
+            recorded.resize(recorded.len() + samples as usize * 2, 0.0);
+        }
+    }
+
     fn do_output(&mut self) {
         self.run();
         debug_assert!(self.time == self.prev_time);
@@ -575,7 +802,9 @@ impl Sound {
 
         if !self.need_sync || self.player.underflowed() {
             self.need_sync = false;
-            self.mix_buffers();
+            self.mix_buffers(true);
+        } else if self.recorded.is_some() {
+            self.mix_buffers(false);
         } else {
             // Prevent the BlipBuf's from filling up and triggering an assertion
             self.clear_buffers();
@@ -617,7 +846,7 @@ impl Sound {
         }
     }
 
-    fn mix_buffers(&mut self) {
+    fn mix_buffers(&mut self, play: bool) {
         let sample_count = self.channel1.blip.samples_avail() as usize;
         debug_assert!(sample_count == self.channel2.blip.samples_avail() as usize);
         debug_assert!(sample_count == self.channel3.blip.samples_avail() as usize);
@@ -677,7 +906,15 @@ impl Sound {
             debug_assert!(count1 == count3);
             debug_assert!(count1 == count4);
 
-            self.player.play(&buf_left[..count1], &buf_right[..count1]);
+            if play {
+                self.player.play(&buf_left[..count1], &buf_right[..count1]);
+            }
+            if let Some(ref mut recorded) = self.recorded {
+                for (l, r) in buf_left[..count1].iter().zip(buf_right[..count1].iter()) {
+                    recorded.push(*l);
+                    recorded.push(*r);
+                }
+            }
 
             outputted += count1;
         }
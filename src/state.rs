@@ -0,0 +1,196 @@
+use crate::StrResult;
+
+const STATE_MAGIC: &[u8; 8] = b"RBOYSTAT";
+const STATE_VERSION: u32 = 10;
+
+pub struct StateWriter {
+    data: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new() -> StateWriter {
+        StateWriter { data: Vec::new() }
+    }
+
+    pub fn write_header(&mut self) {
+        self.write_bytes(STATE_MAGIC);
+        self.write_u32(STATE_VERSION);
+    }
+
+    pub fn write_u8(&mut self, v: u8) {
+        self.data.push(v);
+    }
+
+    pub fn write_bool(&mut self, v: bool) {
+        self.write_u8(if v { 1 } else { 0 });
+    }
+
+    pub fn write_u16(&mut self, v: u16) {
+        self.write_bytes(&v.to_be_bytes());
+    }
+
+    pub fn write_u32(&mut self, v: u32) {
+        self.write_bytes(&v.to_be_bytes());
+    }
+
+    pub fn write_u64(&mut self, v: u64) {
+        self.write_bytes(&v.to_be_bytes());
+    }
+
+    // Fixed size block, the reader has to know its length
+    pub fn write_bytes(&mut self, v: &[u8]) {
+        self.data.extend_from_slice(v);
+    }
+
+    // Variable sized block, prefixed with its length
+    pub fn write_vec(&mut self, v: &[u8]) {
+        self.write_u32(v.len() as u32);
+        self.write_bytes(v);
+    }
+
+    pub fn into_inner(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+pub struct StateReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(data: &'a [u8]) -> StateReader<'a> {
+        StateReader { data, pos: 0 }
+    }
+
+    pub fn read_header(&mut self) -> StrResult<()> {
+        let mut magic = [0; 8];
+        self.read_bytes(&mut magic).map_err(|_| "Not a save state file")?;
+        if &magic != STATE_MAGIC {
+            return Err("Not a save state file");
+        }
+        match self.read_u32()? {
+            STATE_VERSION => Ok(()),
+            _ => Err("Unsupported save state version"),
+        }
+    }
+
+    pub fn read_u8(&mut self) -> StrResult<u8> {
+        let mut v = [0; 1];
+        self.read_bytes(&mut v)?;
+        Ok(v[0])
+    }
+
+    pub fn read_bool(&mut self) -> StrResult<bool> {
+        self.read_u8().map(|v| v != 0)
+    }
+
+    pub fn read_u16(&mut self) -> StrResult<u16> {
+        let mut v = [0; 2];
+        self.read_bytes(&mut v)?;
+        Ok(u16::from_be_bytes(v))
+    }
+
+    pub fn read_u32(&mut self) -> StrResult<u32> {
+        let mut v = [0; 4];
+        self.read_bytes(&mut v)?;
+        Ok(u32::from_be_bytes(v))
+    }
+
+    pub fn read_u64(&mut self) -> StrResult<u64> {
+        let mut v = [0; 8];
+        self.read_bytes(&mut v)?;
+        Ok(u64::from_be_bytes(v))
+    }
+
+    pub fn read_bytes(&mut self, v: &mut [u8]) -> StrResult<()> {
+        let end = self.pos + v.len();
+        if end > self.data.len() {
+            return Err("Save state is truncated");
+        }
+        v.copy_from_slice(&self.data[self.pos..end]);
+        self.pos = end;
+        Ok(())
+    }
+
+    pub fn read_vec(&mut self) -> StrResult<Vec<u8>> {
+        let len = self.read_u32()? as usize;
+        if len > self.data.len() - self.pos {
+            return Err("Save state is truncated");
+        }
+        let mut v = vec![0; len];
+        self.read_bytes(&mut v)?;
+        Ok(v)
+    }
+
+    // Reads a vector written by write_vec into a buffer that cannot change size
+    pub fn read_vec_into(&mut self, v: &mut [u8]) -> StrResult<()> {
+        if self.read_u32()? as usize != v.len() {
+            return Err("Save state does not match the buffer size");
+        }
+        self.read_bytes(v)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{StateReader, StateWriter};
+
+    #[test]
+    fn roundtrip() {
+        let mut w = StateWriter::new();
+        w.write_header();
+        w.write_u8(0x12);
+        w.write_bool(true);
+        w.write_u16(0x3456);
+        w.write_u32(0x789ABCDE);
+        w.write_u64(0x0123456789ABCDEF);
+        w.write_bytes(&[1, 2, 3]);
+        w.write_vec(&[4, 5]);
+        let data = w.into_inner();
+
+        let mut r = StateReader::new(&data);
+        r.read_header().unwrap();
+        assert_eq!(r.read_u8().unwrap(), 0x12);
+        assert!(r.read_bool().unwrap());
+        assert_eq!(r.read_u16().unwrap(), 0x3456);
+        assert_eq!(r.read_u32().unwrap(), 0x789ABCDE);
+        assert_eq!(r.read_u64().unwrap(), 0x0123456789ABCDEF);
+        let mut fixed = [0; 3];
+        r.read_bytes(&mut fixed).unwrap();
+        assert_eq!(fixed, [1, 2, 3]);
+        assert_eq!(r.read_vec().unwrap(), vec![4, 5]);
+        assert!(r.read_u8().is_err());
+
+        // A length past the end of the data is rejected before allocating
+        let mut w = StateWriter::new();
+        w.write_u32(0xFFFF_FFFF);
+        let data = w.into_inner();
+        assert!(StateReader::new(&data).read_vec().is_err());
+    }
+
+    #[test]
+    fn vec_size_mismatch() {
+        let mut w = StateWriter::new();
+        w.write_vec(&[1, 2, 3]);
+        let data = w.into_inner();
+
+        let mut small = [0; 2];
+        assert!(StateReader::new(&data).read_vec_into(&mut small).is_err());
+        let mut exact = [0; 3];
+        StateReader::new(&data).read_vec_into(&mut exact).unwrap();
+        assert_eq!(exact, [1, 2, 3]);
+    }
+
+    #[test]
+    fn bad_header() {
+        let mut r = StateReader::new(b"RBOYSAVE\0\0\0\x01");
+        assert!(r.read_header().is_err());
+
+        let mut w = StateWriter::new();
+        w.write_bytes(b"RBOYSTAT");
+        w.write_u32(0xFFFF);
+        let data = w.into_inner();
+        assert!(StateReader::new(&data).read_header().is_err());
+    }
+}
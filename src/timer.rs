@@ -1,3 +1,6 @@
+use crate::state::{StateReader, StateWriter};
+use crate::StrResult;
+
 pub struct Timer {
     divider: u8,
     counter: u8,
@@ -23,6 +26,12 @@ impl Timer {
         }
     }
 
+    // Sets the full 16 bit divider, of which DIV is the upper byte
+    pub fn set_div(&mut self, value: u16) {
+        self.divider = (value >> 8) as u8;
+        self.internaldiv = (value & 0xFF) as u32;
+    }
+
     pub fn rb(&self, a: u16) -> u8 {
         match a {
             0xFF04 => self.divider,
@@ -49,6 +58,32 @@ impl Timer {
         };
     }
 
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write_u8(self.divider);
+        w.write_u8(self.counter);
+        w.write_u8(self.modulo);
+        w.write_bool(self.enabled);
+        w.write_u32(self.step);
+        w.write_u32(self.internalcnt);
+        w.write_u32(self.internaldiv);
+        w.write_u8(self.interrupt);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> StrResult<()> {
+        self.divider = r.read_u8()?;
+        self.counter = r.read_u8()?;
+        self.modulo = r.read_u8()?;
+        self.enabled = r.read_bool()?;
+        self.step = match r.read_u32()? {
+            n @ 16 | n @ 64 | n @ 256 | n @ 1024 => n,
+            _ => return Err("Invalid timer state"),
+        };
+        self.internalcnt = r.read_u32()?;
+        self.internaldiv = r.read_u32()?;
+        self.interrupt = r.read_u8()?;
+        Ok(())
+    }
+
     pub fn do_cycle(&mut self, ticks: u32) {
         self.internaldiv += ticks;
         while self.internaldiv >= 256 {